@@ -0,0 +1,142 @@
+use std::fmt::Display;
+
+use session::{session_type::{Label, Participant}, trace::{EventKind, TraceEvent}};
+
+use crate::merging::GlobalType;
+
+/// One message exchanged between two participants, as seen from the protocol as a whole.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Interaction {
+    pub from: Participant,
+    pub to: Participant,
+    pub label: Label,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Conformance {
+    /// The log is a full run of the protocol
+    Complete,
+    /// The log follows the protocol so far, which would continue as `remaining`
+    Prefix { remaining: GlobalType },
+}
+
+/// The first event of a log that the protocol does not allow.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    /// Position of the event in the log
+    pub index: usize,
+    pub event: Interaction,
+    /// What the protocol allowed instead, empty if it had already ended
+    pub allowed: Vec<Interaction>,
+}
+
+impl Display for Interaction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} -> {} : {}", self.from, self.to, self.label)
+    }
+}
+
+impl Display for Divergence {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Event {} ({}) does not follow the protocol, ", self.index, self.event)?;
+        if self.allowed.is_empty() {
+            return write!(f, "which had already ended");
+        }
+        write!(f, "expected one of [")?;
+        for interaction in &self.allowed {
+            write!(f, "{}, ", interaction)?;
+        }
+        write!(f, "]")
+    }
+}
+
+impl std::error::Error for Divergence {}
+
+impl Interaction {
+    /// The label is read as it is displayed, `Response::Accept`, or as only `Accept` to match that label of any message type.
+    pub fn new(from: &str, to: &str, label: &str) -> Interaction {
+        Interaction {
+            from: Participant::named(from),
+            to: Participant::named(to),
+            label: Label::from(label),
+        }
+    }
+}
+
+/// The interactions in a recorded trace, in the order they were sent.
+/// Every message appears once, from its sender's events, so traces of all participants can be passed together.
+/// Traces from different machines are ordered by their timestamps, so they are only as reliable as the clocks that took them.
+pub fn trace_interactions(events: &[TraceEvent]) -> Vec<Interaction> {
+    let mut sends: Vec<&TraceEvent> = events.iter().filter(|event| event.kind == EventKind::Send).collect();
+    sends.sort_by_key(|event| event.timestamp);
+    sends.into_iter().map(|event| Interaction {
+        from: event.role.clone(),
+        to: event.peer.clone(),
+        label: event.label.clone(),
+    }).collect()
+}
+
+/// Decides whether `log` is a complete run of `global`, or a prefix of one.
+/// Recursions are unfolded as the log goes through them, so a log can go around a loop any number of times.
+pub fn check_log(global: &GlobalType, log: &[Interaction]) -> Result<Conformance, Divergence> {
+    let mut recs: Vec<(i32, GlobalType)> = Vec::new();
+    let mut state = unfold(global.clone(), &mut recs);
+    for (index, event) in log.iter().enumerate() {
+        let next = match &state {
+            GlobalType::Send(from, to, label, cont) if admits(from, to, label, event) => Some((**cont).clone()),
+            GlobalType::Select(from, to, choices) => choices.iter()
+                .find(|(label, _)| admits(from, to, label, event))
+                .map(|(_, cont)| cont.clone()),
+            _ => None,
+        };
+        match next {
+            Some(next) => state = unfold(next, &mut recs),
+            None => return Err(Divergence { index, event: event.clone(), allowed: allowed(&state) }),
+        }
+    }
+    match state {
+        GlobalType::End => Ok(Conformance::Complete),
+        remaining => Ok(Conformance::Prefix { remaining }),
+    }
+}
+
+fn admits(from: &Participant, to: &Participant, label: &Label, event: &Interaction) -> bool {
+    from.admits(&event.from) && to.admits(&event.to) && label.admits(&event.label)
+}
+
+fn allowed(state: &GlobalType) -> Vec<Interaction> {
+    match state {
+        GlobalType::Send(from, to, label, _) => vec![Interaction { from: from.clone(), to: to.clone(), label: label.clone() }],
+        GlobalType::Select(from, to, choices) => choices.iter()
+            .map(|(label, _)| Interaction { from: from.clone(), to: to.clone(), label: label.clone() })
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Enters recursions and follows `X`s until the next interaction is reached.
+/// `recs` holds the recursions entered so far, innermost last.
+fn unfold(mut state: GlobalType, recs: &mut Vec<(i32, GlobalType)>) -> GlobalType {
+    // Re-entering the same recursion twice without an interaction means the loop is unguarded, so it is left at the `X`
+    let mut entered = Vec::new();
+    loop {
+        match state {
+            GlobalType::RecX(id, cont) => {
+                recs.push((id, GlobalType::RecX(id, cont.clone())));
+                state = *cont;
+            },
+            GlobalType::X(target) => {
+                let Some(index) = recs.iter().rposition(|(id, _)| *id == target) else {
+                    return state;
+                };
+                if entered.contains(&target) {
+                    return state;
+                }
+                entered.push(target);
+                state = recs[index].1.clone();
+                recs.truncate(index);
+            },
+            state => return state,
+        }
+    }
+}
@@ -1,2 +1,2 @@
 pub mod merging;
-
+pub mod conformance;
@@ -1,52 +1,100 @@
-use std::{collections::{BTreeMap, BTreeSet, HashMap, HashSet}, fmt::Display, iter::Map};
+use std::{cell::Cell, collections::{BTreeMap, HashMap}, fmt::Display, rc::Rc, str::FromStr};
 
-use session::{session_type::{MPSTLocalType, Participant}, Message};
+use session::session_type::{fmt_choices, Label, MPSTLocalType, Participant};
+use session::syntax::{ParseError, Parser};
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum GlobalType {
-    Send(Participant, Participant, String, Box<GlobalType>),
-    Select(Participant, Participant, Vec<(String, GlobalType)>),
+    Send(Participant, Participant, Label, Box<GlobalType>),
+    Select(Participant, Participant, Vec<(Label, GlobalType)>),
     RecX(i32, Box<GlobalType>),
     X(i32),
     End,
 }
 
 
+/// Prints the text syntax of `session::syntax`, e.g. `mu X1. A -> B : { Left. X1, Right. end }`, which parses back to the same type.
+/// A `Send` prints as a plain interaction, `C -> A : Quote(u32). end`, while a `Select` always has braces, even around a single label.
+/// With `{:#}`, labels are printed without their message type.
 impl Display for GlobalType {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // The nested labels and types are formatted with `f` itself, so they keep its flags
         match self {
             GlobalType::Send(from, to, label, cont) => {
-                write!(f, "Send<{}, {}, {}, {}>", from, to, label, cont)?;
+                write!(f, "{} -> {} : ", from, to)?;
+                Display::fmt(label, f)?;
+                write!(f, ". ")?;
+                Display::fmt(cont, f)?;
             }
             GlobalType::Select(from, to, choices) => {
-                write!(f, "Select<{}, {}, {{ ", from, to)?;
-                for (label, cont) in choices {
-                    write!(f, "{}. {}, ", label, cont)?;
-                }
-                write!(f, "}}>")?;
+                write!(f, "{} -> {} : ", from, to)?;
+                fmt_choices(choices, f)?;
             }
             GlobalType::RecX(id, cont) => {
-                write!(f, "Rec[{}]<{}>", id, cont)?;
+                write!(f, "mu X{}. ", id)?;
+                Display::fmt(cont, f)?;
             }
-            GlobalType::X(id) => write!(f, "X[{}]", id)?,
+            GlobalType::X(id) => write!(f, "X{}", id)?,
             GlobalType::End => write!(f, "end")?,
         }
         Ok(())
     }
 }
 
+fn global_type(parser: &mut Parser) -> Result<GlobalType, ParseError> {
+    if parser.keyword("end") {
+        return Ok(GlobalType::End);
+    }
+    if parser.keyword("mu") {
+        let (id, cont) = parser.recursion(global_type)?;
+        return Ok(GlobalType::RecX(id, Box::new(cont)));
+    }
+    let pos = parser.position();
+    let from = parser.participant()?;
+    if !parser.eat("->") {
+        return match from.role() {
+            Some(name) => Ok(GlobalType::X(parser.variable(name, pos)?)),
+            None => Err(parser.error("Expected `->`")),
+        };
+    }
+    let to = parser.participant()?;
+    parser.expect(":")?;
+    if parser.peek("{") {
+        return Ok(GlobalType::Select(from, to, parser.choices(global_type)?));
+    }
+    let label = parser.label()?;
+    parser.expect(".")?;
+    Ok(GlobalType::Send(from, to, label, Box::new(global_type(parser)?)))
+}
+
+/// Parses the syntax `Display` prints, see `session::syntax`.
+impl FromStr for GlobalType {
+    type Err = ParseError;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser::new(text);
+        let global_type = global_type(&mut parser)?;
+        parser.finish()?;
+        Ok(global_type)
+    }
+}
+
 #[derive(Clone)]
 pub struct Parties {
     pub parties: BTreeMap<Participant, MPSTLocalType>,
     pub global_depth: i32,
     pub local_depth: BTreeMap<Participant, i32>,
     pub recursive_context: RecursiveContext,
+    // The last id given to a recursion of the global type, shared by every branch so no two recursions get the same one
+    last_rec_id: Rc<Cell<i32>>,
 }
 
 #[derive(Clone)]
 pub struct RecursiveContext {
     pub local_depths: BTreeMap<Participant, i32>,
-    pub global_depth: i32
+    pub global_depth: i32,
+    /// The global depth each recursion of the global type was entered at, by its id
+    pub rec_depths: BTreeMap<i32, i32>,
 }
 
 impl RecursiveContext {
@@ -54,6 +102,7 @@ impl RecursiveContext {
         RecursiveContext {
             local_depths: BTreeMap::from_iter(participants.iter().map(|p| (p.clone(), 0))),
             global_depth: 0,
+            rec_depths: BTreeMap::new(),
         }
     }
 }
@@ -66,6 +115,7 @@ impl Parties {
             global_depth: 0,
             local_depth,
             recursive_context: RecursiveContext::init(parties.iter().map(|(p,_)| p.clone()).collect::<Vec<_>>().as_slice()),
+            last_rec_id: Rc::new(Cell::new(0)),
         }
     }
 
@@ -85,7 +135,7 @@ impl Display for Parties {
 }
 
 pub fn merge_locals(parties: Parties) -> Result<GlobalType, String> {
-    println!("Merging local types {}", parties);
+    let _span = tracing::debug_span!("merge", %parties).entered();
     if parties.is_end_state() {
         return Ok(GlobalType::End);
     }
@@ -93,18 +143,18 @@ pub fn merge_locals(parties: Parties) -> Result<GlobalType, String> {
     let mut duals = enumerate_duals(&parties);
     duals.sort();
     duals.dedup();
-    println!("Duals: {:?}", duals);
+    tracing::debug!(?duals, "found duals");
     // duals.reverse();
     for (p1, p2) in duals {
         match reduce_then_merge(p1.clone(), p2.clone(), &parties) {
             Ok(gt) => return Ok(gt),
-            Err(err) => println!("Cannot merge {} and {}: {}, trying next dual", p1, p2, err),
+            Err(err) => tracing::debug!(%p1, %p2, %err, "backtracking, trying next dual"),
         }
     }
 
-    let (gen_new_rec, parties) = unwrap_rec(parties);
-    if gen_new_rec {
-        return Ok(GlobalType::RecX(parties.global_depth, Box::new(merge_locals(parties)?)));
+    let (new_rec, parties) = unwrap_rec(parties);
+    if let Some(id) = new_rec {
+        return Ok(GlobalType::RecX(id, Box::new(merge_locals(parties)?)));
     }
 
     // The other reduction case is if all parties are End or compatible X(_), in which case we can just return X
@@ -118,11 +168,11 @@ pub fn merge_locals(parties: Parties) -> Result<GlobalType, String> {
     let mut is_unsychronised_recursion = false;
     for (_, lt) in non_ends {
         match lt {
-            MPSTLocalType::X(depth, mapped) => {
+            MPSTLocalType::X(id, mapped) => {
                 assert!(mapped);
                 match will_recurse_to {
-                    Some(fixed_depth) => assert!(Some(fixed_depth) == *depth),
-                    None => will_recurse_to = *depth,
+                    Some(fixed_id) => assert!(fixed_id == *id),
+                    None => will_recurse_to = Some(*id),
                 }
             },
             _ => is_unsychronised_recursion = true,
@@ -132,20 +182,20 @@ pub fn merge_locals(parties: Parties) -> Result<GlobalType, String> {
     if is_unsychronised_recursion && will_recurse_to.is_none() {
         return Err(format!("Cannot merge local types, not all parties are recursing {}", parties));
     } else if is_unsychronised_recursion && will_recurse_to.is_some() {
-        println!("ERROR: We do not know if these recursive types are compatible, PLEASE check with KMC")
+        tracing::warn!("We do not know if these recursive types are compatible, PLEASE check with KMC")
     }
 
     for (p, lt) in ends {
         match lt {
             MPSTLocalType::End => {
                 match will_recurse_to {
-                    None => (),
                     // If the local depth is further ahead of where the recursion goes back to, 
                     // then communication that should not be repeated (from this LT) will happen.
-                    Some(depth) => if depth != parties.local_depth[p] {
+                    Some(id) if parties.recursive_context.rec_depths[&id] != parties.local_depth[p] => {
                         all_end_or_x = false;
                         break
-                    }
+                    },
+                    _ => (),
                 }
             },
             _ => {
@@ -156,24 +206,25 @@ pub fn merge_locals(parties: Parties) -> Result<GlobalType, String> {
     if all_end_or_x {
         match will_recurse_to {
             None => return Ok(GlobalType::End),
-            Some(depth) => return Ok(GlobalType::X(depth)),
+            Some(id) => return Ok(GlobalType::X(id)),
         }
     }
 
     Err(format!("Cannot merge local types {}", parties))
 }
 
-fn unwrap_rec(parties: Parties) -> (bool, Parties) {
+fn unwrap_rec(parties: Parties) -> (Option<i32>, Parties) {
     // Iterate over all parties, and replace any RecX closures with their continuation.
     // If for any party, the local type is not RecX or End during the iteration, then we simply return a Result error
     // At the end of the iteration, we collect the results into a new Vec, and if we match an error, we return the original parties input.
 
     let mut gen_new_rec = false;
+    let fresh_id = parties.last_rec_id.get() + 1;
     let mut new_parties = Vec::with_capacity(parties.parties.len());
     for (p, lt) in &parties.parties {
         match lt {
             MPSTLocalType::RecX {cont, id, ..} => {
-                new_parties.push((p.clone(), cont.map_local_x_to_global_rec(*id, parties.global_depth)));
+                new_parties.push((p.clone(), cont.map_local_x_to_global_rec(*id, fresh_id)));
                 gen_new_rec = true;
                 
             }
@@ -181,42 +232,59 @@ fn unwrap_rec(parties: Parties) -> (bool, Parties) {
                 new_parties.push((p.clone(), MPSTLocalType::End));
             }
             // By equivalence of cont == RecX(cont) where X not free in cont, we simply add RecX.
-            // In fact, as we are using global_depth, we don't need to check free vars, assuming well-defined local types
+            // In fact, as every recursion gets a fresh id, we don't need to check free vars, assuming well-defined local types
             _ => {
                 new_parties.push((p.clone(), lt.clone()))
             }
         }
     }
     if !gen_new_rec {
-        return (false, parties);
+        return (None, parties);
     }
+    parties.last_rec_id.set(fresh_id);
     let mut new_parties = Parties {
         parties: new_parties.into_iter().collect(),
         global_depth: parties.global_depth,
         local_depth: parties.local_depth.clone(),
         recursive_context: parties.recursive_context,
+        last_rec_id: parties.last_rec_id.clone(),
     };
     new_parties.recursive_context.global_depth = parties.global_depth;
+    new_parties.recursive_context.rec_depths.insert(fresh_id, parties.global_depth);
     for (p, lt) in &new_parties.parties {
-        match lt {
-            MPSTLocalType::RecX {..} => {
-                new_parties.recursive_context.local_depths.insert(p.clone(), parties.local_depth[p]);
-            }
-            _ => (),
+        if let MPSTLocalType::RecX {..} = lt {
+            new_parties.recursive_context.local_depths.insert(p.clone(), parties.local_depth[p]);
         }
     }
-    (gen_new_rec, new_parties)
+    (Some(fresh_id), new_parties)
+}
+
+/// The step of a global type where `from` sends `to` one of the labels of `conts`, a `Send` if there is only one.
+fn interaction(from: Participant, to: Participant, mut conts: Vec<(Label, GlobalType)>) -> GlobalType {
+    if conts.len() == 1 {
+        let (label, cont) = conts.remove(0);
+        return GlobalType::Send(from, to, label, Box::new(cont));
+    }
+    GlobalType::Select(from, to, conts)
 }
 
 fn reduce_then_merge(p1: Participant, p2: Participant, parties: &Parties) -> Result<GlobalType, String> {
-    println!("Reducing {} and {} from {}", p1, p2, parties);
+    let _span = tracing::debug_span!("dual", %p1, %p2).entered();
     let p1_mpst = parties.parties.iter().find(|(p, _)| *p == &p1).ok_or(String::from("Cannot find party 1 to reduce"))?.1.clone();
     let p2_mpst = parties.parties.iter().find(|(p, _)| *p == &p2).ok_or(String::from("Cannot find party 2 to reduce"))?.1.clone();
     match (p1_mpst, p2_mpst) {
-        (MPSTLocalType::Branch(_, branch_conts), MPSTLocalType::Select(_, sel_conts)) => {
+        (MPSTLocalType::Branch(from, branch_conts), MPSTLocalType::Select(to, sel_conts)) => {
+            if !from.admits(&p2) || !to.admits(&p1) {
+                return Err(format!("{} and {} are not talking to each other", p1, p2));
+            }
             let mut new_conts = Vec::new();
             for (label, sel_cont) in sel_conts {
-                let (_, matched_branch_cont) = branch_conts.iter().find(|(label2, _)| label == *label2).ok_or(format!("No matching label for {}", label))?;
+                let (branch_label, matched_branch_cont) = branch_conts.iter().find(|(label2, _)| label == *label2).ok_or(format!("No matching label for {}", label))?;
+                if !label.sort_agrees(branch_label) {
+                    return Err(format!("Sort mismatch, {} is sent but {} is received", label, branch_label));
+                }
+                // The receiver may know the sort when the sender does not
+                let label = label.sorted(std::slice::from_ref(branch_label));
                 
                 let new_parties = parties.parties.iter().map(|(p, lt)| {
                     if p == &p1 {
@@ -232,6 +300,7 @@ fn reduce_then_merge(p1: Participant, p2: Participant, parties: &Parties) -> Res
                     global_depth: parties.global_depth,
                     local_depth: parties.local_depth.clone(),
                     recursive_context: parties.recursive_context.clone(),
+                    last_rec_id: parties.last_rec_id.clone(),
                 };
                 new_parties.local_depth.insert(p1.clone(), parties.local_depth[&p1] + 1);
                 new_parties.local_depth.insert(p2.clone(), parties.local_depth[&p2] + 1);
@@ -242,12 +311,20 @@ fn reduce_then_merge(p1: Participant, p2: Participant, parties: &Parties) -> Res
                     Err(_) => return Err(format!("Cannot merge local types {}", parties)),
                 }
             }
-            Ok(GlobalType::Select(p2, p1, new_conts))
+            Ok(interaction(p2, p1, new_conts))
         }
-        (MPSTLocalType::Select(_, sel_conts), MPSTLocalType::Branch(_, branch_conts)) => {
+        (MPSTLocalType::Select(to, sel_conts), MPSTLocalType::Branch(from, branch_conts)) => {
+            if !to.admits(&p2) || !from.admits(&p1) {
+                return Err(format!("{} and {} are not talking to each other", p1, p2));
+            }
             let mut new_conts = Vec::new();
             for (label, sel_cont) in sel_conts {
-                let (_, matched_branch_cont) = branch_conts.iter().find(|(label2, _)| label == *label2).ok_or(format!("No matching label for {}", label))?;
+                let (branch_label, matched_branch_cont) = branch_conts.iter().find(|(label2, _)| label == *label2).ok_or(format!("No matching label for {}", label))?;
+                if !label.sort_agrees(branch_label) {
+                    return Err(format!("Sort mismatch, {} is sent but {} is received", label, branch_label));
+                }
+                // The receiver may know the sort when the sender does not
+                let label = label.sorted(std::slice::from_ref(branch_label));
                 
                 let new_parties = parties.parties.iter().map(|(p, lt)| {
                     if p == &p1 {
@@ -262,7 +339,8 @@ fn reduce_then_merge(p1: Participant, p2: Participant, parties: &Parties) -> Res
                     parties: new_parties,
                     global_depth: parties.global_depth,
                     local_depth: parties.local_depth.clone(),
-                    recursive_context: parties.recursive_context.clone()
+                    recursive_context: parties.recursive_context.clone(),
+                    last_rec_id: parties.last_rec_id.clone(),
                 };
 
                 new_parties.local_depth.insert(p1.clone(), parties.local_depth[&p1] + 1);
@@ -274,33 +352,39 @@ fn reduce_then_merge(p1: Participant, p2: Participant, parties: &Parties) -> Res
                     Err(_) => return Err(format!("Cannot merge local types {}", parties)),
                 }
             }
-            Ok(GlobalType::Select(p1, p2, new_conts))
+            Ok(interaction(p1, p2, new_conts))
         }
         _ => Err(format!("Cannot merge local types {}", parties))
     }
 }
 
+/// Pairs up participants whose next actions could be a matching send and receive.
+/// A named peer on either side restricts who it can pair with, anonymous ones are matched on labels alone.
 fn enumerate_duals(parties: &Parties) -> Vec<(Participant, Participant)> {
     let mut duals = Vec::new();
-    let mut receivers: HashMap<String, Participant> = HashMap::new();
-    let mut senders: HashMap<String, Participant> = HashMap::new();
+    let mut receivers: HashMap<Label, Vec<(Participant, Participant)>> = HashMap::new();
+    let mut senders: HashMap<Label, Vec<(Participant, Participant)>> = HashMap::new();
     for (p1, local_type) in &parties.parties {
         match local_type {
-            MPSTLocalType::Branch(p2, conts) => {
+            MPSTLocalType::Branch(from, conts) => {
                 for (label, _) in conts {
-                    receivers.insert(label.clone(), p1.clone());
+                    receivers.entry(label.clone()).or_default().push((p1.clone(), from.clone()));
 
-                    if senders.contains_key(label) {
-                        duals.push((p1.clone(), senders[label].clone()));
+                    for (sender, to) in senders.get(label).into_iter().flatten() {
+                        if from.admits(sender) && to.admits(p1) {
+                            duals.push((p1.clone(), sender.clone()));
+                        }
                     }
                 }
             }
-            MPSTLocalType::Select(p2, conts) => {
+            MPSTLocalType::Select(to, conts) => {
                 for (label, _) in conts {
-                    senders.insert(label.clone(), p1.clone());
+                    senders.entry(label.clone()).or_default().push((p1.clone(), to.clone()));
 
-                    if receivers.contains_key(label) {
-                        duals.push((p1.clone(), receivers[label].clone()));
+                    for (receiver, from) in receivers.get(label).into_iter().flatten() {
+                        if to.admits(receiver) && from.admits(p1) {
+                            duals.push((p1.clone(), receiver.clone()));
+                        }
                     }
                 }
             }
@@ -310,4 +394,4 @@ fn enumerate_duals(parties: &Parties) -> Vec<(Participant, Participant)> {
         }
     }
     duals
-}
\ No newline at end of file
+}
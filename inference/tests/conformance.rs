@@ -0,0 +1,91 @@
+use inference::{conformance::*, merging::*};
+use macros::Message;
+use serde::{Deserialize, Serialize};
+use session::{channel, session_type::{Label, MPSTLocalType, Participant}, trace::SharedBuffer, Session};
+
+/// C sends any number of Adds to S, then a Req, and S answers with Ans
+fn sum_protocol() -> GlobalType {
+    let anon = Participant::anonymous();
+    let client = MPSTLocalType::recX_with_id(Box::new(MPSTLocalType::Select(anon.clone(), vec![
+        (Label::from("Request::Add"), MPSTLocalType::x_with_id(1)),
+        (Label::from("Request::Req"), MPSTLocalType::Branch(anon.clone(), vec![(Label::from("Ans"), MPSTLocalType::End)])),
+    ])), 1);
+    let server = MPSTLocalType::recX_with_id(Box::new(MPSTLocalType::Branch(anon.clone(), vec![
+        (Label::from("Request::Add"), MPSTLocalType::x_with_id(1)),
+        (Label::from("Request::Req"), MPSTLocalType::Select(anon, vec![(Label::from("Ans"), MPSTLocalType::End)])),
+    ])), 1);
+    merge_locals(Parties::new(vec![(Participant::named("C"), client), (Participant::named("S"), server)])).unwrap()
+}
+
+#[test]
+fn complete_run_through_recursion() {
+    let log = [
+        Interaction::new("C", "S", "Add"),
+        Interaction::new("C", "S", "Add"),
+        Interaction::new("C", "S", "Add"),
+        Interaction::new("C", "S", "Req"),
+        Interaction::new("S", "C", "Ans"),
+    ];
+    assert_eq!(check_log(&sum_protocol(), &log), Ok(Conformance::Complete));
+}
+
+#[test]
+fn prefix_of_a_run() {
+    let log = [Interaction::new("C", "S", "Add"), Interaction::new("C", "S", "Req")];
+    match check_log(&sum_protocol(), &log) {
+        Ok(Conformance::Prefix { remaining }) => assert_eq!(remaining.to_string(), "S -> C : Ans. end"),
+        other => panic!("Expected a prefix, got {:?}", other),
+    }
+    assert!(matches!(check_log(&sum_protocol(), &[]), Ok(Conformance::Prefix { .. })));
+}
+
+#[test]
+fn reports_first_divergence() {
+    let log = [
+        Interaction::new("C", "S", "Add"),
+        Interaction::new("S", "C", "Ans"),
+        Interaction::new("C", "S", "Req"),
+    ];
+    let divergence = check_log(&sum_protocol(), &log).unwrap_err();
+    assert_eq!(divergence.index, 1);
+    assert_eq!(divergence.event, Interaction::new("S", "C", "Ans"));
+    assert_eq!(divergence.allowed, vec![Interaction::new("C", "S", "Request::Add"), Interaction::new("C", "S", "Request::Req")]);
+
+    let log = [Interaction::new("C", "S", "Req"), Interaction::new("S", "C", "Ans"), Interaction::new("C", "S", "Add")];
+    let divergence = check_log(&sum_protocol(), &log).unwrap_err();
+    assert_eq!(divergence.index, 2);
+    assert!(divergence.allowed.is_empty());
+}
+
+#[derive(Serialize, Deserialize, Message)]
+enum Request {
+    Add,
+    Req,
+}
+#[derive(Serialize, Deserialize, Message)]
+struct Ans;
+
+#[test]
+fn recorded_trace_conforms() {
+    let buffer = SharedBuffer::default();
+    let mut sessions = channel::connect(&[Participant::named("C"), Participant::named("S")]);
+    let mut server = sessions.pop().unwrap();
+    let mut client = sessions.pop().unwrap();
+    client.record(buffer.clone());
+    server.record(buffer.clone());
+
+    let handle = std::thread::spawn(move || {
+        let mut s: Session = server;
+        while let Request::Add = s.branch::<Request>() {}
+        s.send(Ans);
+    });
+    client.send(Request::Add);
+    client.send(Request::Add);
+    client.send(Request::Req);
+    client.receive::<Ans>();
+    handle.join().unwrap();
+
+    let log = trace_interactions(&buffer.events().unwrap());
+    assert_eq!(log.len(), 4);
+    assert_eq!(check_log(&sum_protocol(), &log), Ok(Conformance::Complete));
+}
@@ -1,5 +1,9 @@
-use session::{session_type::{MPSTLocalType, Participant}, Session, Message};
+#![allow(non_snake_case, dead_code)]
+
+use macros::Message;
+use session::{channel, session_type::{Label, MPSTLocalType, Participant}, Session};
 use inference::merging::*;
+use serde::{Deserialize, Serialize};
 
 #[test]
 fn simple_merge_manual_types() {
@@ -7,10 +11,10 @@ fn simple_merge_manual_types() {
 
     let lt1 = MPSTLocalType::send(
         anon.clone(),
-        String::from("Hello"),
+        Label::from("Hello"),
         MPSTLocalType::Branch(anon.clone(), vec![
-            (String::from("Left"), MPSTLocalType::receive(anon.clone(), String::from("LeftEnd"), MPSTLocalType::End)),
-            (String::from("Right"), MPSTLocalType::send(anon.clone(), String::from("RightEnd"), MPSTLocalType::End)),
+            (Label::from("Left"), MPSTLocalType::receive(anon.clone(), Label::from("LeftEnd"), MPSTLocalType::End)),
+            (Label::from("Right"), MPSTLocalType::send(anon.clone(), Label::from("RightEnd"), MPSTLocalType::End)),
         ])
     );
 
@@ -18,10 +22,10 @@ fn simple_merge_manual_types() {
 
     let lt2 = MPSTLocalType::receive(
         anon.clone(),
-        String::from("Hello"),
+        Label::from("Hello"),
         MPSTLocalType::Select(anon.clone(), vec![
-            (String::from("Left"), MPSTLocalType::send(anon.clone(), String::from("LeftEnd"), MPSTLocalType::End)),
-            (String::from("Right"), MPSTLocalType::receive(anon.clone(), String::from("RightEnd"), MPSTLocalType::End)),
+            (Label::from("Left"), MPSTLocalType::send(anon.clone(), Label::from("LeftEnd"), MPSTLocalType::End)),
+            (Label::from("Right"), MPSTLocalType::receive(anon.clone(), Label::from("RightEnd"), MPSTLocalType::End)),
         ])
     );
     
@@ -33,35 +37,15 @@ fn simple_merge_manual_types() {
 
 #[test]
 fn simple_merge_inferred() {
+    #[derive(Serialize, Deserialize, Message)]
     struct Hello;
-    enum Choice1 { Left, Right };
+    #[derive(Serialize, Deserialize, Message)]
+    enum Choice1 { Left, Right }
+    #[derive(Serialize, Deserialize, Message)]
     struct LeftEnd;
+    #[derive(Serialize, Deserialize, Message)]
     struct RightEnd;
 
-    impl Message for Hello {
-        fn receive() -> Self {
-            Hello
-        }
-    }
-
-    impl Message for Choice1 {
-        fn receive() -> Self {
-            Choice1::Left
-        }
-    }
-
-    impl Message for LeftEnd {
-        fn receive() -> Self {
-            LeftEnd
-        }
-    }
-
-    impl Message for RightEnd {
-        fn receive() -> Self {
-            RightEnd
-        }
-    }
-
 
     #[macros::infer_session_type]
     fn client(mut s: Session) {
@@ -90,8 +74,8 @@ fn simple_merge_inferred() {
 
     let client_role = Participant::new(Some(String::from("C")));
     let server_role = Participant::new(Some(String::from("S")));
-    println!("Client.LocalType: {}", get_session_type_client());
-    println!("Server.LocalType: {}", get_session_type_server());
+    println!("Client.LocalType: {}", get_session_type_client().unwrap());
+    println!("Server.LocalType: {}", get_session_type_server().unwrap());
 
     let client_mpst_local = get_mpst_session_type_client().unwrap();
     let server_mpst_local = get_mpst_session_type_server().unwrap();
@@ -107,10 +91,10 @@ fn more_general_branch() {
 
     let lt1 = MPSTLocalType::send(
         anon.clone(),
-        String::from("Hello"),
+        Label::from("Hello"),
         MPSTLocalType::Branch(anon.clone(), vec![
-            (String::from("Left"), MPSTLocalType::receive(anon.clone(), String::from("LeftEnd"), MPSTLocalType::End)),
-            (String::from("Right"), MPSTLocalType::send(anon.clone(), String::from("RightEnd"), MPSTLocalType::End)),
+            (Label::from("Left"), MPSTLocalType::receive(anon.clone(), Label::from("LeftEnd"), MPSTLocalType::End)),
+            (Label::from("Right"), MPSTLocalType::send(anon.clone(), Label::from("RightEnd"), MPSTLocalType::End)),
         ])
     );
 
@@ -118,9 +102,9 @@ fn more_general_branch() {
 
     let lt2 = MPSTLocalType::receive(
         anon.clone(),
-        String::from("Hello"),
-        MPSTLocalType::send(anon.clone(), String::from("Left"), 
-            MPSTLocalType::send(anon.clone(), String::from("LeftEnd"), MPSTLocalType::End)
+        Label::from("Hello"),
+        MPSTLocalType::send(anon.clone(), Label::from("Left"), 
+            MPSTLocalType::send(anon.clone(), Label::from("LeftEnd"), MPSTLocalType::End)
         )
     );
     
@@ -157,24 +141,26 @@ fn recursive_sum_manual() {
 
     let anon = Participant::anonymous();
 
-    let lt1 = MPSTLocalType::recX(
+    let lt1 = MPSTLocalType::recX_with_id(
         Box::new(
             MPSTLocalType::Select(anon.clone(), vec![
-                (String::from("Add"), MPSTLocalType::x()),
-                (String::from("Req"), MPSTLocalType::Branch(anon.clone(), vec![(String::from("Ans"), MPSTLocalType::End)]))
+                (Label::from("Add"), MPSTLocalType::x_with_id(1)),
+                (Label::from("Req"), MPSTLocalType::Branch(anon.clone(), vec![(Label::from("Ans"), MPSTLocalType::End)]))
             ])
-        )
+        ),
+        1
     );
 
     let lt1_role = Participant::new(Some(String::from("C")));
 
-    let lt2 = MPSTLocalType::recX(
+    let lt2 = MPSTLocalType::recX_with_id(
         Box::new(
             MPSTLocalType::Branch(anon.clone(), vec![
-                (String::from("Add"), MPSTLocalType::x()),
-                (String::from("Req"), MPSTLocalType::Select(anon.clone(), vec![(String::from("Ans"), MPSTLocalType::End)]))
+                (Label::from("Add"), MPSTLocalType::x_with_id(1)),
+                (Label::from("Req"), MPSTLocalType::Select(anon.clone(), vec![(Label::from("Ans"), MPSTLocalType::End)]))
             ])
-        )
+        ),
+        1
     );
 
     let lt2_role = Participant::new(Some(String::from("S")));
@@ -183,24 +169,167 @@ fn recursive_sum_manual() {
 }
 
 #[test]
-fn recursive_sum() {
-    enum Choice1 {
-        Add,
-        Req
+fn sibling_recursions_get_distinct_ids() {
+    // Both parties loop on Add, then on Ping, each numbering its loops 1 and 2
+    let anon = Participant::anonymous();
+    let client = MPSTLocalType::recX_with_id(Box::new(MPSTLocalType::Select(anon.clone(), vec![
+        (Label::from("Add"), MPSTLocalType::x_with_id(1)),
+        (Label::from("Done"), MPSTLocalType::recX_with_id(Box::new(MPSTLocalType::Select(anon.clone(), vec![
+            (Label::from("Ping"), MPSTLocalType::x_with_id(2)),
+            (Label::from("Stop"), MPSTLocalType::End),
+        ])), 2)),
+    ])), 1);
+    let server = MPSTLocalType::recX_with_id(Box::new(MPSTLocalType::Branch(anon.clone(), vec![
+        (Label::from("Add"), MPSTLocalType::x_with_id(1)),
+        (Label::from("Done"), MPSTLocalType::recX_with_id(Box::new(MPSTLocalType::Branch(anon.clone(), vec![
+            (Label::from("Ping"), MPSTLocalType::x_with_id(2)),
+            (Label::from("Stop"), MPSTLocalType::End),
+        ])), 2)),
+    ])), 1);
+
+    let (c, s) = (Participant::named("C"), Participant::named("S"));
+    let expected = GlobalType::RecX(1, Box::new(GlobalType::Select(c.clone(), s.clone(), vec![
+        (Label::from("Add"), GlobalType::X(1)),
+        (Label::from("Done"), GlobalType::RecX(2, Box::new(GlobalType::Select(c.clone(), s.clone(), vec![
+            (Label::from("Ping"), GlobalType::X(2)),
+            (Label::from("Stop"), GlobalType::End),
+        ])))),
+    ])));
+    assert_eq!(merge_locals(Parties::new(vec![(c, client), (s, server)])).unwrap(), expected);
+}
+
+#[test]
+fn same_variant_of_different_enums() {
+    #[derive(Serialize, Deserialize, Message)]
+    enum Login {
+        Ok,
+        Denied
+    }
+    #[derive(Serialize, Deserialize, Message)]
+    enum Upload {
+        Ok,
+        Failed
+    }
+
+    #[macros::infer_session_type]
+    fn client(mut s: Session) {
+        s.send(Login::Ok);
     }
-    struct Sum;
 
-    impl Message for Choice1 {
-        fn receive() -> Self {
-            Choice1::Add
+    #[macros::infer_session_type]
+    fn login_server(mut s: Session) {
+        match s.branch::<Login>() {
+            Login::Ok => {}
+            Login::Denied => {}
         }
     }
-    impl Message for Sum {
-        fn receive() -> Self {
-            Sum
+
+    #[macros::infer_session_type]
+    fn upload_server(mut s: Session) {
+        match s.branch::<Upload>() {
+            Upload::Ok => {}
+            Upload::Failed => {}
         }
     }
 
+    let (c, s) = (Participant::named("C"), Participant::named("S"));
+    let client = get_mpst_session_type_client().unwrap();
+    let global = merge_locals(Parties::new(vec![(c.clone(), client.clone()), (s.clone(), get_mpst_session_type_login_server().unwrap())])).unwrap();
+    assert_eq!(global.to_string(), "C -> S : Login::Ok. end");
+    assert_eq!(format!("{:#}", global), "C -> S : Ok. end");
+    // Upload::Ok is a different message, even though it is also called Ok
+    assert!(merge_locals(Parties::new(vec![(c, client), (s, get_mpst_session_type_upload_server().unwrap())])).is_err());
+}
+
+#[test]
+fn payload_sorts() {
+    #[derive(Serialize, Deserialize, Message)]
+    struct Quote(u32);
+
+    #[macros::infer_session_type]
+    fn customer(mut s: Session) {
+        s.send(Quote(120));
+    }
+
+    #[macros::infer_session_type]
+    fn agency(mut s: Session) {
+        s.receive::<Quote>();
+    }
+
+    let (c, a) = (Participant::named("C"), Participant::named("A"));
+    let global = merge_locals(Parties::new(vec![(c.clone(), get_mpst_session_type_customer().unwrap()), (a.clone(), get_mpst_session_type_agency().unwrap())])).unwrap();
+    assert_eq!(global.to_string(), "C -> A : Quote(u32). end");
+
+    // The same label with another payload is a different message
+    let sender = MPSTLocalType::send(a.clone(), Label::from("Quote(u32)"), MPSTLocalType::End);
+    let receiver = MPSTLocalType::receive(c.clone(), Label::from("Quote(String)"), MPSTLocalType::End);
+    assert!(merge_locals(Parties::new(vec![(c.clone(), sender), (a.clone(), receiver)])).is_err());
+
+    // So is a generic message instantiated with another type
+    #[derive(Serialize, Deserialize, Message)]
+    struct Wrapper<T>(T);
+
+    #[macros::infer_session_type]
+    fn wrap(mut s: Session) {
+        let wrapped: Wrapper<u32> = Wrapper(7);
+        s.send(wrapped);
+    }
+
+    #[macros::infer_session_type]
+    fn unwrap(mut s: Session) {
+        s.receive::<Wrapper<String>>();
+    }
+
+    // Labels are equal whatever their sorts, so the inferred sorts are checked as printed
+    assert_eq!(get_mpst_session_type_wrap().unwrap().to_string(), "?!{ Wrapper<u32>(u32). end }");
+    assert_eq!(get_mpst_session_type_unwrap().unwrap().to_string(), "??{ Wrapper<String>(String). end }");
+    let parties = Parties::new(vec![(c, get_mpst_session_type_wrap().unwrap()), (a, get_mpst_session_type_unwrap().unwrap())]);
+    assert!(merge_locals(parties).is_err());
+}
+
+#[test]
+fn global_types_as_text() {
+    let (a, b, c) = (Participant::named("A"), Participant::named("B"), Participant::named("C"));
+    let global: GlobalType = "mu Loop. A -> B : { Left. B -> C : Hello. Loop, Right. end }".parse().unwrap();
+    assert_eq!(global, GlobalType::RecX(1, Box::new(GlobalType::Select(a.clone(), b.clone(), vec![
+        (Label::from("Left"), GlobalType::Send(b.clone(), c.clone(), Label::from("Hello"), Box::new(GlobalType::X(1)))),
+        (Label::from("Right"), GlobalType::End),
+    ]))));
+    assert_eq!(global.to_string(), "mu X1. A -> B : { Left. B -> C : Hello. X1, Right. end }");
+    assert_eq!(global.to_string().parse::<GlobalType>().unwrap(), global);
+
+    // A send and a choice of one label read back as themselves
+    let send = GlobalType::Send(b.clone(), c.clone(), Label::from("Hello"), Box::new(GlobalType::End));
+    assert_eq!(send.to_string(), "B -> C : Hello. end");
+    assert_eq!(send.to_string().parse::<GlobalType>().unwrap(), send);
+    let select = GlobalType::Select(b, c, vec![(Label::from("Hello"), GlobalType::End)]);
+    assert_eq!(select.to_string(), "B -> C : { Hello. end }");
+    assert_eq!(select.to_string().parse::<GlobalType>().unwrap(), select);
+
+    // Merged types read back as themselves, and print the same, sorts included
+    let customer: MPSTLocalType = "A!{ Quote(u32). A?{ Reply::Accept. end, Reply::Reject(String). end } }".parse().unwrap();
+    let agency: MPSTLocalType = "C?{ Quote(u32). C!{ Reply::Accept. end, Reply::Reject(String). end } }".parse().unwrap();
+    let merged = merge_locals(Parties::new(vec![(Participant::named("C"), customer), (a, agency)])).unwrap();
+    let reparsed: GlobalType = merged.to_string().parse().unwrap();
+    assert_eq!(reparsed, merged);
+    // Labels are equal whatever their sorts, so the sorts are compared as printed
+    assert_eq!(reparsed.to_string(), merged.to_string());
+    assert!(merged.to_string().contains("Quote(u32)") && merged.to_string().contains("Reply::Reject(String)"));
+
+    let err = "A -> B Hello. end".parse::<GlobalType>().unwrap_err();
+    assert_eq!(err.to_string(), "1:8: Expected `:`");
+}
+
+#[test]
+fn recursive_sum() {
+    #[derive(Serialize, Deserialize, Message)]
+    enum Choice1 {
+        Add,
+        Req
+    }
+    #[derive(Serialize, Deserialize, Message)]
+    struct Sum;
+
     #[macros::infer_session_type]
     fn client(mut s: Session) {
         for _i in 1..10 {
@@ -211,6 +340,7 @@ fn recursive_sum() {
     }
 
     #[macros::infer_session_type]
+    #[allow(clippy::while_let_loop)]
     fn server(mut s: Session) {
         loop {
             match s.branch::<Choice1>() {
@@ -227,8 +357,8 @@ fn recursive_sum() {
 
     let client_role = Participant::new(Some(String::from("C")));
     let server_role = Participant::new(Some(String::from("S")));
-    println!("Client.LocalType: {}", get_session_type_client());
-    println!("Server.LocalType: {}", get_session_type_server());
+    println!("Client.LocalType: {}", get_session_type_client().unwrap());
+    println!("Server.LocalType: {}", get_session_type_server().unwrap());
 
     let client_mpst_local = get_mpst_session_type_client().unwrap();
     let server_mpst_local = get_mpst_session_type_server().unwrap();
@@ -239,34 +369,19 @@ fn recursive_sum() {
 
 #[test]
 fn test_triple_session_type() {
+    #[derive(Serialize, Deserialize, Message)]
     struct Hello;
+    #[derive(Serialize, Deserialize, Message)]
     enum Choice {
         Left,
         Right
     }
+    #[derive(Serialize, Deserialize, Message)]
     enum Choice2 {
         CLeft,
         CRight
     }
 
-    impl Message for Hello {
-        fn receive() -> Self {
-            Hello
-        }
-    }
-
-    impl Message for Choice {
-        fn receive() -> Self {
-            Choice::Left
-        }
-    }
-
-    impl Message for Choice2 {
-        fn receive() -> Self {
-            Choice2::CLeft
-        }
-    }
-
     #[macros::infer_session_type]
     fn A(mut s: Session) {
         s.send(Hello);
@@ -314,7 +429,22 @@ fn test_triple_session_type() {
     println!("B.MPSTLocalType: {}", b_mpst_local);
     println!("C.MPSTLocalType: {}", c_mpst_local);
 
-    println!("{}", merge_locals(Parties::new(vec![(a_role, a_mpst_local), (b_role, b_mpst_local), (c_role, c_mpst_local)])).unwrap());
+    println!("{}", merge_locals(Parties::new(vec![(a_role.clone(), a_mpst_local), (b_role.clone(), b_mpst_local), (c_role.clone(), c_mpst_local)])).unwrap());
+
+    let mut sessions = channel::connect(&[a_role, b_role.clone(), c_role.clone()]);
+    let c_session = sessions.pop().unwrap();
+    let mut b_session = sessions.pop().unwrap();
+    let mut a_session = sessions.pop().unwrap();
+    a_session.set_peer(b_role);
+    b_session.set_peer(c_role);
+    let handles = vec![
+        std::thread::spawn(move || B(b_session)),
+        std::thread::spawn(move || C(c_session)),
+    ];
+    A(a_session);
+    for handle in handles {
+        handle.join().unwrap();
+    }
 }
 
 #[test]
@@ -328,17 +458,12 @@ fn test_backtracking_triple() {
      * This is a test for backtracking. The initial duals are <A, B> and <A, C>, but <A, C> leads to a dead end, so we backtrack to <A, B>.
      * The backtracking behaviour might not be triggered if <A, B> is explored first.
      */
+    #[derive(Serialize, Deserialize, Message)]
     enum Choice {
         Left,
         Right
     }
 
-    impl Message for Choice {
-        fn receive() -> Self {
-            Choice::Left
-        }
-    }
-
     #[macros::infer_session_type]
     fn A(mut s: Session) {
         if env!("PATH").contains("debug") {
@@ -389,21 +514,11 @@ fn test_backtracking_triple() {
 
 #[test]
 fn test_recursive_triple() {
+    #[derive(Serialize, Deserialize, Message)]
     struct Msg1;
+    #[derive(Serialize, Deserialize, Message)]
     struct Msg2;
 
-    impl Message for Msg1 {
-        fn receive() -> Self {
-            Msg1
-        }
-    }
-
-    impl Message for Msg2 {
-        fn receive() -> Self {
-            Msg2
-        }
-    }
-
     #[macros::infer_session_type]
     fn A(mut s: Session) {
         loop {
@@ -443,28 +558,13 @@ fn test_recursive_triple() {
 
 #[test]
 fn eventually_synchronous_mpst() {
+    #[derive(Serialize, Deserialize, Message)]
     struct Hello;
+    #[derive(Serialize, Deserialize, Message)]
     struct Repeat1;
+    #[derive(Serialize, Deserialize, Message)]
     struct Repeat2;
 
-    impl Message for Hello {
-        fn receive() -> Self {
-            Hello
-        }
-    }
-
-    impl Message for Repeat1 {
-        fn receive() -> Self {
-            Repeat1
-        }
-    }
-
-    impl Message for Repeat2 {
-        fn receive() -> Self {
-            Repeat2
-        }
-    }
-
     #[macros::infer_session_type]
     fn A(mut s: Session) {
         s.send(Hello);
@@ -519,38 +619,28 @@ fn nested_recursion() {
     // Local type A: RecX { Send<B, Hi, RecY { Branch<B, {1. X, 2. Y} } } }
     // Local type B: RecY { Receive<A, Hi, RecX { Select<A, {1. X, 2. Y} } } }
 
+    #[derive(Serialize, Deserialize, Message)]
     struct Hi;
+    #[derive(Serialize, Deserialize, Message)]
     enum Choice {
         RepeatX,
         RepeatY
     }
 
-    impl Message for Hi {
-        fn receive() -> Self {
-            Hi
-        }
-    }
-
-    impl Message for Choice {
-        fn receive() -> Self {
-            Choice::RepeatX
-        }
-    }
-
     let anon = Participant::anonymous();
 
     let ltA = MPSTLocalType::recX_with_id(
         Box::new(
             MPSTLocalType::send(
                 anon.clone(),
-                String::from("Hi"),
+                Label::from("Hi"),
                 MPSTLocalType::recX_with_id(
                     Box::new(
                         MPSTLocalType::Branch(
                             anon.clone(),
                             vec![
-                                (String::from("RepeatX"), MPSTLocalType::x_with_id(1)),
-                                (String::from("RepeatY"), MPSTLocalType::x_with_id(2))
+                                (Label::from("RepeatX"), MPSTLocalType::x_with_id(1)),
+                                (Label::from("RepeatY"), MPSTLocalType::x_with_id(2))
                             ]
                         )
                     ),
@@ -565,14 +655,14 @@ fn nested_recursion() {
         Box::new(
             MPSTLocalType::receive(
                 anon.clone(),
-                String::from("Hi"),
+                Label::from("Hi"),
                 MPSTLocalType::recX_with_id(
                     Box::new(
                         MPSTLocalType::Select(
                             anon.clone(),
                             vec![
-                                (String::from("RepeatX"), MPSTLocalType::x_with_id(1)),
-                                (String::from("RepeatY"), MPSTLocalType::x_with_id(2))
+                                (Label::from("RepeatX"), MPSTLocalType::x_with_id(1)),
+                                (Label::from("RepeatY"), MPSTLocalType::x_with_id(2))
                             ]
                         )
                     ),
@@ -591,21 +681,11 @@ fn nested_recursion() {
 
 #[test]
 fn unsynchronised_recursion() {
+    #[derive(Serialize, Deserialize, Message)]
     struct Hi;
+    #[derive(Serialize, Deserialize, Message)]
     struct Hello;
 
-    impl Message for Hi {
-        fn receive() -> Self {
-            Hi
-        }
-    }
-
-    impl Message for Hello {
-        fn receive() -> Self {
-            Hello
-        }
-    }
-
     #[macros::infer_session_type]
     fn A(mut s: Session) {
         s.send(Hi);
@@ -635,3 +715,155 @@ fn unsynchronised_recursion() {
     println!("{}", merge_locals(Parties::new(vec![(a_role, a_mpst_local), (b_role, b_mpst_local)])).unwrap());
 }
 
+
+#[test]
+fn role_addressed_triple() {
+    // Both A and B send Hello, so without roles C's receive could be paired with either sender
+    #[derive(Serialize, Deserialize, Message)]
+    struct Hello;
+    #[derive(Serialize, Deserialize, Message)]
+    struct Bye;
+
+    #[macros::infer_session_type]
+    fn A(mut s: Session) {
+        s.send_to("B", Hello);
+        s.receive_from::<Bye>("C");
+    }
+
+    #[macros::infer_session_type]
+    fn B(mut s: Session) {
+        s.receive_from::<Hello>("A");
+        s.send_to("C", Hello);
+    }
+
+    #[macros::infer_session_type]
+    fn C(mut s: Session) {
+        s.receive_from::<Hello>("B");
+        s.send_to("A", Bye);
+    }
+
+    let a_role = Participant::named("A");
+    let b_role = Participant::named("B");
+    let c_role = Participant::named("C");
+
+    let a_mpst_local = get_mpst_session_type_A().unwrap();
+    let b_mpst_local = get_mpst_session_type_B().unwrap();
+    let c_mpst_local = get_mpst_session_type_C().unwrap();
+
+    assert_eq!(a_mpst_local, MPSTLocalType::send(b_role.clone(), Label::from("Hello"), MPSTLocalType::receive(c_role.clone(), Label::from("Bye"), MPSTLocalType::End)));
+
+    let global = merge_locals(Parties::new(vec![(a_role.clone(), a_mpst_local), (b_role.clone(), b_mpst_local), (c_role.clone(), c_mpst_local)])).unwrap();
+    assert_eq!(global.to_string(), "A -> B : Hello. B -> C : Hello. C -> A : Bye. end");
+
+    let mut sessions = channel::connect(&[a_role, b_role, c_role]);
+    let c_session = sessions.pop().unwrap();
+    let b_session = sessions.pop().unwrap();
+    let a_session = sessions.pop().unwrap();
+    let handles = vec![
+        std::thread::spawn(move || B(b_session)),
+        std::thread::spawn(move || C(c_session)),
+    ];
+    A(a_session);
+    for handle in handles {
+        handle.join().unwrap();
+    }
+}
+
+#[test]
+fn simulate_merged_participants() {
+    use session::{simulate::{Exhaustive, Simulation}, AsyncSession};
+
+    #[derive(Serialize, Deserialize, Message)]
+    enum Request {
+        Add,
+        Req
+    }
+    #[derive(Serialize, Deserialize, Message)]
+    struct Sum;
+
+    #[macros::infer_session_type]
+    async fn client(mut s: AsyncSession) {
+        for _i in 1..3 {
+            s.send(Request::Add).await;
+        }
+        s.send(Request::Req).await;
+        s.receive::<Sum>().await;
+    }
+
+    #[macros::infer_session_type]
+    #[allow(clippy::while_let_loop)]
+    async fn server(mut s: AsyncSession) {
+        loop {
+            match s.branch::<Request>().await {
+                Request::Add => {}
+                Request::Req => {
+                    break;
+                }
+            }
+        }
+        s.send(Sum).await;
+    }
+
+    let parties = Parties::new(vec![
+        (Participant::named("C"), get_mpst_session_type_client().unwrap()),
+        (Participant::named("S"), get_mpst_session_type_server().unwrap()),
+    ]);
+    merge_locals(parties).unwrap();
+    // Then run the same programs the protocol was merged from, under every schedule
+    let report = Simulation::new().participant("C", client).participant("S", server).run(&mut Exhaustive::default()).unwrap();
+    assert!(report.runs > 1);
+}
+
+#[test]
+fn merge_logs_backtracking() {
+    #[derive(Serialize, Deserialize, Message)]
+    enum Choice {
+        Left,
+        Right
+    }
+
+    #[macros::infer_session_type]
+    fn A(mut s: Session) {
+        if env!("PATH").contains("debug") {
+            s.send(Choice::Left);
+        } else {
+            s.send(Choice::Right);
+        }
+    }
+
+    #[macros::infer_session_type]
+    fn B(mut s: Session) {
+        match s.branch_from::<Choice>("A") {
+            Choice::Left => {
+                s.send_to("L", Choice::Left);
+            }
+            Choice::Right => {
+                s.send_to("L", Choice::Right);
+            }
+        }
+    }
+
+    #[macros::infer_session_type]
+    fn C(mut s: Session) {
+        match s.branch::<Choice>() {
+            Choice::Left => {}
+            Choice::Right => {}
+        }
+    }
+
+    let parties = Parties::new(vec![
+        (Participant::named("A"), get_mpst_session_type_A().unwrap()),
+        (Participant::named("M"), get_mpst_session_type_B().unwrap()),
+        (Participant::named("L"), get_mpst_session_type_C().unwrap()),
+    ]);
+    let (global, events) = session::log::capture(|| merge_locals(parties));
+    global.unwrap();
+
+    // L could be reading from A, and sorts first, so that dual is tried and abandoned before M's
+
+    let backtrack = events.iter().find(|event| event.message.starts_with("backtracking")).unwrap();
+    assert_eq!(backtrack.fields["p1"], "L");
+    assert_eq!(backtrack.fields["p2"], "A");
+    assert!(backtrack.in_span("merge"));
+    assert!(events.iter().any(|event| event.in_span("dual") && event.in_span("merge")));
+}
@@ -1,55 +1,28 @@
-#[cfg(test)]
+#![allow(dead_code)]
 
-use session::{session_type::{MPSTLocalType, Participant}, Session, Message};
+use macros::Message;
+use session::{channel, session_type::Participant, Session};
 use inference::merging::*;
+use serde::{Deserialize, Serialize};
 
 #[test]
 fn travel_agency() {
+    #[derive(Serialize, Deserialize, Message)]
     struct Order {
         max_distance_km: u32
     }
+    #[derive(Serialize, Deserialize, Message)]
     struct Quote(u32);
+    #[derive(Serialize, Deserialize, Message)]
     enum Response { 
         Accept(bool),
         Reject(i32)
     }
+    #[derive(Serialize, Deserialize, Message)]
     struct Address(String);
+    #[derive(Serialize, Deserialize, Message)]
     struct Date(u32);
 
-    let x = Date(3);
-
-    impl Message for Order {
-        fn receive() -> Self {
-            Order {
-                max_distance_km: 0
-            }
-        }
-    }
-
-    impl Message for Quote {
-        fn receive() -> Self {
-            Quote(0)
-        }
-    }
-
-    impl Message for Response {
-        fn receive() -> Self {
-            Response::Accept(false)
-        }
-    }
-
-    impl Message for Address {
-        fn receive() -> Self {
-            Address("".into())
-        }
-    }
-
-    impl Message for Date {
-        fn receive() -> Self {
-            Date(0)
-        }
-    }
-
     #[macros::infer_session_type]
     fn client(mut s: Session) {
         let max_distance_km = 30;
@@ -93,5 +66,12 @@ fn travel_agency() {
     println!("Client.MPSTLocalType: {}", client_mpst_local);
     println!("Agency.MPSTLocalType: {}", agency_mpst_local);
 
-    println!("{}", merge_locals(Parties::new(vec![(client_role, client_mpst_local), (agency_role, agency_mpst_local)])).unwrap());
+    println!("{}", merge_locals(Parties::new(vec![(client_role.clone(), client_mpst_local), (agency_role.clone(), agency_mpst_local)])).unwrap());
+
+    let mut sessions = channel::connect(&[client_role, agency_role]);
+    let agency_session = sessions.pop().unwrap();
+    let client_session = sessions.pop().unwrap();
+    let agency_handle = std::thread::spawn(move || agency(agency_session));
+    client(client_session);
+    agency_handle.join().unwrap();
 }
\ No newline at end of file
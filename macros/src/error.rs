@@ -0,0 +1,104 @@
+use std::fmt::Display;
+
+use proc_macro2::Span;
+
+/// Why a piece of code could not be read as part of a session type.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// An expression the inference does not know how to read, named by what kind of expression it is
+    UnsupportedConstruct(&'static str),
+    /// A `send` whose message type cannot be found from its argument or a turbofish
+    InvalidSendArgument,
+    /// A receive without a turbofish naming the message type, for the named method
+    MissingTurbofish(String),
+    /// A receive whose turbofish is not a type a message can have
+    InvalidReceiveType,
+    /// A role-addressed method whose role is not a string literal
+    RoleNotLiteral(String),
+    /// A method on the session that does not send or receive
+    UnknownSessionMethod(String),
+    /// A match arm or `let` pattern on a `branch` that has no label
+    InvalidMatchArm,
+    /// The parts were read, but do not make a valid local type
+    InvalidType(String),
+}
+
+/// One error, with the code it is about.
+#[derive(Debug, Clone)]
+pub struct InferError {
+    pub span: Span,
+    pub kind: ErrorKind,
+}
+
+/// Every error found in a function, so they can all be reported at once.
+#[derive(Debug, Clone, Default)]
+pub struct InferErrors(pub Vec<InferError>);
+
+impl Display for ErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ErrorKind::UnsupportedConstruct(construct) => write!(f, "Unsupported Rust construct: {}", construct),
+            ErrorKind::InvalidSendArgument => write!(f, "Invalid send call, the message must be a constructor, a call, or a local whose type is annotated with a concrete type, otherwise name its type as `send::<T>`"),
+            ErrorKind::MissingTurbofish(method) => write!(f, "{} call has no turbofish, the message type must be given as `{}::<T>`", method, method),
+            ErrorKind::InvalidReceiveType => write!(f, "Invalid receive call, the message type must be a path, tuple or array type"),
+            ErrorKind::RoleNotLiteral(method) => write!(f, "The role in {} must be a string literal", method),
+            ErrorKind::UnknownSessionMethod(method) => write!(f, "Invalid method call: {} is not a session method", method),
+            ErrorKind::InvalidMatchArm => write!(f, "Invalid pattern on a branch, the pattern must name the variant received"),
+            ErrorKind::InvalidType(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl InferError {
+    pub fn new(span: Span, kind: ErrorKind) -> InferError {
+        InferError { span, kind }
+    }
+}
+
+impl From<InferError> for InferErrors {
+    fn from(err: InferError) -> Self {
+        InferErrors(vec![err])
+    }
+}
+
+impl InferErrors {
+    /// Keeps going after a failure, so the errors of every result are reported, not just the first.
+    pub fn collect<T>(results: impl IntoIterator<Item = Result<T, InferErrors>>) -> Result<Vec<T>, InferErrors> {
+        let mut values = Vec::new();
+        let mut errors = Vec::new();
+        for result in results {
+            match result {
+                Ok(value) => values.push(value),
+                Err(InferErrors(errs)) => errors.extend(errs),
+            }
+        }
+        if errors.is_empty() {
+            Ok(values)
+        } else {
+            Err(InferErrors(errors))
+        }
+    }
+
+    /// Combines two independent results, keeping the errors of both.
+    pub fn join<A, B>(a: Result<A, InferErrors>, b: Result<B, InferErrors>) -> Result<(A, B), InferErrors> {
+        match (a, b) {
+            (Ok(a), Ok(b)) => Ok((a, b)),
+            (Err(InferErrors(mut a)), Err(InferErrors(b))) => {
+                a.extend(b);
+                Err(InferErrors(a))
+            },
+            (Err(err), _) | (_, Err(err)) => Err(err),
+        }
+    }
+
+    pub fn to_compile_error(&self) -> proc_macro2::TokenStream {
+        self.0.iter()
+            .map(|err| syn::Error::new(err.span, &err.kind))
+            .reduce(|mut combined, err| {
+                combined.combine(err);
+                combined
+            })
+            .map(|err| err.to_compile_error())
+            .unwrap_or_default()
+    }
+}
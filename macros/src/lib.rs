@@ -2,52 +2,125 @@ extern crate proc_macro;
 
 use proc_macro::TokenStream;
 use quote::format_ident;
-use quote::ToTokens;
-use quote::quote;
 use session::ilt::PartialLocalType;
 
 mod parse;
+mod message;
+mod error;
 
 #[proc_macro_attribute]
-pub fn infer_session_type(_attr: TokenStream, item: TokenStream) -> TokenStream {
+pub fn infer_session_type(attr: TokenStream, item: TokenStream) -> TokenStream {
+    // `#[infer_session_type(session = Alias)]` names other types the session parameter can have,
+    // and `helper = path` an annotated function whose protocol is spliced in where the session is passed to it
+    let mut aliases: Vec<syn::Ident> = Vec::new();
+    let mut helpers: Vec<syn::Path> = Vec::new();
+    let attr_parser = syn::meta::parser(|meta| {
+        if meta.path.is_ident("session") {
+            aliases.push(meta.value()?.parse()?);
+            Ok(())
+        } else if meta.path.is_ident("helper") {
+            helpers.push(meta.value()?.parse()?);
+            Ok(())
+        } else {
+            Err(meta.error("Unsupported argument, expected `session = Alias` or `helper = path`"))
+        }
+    });
+    syn::parse_macro_input!(attr with attr_parser);
+
     let item = syn::parse_macro_input!(item as syn::ItemFn);
     let fn_ident = item.sig.ident.to_string();
-    println!("Processing {}", fn_ident);
-    let local_type = match parse::infer_block_session_type(&item.block, 0).as_ref().map(PartialLocalType::to_local_type) {
-        Ok(Ok(local_type)) => local_type,
-        Ok(Err(err)) => panic!("Error: {}", err),
-        Err(err) => panic!("Error: {}", err)
+    #[cfg(feature = "log")]
+    session::log::init_from_env();
+    let _span = tracing::debug_span!("infer_session_type", function = %fn_ident).entered();
+    let session_ident = match parse::get_session_arg(&item.sig, &aliases) {
+        Ok(session_ident) => session_ident,
+        Err(err) => {
+            let err = err.to_compile_error();
+            return quote::quote! { #item #err }.into();
+        }
+    };
+    // Calls to other annotated functions can only be spliced in at runtime, and the labels of message types are only known then, so such types are built then
+    let rec_ids = parse::RecIds::default();
+    let messages = parse::Messages::of_params(&item.sig);
+    let inferred = parse::infer_block_session_type(&item.block, std::slice::from_ref(&session_ident), 0, &[], &rec_ids, &messages, &helpers).and_then(|partial| {
+        // Returning ends the function's protocol, and a caller's carries on from there
+        let partial = partial.map_return_to(PartialLocalType::End);
+        if partial.resolved_at_runtime() {
+            return Ok((partial, None));
+        }
+        // Breaks and continues outside a loop are reported at their expression, so this can only fail on a type that carries no spans,
+        // which is why it points at the function
+        let local_type = partial.to_local_type()
+            .map_err(|err| error::InferError::new(item.sig.ident.span(), error::ErrorKind::InvalidType(err)))?;
+        Ok((partial, Some(local_type)))
+    });
+    let (partial, local_type) = match inferred {
+        Ok(inferred) => inferred,
+        Err(errors) => {
+            tracing::debug!(errors = errors.0.len(), "inference failed");
+            // Keep the function, so the only errors are the ones about its session
+            let errors = errors.to_compile_error();
+            return quote::quote! { #item #errors }.into();
+        }
     };
-    let ilt_tokens: proc_macro2::TokenStream = local_type.to_syn_ast().to_token_stream();
-    println!("{}", ilt_tokens);
     let session_type_id = format_ident!("get_session_type_{}", fn_ident);
-
     let mpst_session_type_id = format_ident!("get_mpst_session_type_{}", fn_ident);
-    let mpst_session_type_tokens: proc_macro2::TokenStream = match local_type.to_session_type() {
-        Ok(rs_type) => {
-            println!("MPST output: {}", rs_type);
-            let rs_type = rs_type.to_syn_ast();
-            syn::parse_quote! {
-                Ok(#rs_type)
-            }
+    let fragment_id = session::ilt::fragment_ident(&fn_ident);
+    // The variables the generated code builds the types with
+    let (sorts_id, offset_id, fragments_id) = (format_ident!("sorts"), format_ident!("offset"), format_ident!("fragments"));
+    let fragment_tokens = partial.to_syn_ast(&sorts_id, &offset_id, &fragments_id);
+    let rec_count = rec_ids.count();
+    let vis = &item.vis;
+    // The labels of the message types the function uses, which the labels of its type take their sorts from
+    let message_types = messages.types();
+    let sorts = quote::quote! {
+        #[allow(unused_variables)]
+        let #sorts_id: Vec<::session::session_type::Label> = ::std::iter::empty()
+            #(.chain(<#message_types as ::session::Message>::qualified_labels()))*
+            .collect();
+    };
+
+    let (ilt_tokens, mpst_session_type_tokens): (proc_macro2::TokenStream, proc_macro2::TokenStream) = match local_type {
+        Some(local_type) => {
+            tracing::debug!(%local_type, "inferred local type");
+            let ilt_tokens = local_type.to_syn_ast(&sorts_id);
+            let ilt_tokens = quote::quote! { Ok(#ilt_tokens) };
+            let mpst_session_type_tokens = match local_type.to_session_type() {
+                Ok(rs_type) => {
+                    tracing::debug!(mpst_type = %rs_type, "converted to MPST local type");
+                    let rs_type = rs_type.to_syn_ast(&sorts_id);
+                    syn::parse_quote! {
+                        Ok(#rs_type)
+                    }
+                },
+                Err(err) => {
+                    tracing::debug!(%err, "no MPST local type");
+                    syn::parse_quote! {
+                        Err(String::from(#err))
+                    }
+                }
+            };
+            (ilt_tokens, mpst_session_type_tokens)
+        },
+        None => {
+            tracing::debug!(?partial, "inferred parts only known at runtime, building it then");
+            // The function is the outermost fragment, so its loops keep their ids
+            (
+                quote::quote! { #fragment_id(&mut Default::default()).to_local_type() },
+                quote::quote! { #fragment_id(&mut Default::default()).to_local_type()?.to_session_type() },
+            )
         },
-        Err(err) => {
-            syn::parse_quote! {
-                Err(String::from(#err))
-            }
-        }
     };
-    println!("{}", mpst_session_type_tokens);
 
-    println!("{}", session_type_id);
     (quote::quote! {
         #item
 
-        fn #session_type_id () -> ::session::ilt::LocalType { 
+        fn #session_type_id () -> Result<::session::ilt::LocalType, String> {
             use ::session::ilt::LocalType;
             use ::session::ilt::LocalType::*;
             use ::session::session_type::Participant;
 
+            #sorts
             #ilt_tokens
         }
 
@@ -56,8 +129,25 @@ pub fn infer_session_type(_attr: TokenStream, item: TokenStream) -> TokenStream
             use ::session::session_type::MPSTLocalType;
             use ::session::session_type::MPSTLocalType::*;
 
+            #sorts
             #mpst_session_type_tokens
         }
+
+        /// Builds the type of the annotated function, for the functions it is called from.
+        #[doc(hidden)]
+        #[allow(dead_code, non_snake_case)]
+        #vis fn #fragment_id (#fragments_id: &mut ::session::ilt::Fragments) -> ::session::ilt::PartialLocalType {
+            #sorts
+            ::session::ilt::PartialLocalType::fragment(concat!(module_path!(), "::", #fn_ident), #fragments_id, #rec_count, |#fragments_id, #offset_id| #fragment_tokens)
+        }
     }).into()
-    // (String::from("fn print_session_type() { println!(\"{}\", \"") + &output.to_string() + "\") }").parse().unwrap()
-}
\ No newline at end of file
+}
+
+#[proc_macro_derive(Message)]
+pub fn derive_message(item: TokenStream) -> TokenStream {
+    let item = syn::parse_macro_input!(item as syn::DeriveInput);
+    match message::derive_message(&item) {
+        Ok(tokens) => tokens.into(),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
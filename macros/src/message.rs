@@ -0,0 +1,156 @@
+use quote::{quote, ToTokens};
+
+/// Implements `session::Message` with fixed labels: the type name for structs, and the variant name for each enum variant.
+/// A generic struct is labelled with its generic arguments, e.g. `Wrapper<u32>`, named by their `wire::TypeName`, which the derive also implements.
+/// The variants of a generic enum are qualified by its name alone, as patterns and constructors name them.
+/// These are the labels `infer_session_type` reads from struct constructors, `receive::<T>()` and match arms on `branch::<T>()`,
+/// and the qualified labels name the enum of each variant, as the inferred types do, along with the sort of its fields.
+pub fn derive_message(input: &syn::DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+    let ident = &input.ident;
+    let message = ident.to_string();
+    // The qualified labels are built in the same order, and by the same match, as the short ones
+    let (labels, qualified_labels, label_body, qualified_label_body): (Vec<proc_macro2::TokenStream>, Vec<proc_macro2::TokenStream>, proc_macro2::TokenStream, proc_macro2::TokenStream) = match &input.data {
+        syn::Data::Struct(data) => {
+            let sort = sort(&data.fields, &input.generics);
+            let label = match input.generics.type_params().next().is_some() || input.generics.const_params().next().is_some() {
+                true => quote! { <Self as ::session::wire::TypeName>::type_name() },
+                false => quote! { String::from(#message) },
+            };
+            let qualified = quote! { ::session::session_type::Label::message(&#label).with_sort(#sort) };
+            (vec![label.clone()], vec![qualified.clone()], label, qualified)
+        },
+        syn::Data::Enum(data) => {
+            let labels: Vec<String> = data.variants.iter().map(|variant| variant.ident.to_string()).collect();
+            let qualified_labels: Vec<proc_macro2::TokenStream> = data.variants.iter().zip(&labels)
+                .map(|(variant, label)| {
+                    let sort = sort(&variant.fields, &input.generics);
+                    quote! { ::session::session_type::Label::variant(#message, #label).with_sort(#sort) }
+                })
+                .collect();
+            let variants: Vec<&syn::Ident> = data.variants.iter().map(|variant| &variant.ident).collect();
+            (labels.iter().map(|label| quote! { String::from(#label) }).collect(), qualified_labels.clone(), quote! {
+                match self {
+                    #(Self::#variants { .. } => String::from(#labels),)*
+                }
+            }, quote! {
+                match self {
+                    #(Self::#variants { .. } => #qualified_labels,)*
+                }
+            })
+        },
+        syn::Data::Union(_) => return Err(syn::Error::new_spanned(ident, "Message cannot be derived for unions")),
+    };
+
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    let mut where_clause = where_clause.cloned().unwrap_or_else(|| syn::parse_quote! { where });
+    // The type is named after its declaration, and its generic arguments after their own names
+    for param in input.generics.type_params() {
+        let param = &param.ident;
+        where_clause.predicates.push(syn::parse_quote! { #param: ::session::wire::TypeName });
+    }
+    let name_where_clause = where_clause.clone();
+    let args: Vec<proc_macro2::TokenStream> = input.generics.params.iter().filter_map(|param| match param {
+        syn::GenericParam::Type(param) => {
+            let param = &param.ident;
+            Some(quote! { <#param as ::session::wire::TypeName>::type_name() })
+        },
+        syn::GenericParam::Const(param) => {
+            let param = &param.ident;
+            Some(quote! { #param.to_string() })
+        },
+        syn::GenericParam::Lifetime(_) => None,
+    }).collect();
+    let type_name = match args.is_empty() {
+        true => quote! { String::from(#message) },
+        false => quote! { ::std::format!("{}<{}>", #message, [#(#args),*].join(", ")) },
+    };
+    where_clause.predicates.push(syn::parse_quote! {
+        Self: ::session::serde::Serialize + ::session::serde::de::DeserializeOwned + ::session::wire::TypeName
+    });
+
+    Ok(quote! {
+        impl #impl_generics ::session::wire::TypeName for #ident #ty_generics #name_where_clause {
+            fn type_name() -> String {
+                #type_name
+            }
+        }
+
+        impl #impl_generics ::session::Message for #ident #ty_generics #where_clause {
+            fn labels() -> Vec<String> {
+                vec![#(#labels),*]
+            }
+
+            fn label(&self) -> String {
+                #label_body
+            }
+
+            fn qualified_labels() -> Vec<::session::session_type::Label> {
+                vec![#(#qualified_labels),*]
+            }
+
+            fn qualified_label(&self) -> ::session::session_type::Label {
+                #qualified_label_body
+            }
+        }
+    })
+}
+
+/// The sort of a message's payload, its fields as they are declared after its name: `(u32)`, `{ price: u32 }`, or empty for a unit.
+/// A field whose type uses the generic parameters is written as it is instantiated, `(u32)` rather than `(T)` for a `Wrapper<u32>`,
+/// so the sort is built at runtime.
+fn sort(fields: &syn::Fields, generics: &syn::Generics) -> proc_macro2::TokenStream {
+    let (open, close, fields): (&str, &str, Vec<(String, &syn::Type)>) = match fields {
+        syn::Fields::Named(fields) => ("{ ", " }", fields.named.iter()
+            .map(|field| (format!("{}: ", field.ident.as_ref().expect("named fields have names")), &field.ty))
+            .collect()),
+        syn::Fields::Unnamed(fields) => ("(", ")", fields.unnamed.iter().map(|field| (String::new(), &field.ty)).collect()),
+        syn::Fields::Unit => return quote! { "" },
+    };
+    if !fields.iter().any(|(_, ty)| uses_generics(ty, generics)) {
+        let fields: Vec<String> = fields.iter().map(|(name, ty)| format!("{}{}", name, type_name(ty))).collect();
+        let sort = format!("{}{}{}", open, fields.join(", "), close);
+        return quote! { #sort };
+    }
+    let fields = fields.iter().map(|(name, ty)| match uses_generics(ty, generics) {
+        true => quote! { ::std::format!("{}{}", #name, <#ty as ::session::wire::TypeName>::type_name()) },
+        false => {
+            let field = format!("{}{}", name, type_name(ty));
+            quote! { String::from(#field) }
+        },
+    });
+    quote! { &::std::format!("{}{}{}", #open, [#(#fields),*].join(", "), #close) }
+}
+
+/// Whether `ty` names one of the type's generic parameters.
+fn uses_generics(ty: &syn::Type, generics: &syn::Generics) -> bool {
+    fn uses(tokens: proc_macro2::TokenStream, params: &[&syn::Ident]) -> bool {
+        tokens.into_iter().any(|token| match token {
+            proc_macro2::TokenTree::Ident(ident) => params.contains(&&ident),
+            proc_macro2::TokenTree::Group(group) => uses(group.stream(), params),
+            _ => false,
+        })
+    }
+    let params: Vec<&syn::Ident> = generics.type_params().map(|param| &param.ident)
+        .chain(generics.const_params().map(|param| &param.ident))
+        .collect();
+    uses(ty.to_token_stream(), &params)
+}
+
+/// How a type is written, with the spaces `to_string` puts between every token removed, so `Vec<u32>` is not `Vec < u32 >`.
+pub fn type_name(ty: &syn::Type) -> String {
+    let tokens: Vec<char> = ty.to_token_stream().to_string().chars().collect();
+    let word = |c: char| c.is_alphanumeric() || c == '_' || c == '\'';
+    let mut name = String::new();
+    for (i, c) in tokens.iter().enumerate() {
+        if *c == ' ' {
+            // Spaces only separate words, such as `dyn Trait`, and follow commas
+            let after = name.chars().last().unwrap_or(',');
+            let before = tokens.get(i + 1).copied().unwrap_or(' ');
+            if !((word(after) && word(before)) || after == ',' || after == ';') {
+                continue;
+            }
+        }
+        name.push(*c);
+    }
+    name
+}
@@ -1,118 +1,118 @@
+use std::cell::{Cell, RefCell};
+
 use quote::ToTokens;
-use syn::{punctuated::Punctuated, FnArg, token::Comma, spanned::Spanned};
+use proc_macro2::Span;
+use syn::spanned::Spanned;
+
+use session::{ilt::{fragment_ident, PartialLocalType, Tokens}, session_type::{Label, Participant}};
 
-use session::ilt::{LocalType, PartialLocalType};
+use crate::{error::{ErrorKind, InferError, InferErrors}, message::type_name};
 
-pub fn infer_block_session_type(item: &syn::Block, rec_id: i32) -> Result<PartialLocalType, String> {
-    let session_var = "s";
-    let mut actions: Vec<PartialLocalType> = vec![];
+pub fn infer_block_session_type(item: &syn::Block, sessions: &Sessions, rec_id: i32, labels: &Labels, ids: &RecIds, messages: &Messages, helpers: &[syn::Path]) -> Result<PartialLocalType, InferErrors> {
+    // The session can be borrowed or moved into other bindings part way through the block
+    let mut session_vars = sessions.to_vec();
+    let scope = messages.enter();
+    let mut actions: Vec<Result<Option<PartialLocalType>, InferErrors>> = vec![];
     for stmt in &item.stmts {
         match stmt {
             syn::Stmt::Expr(expr, _tok) => {
-                if let Some(action) = gen_session_type(expr, session_var, rec_id)? {
-                    actions.push(action);
-                }
+                actions.push(gen_session_type(expr, &session_vars, rec_id, labels, ids, messages, helpers));
             }
             syn::Stmt::Local(local) => {
-                if let Some(action) = gen_session_type(local.init.clone().unwrap().expr.as_ref(), session_var, rec_id)? {
-                    actions.push(action);
+                let Some(init) = &local.init else {
+                    messages.bind(&local.pat, None);
+                    continue;
+                };
+                match &init.diverge {
+                    // The rest of the block follows a match, and the else block has to leave it
+                    Some((_, else_expr)) => {
+                        let else_type = gen_session_type(else_expr, &session_vars, rec_id, labels, ids, messages, helpers)
+                            .map(|else_type| diverging(else_type.unwrap_or(PartialLocalType::End)));
+                        actions.push(pattern_choice(&local.pat, &init.expr, Ok(PartialLocalType::End), else_type, &session_vars, rec_id, labels, ids, messages, helpers).map(Some));
+                    }
+                    None => actions.push(gen_session_type(init.expr.as_ref(), &session_vars, rec_id, labels, ids, messages, helpers)),
+                }
+                messages.bind(&local.pat, Some(&init.expr));
+                if let syn::Pat::Ident(pat) = strip_pat_type(&local.pat) {
+                    let name = pat.ident.to_string();
+                    // A borrow leaves the session usable by its old name once the borrow ends, so every name is kept
+                    let is_session = refers_to_session(&init.expr, &session_vars);
+                    // Shadowed by something else, so the session cannot be used by this name from here on
+                    session_vars.retain(|var| *var != name);
+                    if is_session {
+                        session_vars.push(name);
+                    }
                 }
             }
             _ => {}
         }
     }
 
-    sequence_session_types(actions)
+    messages.leave(scope);
+    let actions = InferErrors::collect(actions)?;
+    Ok(sequence_session_types(actions.into_iter().flatten().collect()))
 }
 
-pub fn gen_session_type(expr: &syn::Expr, session_ident: &str, rec_id: i32) -> Result<Option<PartialLocalType>, String> {
+/// Infers each expression in turn, reporting the errors of all of them, and sequences what they do.
+fn sequence_exprs<'a>(exprs: impl IntoIterator<Item = &'a syn::Expr>, sessions: &Sessions, rec_id: i32, labels: &Labels, ids: &RecIds, messages: &Messages, helpers: &[syn::Path]) -> Result<PartialLocalType, InferErrors> {
+    let types = InferErrors::collect(exprs.into_iter().map(|expr| gen_session_type(expr, sessions, rec_id, labels, ids, messages, helpers)))?;
+    Ok(sequence_session_types(types.into_iter().flatten().collect()))
+}
+
+pub fn gen_session_type(expr: &syn::Expr, sessions: &Sessions, rec_id: i32, labels: &Labels, ids: &RecIds, messages: &Messages, helpers: &[syn::Path]) -> Result<Option<PartialLocalType>, InferErrors> {
     use PartialLocalType::*;
-    // println!("{:?}", expr.span().source_text());
+    let _span = tracing::trace_span!("infer", construct = construct_name(expr)).entered();
     match expr {
         syn::Expr::Call(call) => {
-            // println!("Parsing call args for {:?}", call.func.span().source_text());
-            let arg_psts: Result<Vec<Option<PartialLocalType>>, String> = call.args.iter().map(|arg| gen_session_type(arg, session_ident, rec_id)).collect();
-            let arg_psts: Vec<PartialLocalType> = arg_psts?.iter().filter_map(|arg| arg.clone()).collect();
-            let arg_combined_pst = sequence_session_types(arg_psts)?;
+            let (arg_combined_pst, call_pst) = InferErrors::join(
+                sequence_exprs(&call.args, sessions, rec_id, labels, ids, messages, helpers),
+                gen_session_type(&call.func, sessions, rec_id, labels, ids, messages, helpers),
+            )?;
 
-            let call_pst = gen_session_type(&call.func, session_ident, rec_id)?.unwrap_or(End);
+            // Passing the session to a helper named in the attribute runs its protocol, which is spliced in at runtime.
+            // Other functions, such as `drop(s)`, are taken to do nothing with it
+            if let syn::Expr::Path(path) = &*call.func {
+                if is_helper(&path.path, helpers) && call.args.iter().any(|arg| refers_to_session(arg, sessions)) {
+                    // The callee is named by the path it was called with, with its last segment replaced by the generated function
+                    let mut callee = path.path.clone();
+                    if let Some(last) = callee.segments.last_mut() {
+                        last.ident = fragment_ident(&last.ident.to_string());
+                    }
+                    let fragment = Fragment(Tokens(callee.to_token_stream()), Box::new(End));
+                    return Ok(Some(arg_combined_pst.map_end_to(fragment)));
+                }
+            }
 
-            Ok(Some(call_pst.map_end_to(arg_combined_pst)))
+            Ok(Some(call_pst.unwrap_or(End).map_end_to(arg_combined_pst)))
         }
         syn::Expr::MethodCall(method_call) => {
             // Parse method call's argument local types
-            // println!("Parsing method call args for {:?}", method_call.method.to_string());
-            let arg_psts: Result<Vec<_>, _> = method_call.args.iter().map(|arg| gen_session_type(arg, session_ident, rec_id)).collect();
-            let arg_psts: Vec<PartialLocalType> = arg_psts?.iter().filter_map(|arg| arg.clone()).collect();
-            let arg_combined_pst = sequence_session_types(arg_psts)?;
+            let arg_combined_pst = sequence_exprs(&method_call.args, sessions, rec_id, labels, ids, messages, helpers);
 
             // Parse method call's receiver to send or receive
-            let session_call: Result<Option<PartialLocalType>, String> = if let syn::Expr::Path(path) = &*method_call.receiver {
-                if let Some(ident) = path.path.get_ident() {
-                    if ident == session_ident {
-                        let method_name = method_call.method.to_string();
-                            if method_name == "send" {
-                                // We need to find label from the constructor of the first argument
-                                let arg = method_call.args.first().ok_or("Invalid send call")?;
-                                if let syn::Expr::Struct(struct_expr) = arg {
-                                    let label = struct_expr.path.segments.first().unwrap().ident.to_string();
-                                    return Ok(Some(Send(label, Box::new(End))));
-                                } else if let syn::Expr::Path(path) = arg {
-                                    let label = path.path.segments.last().ok_or("Invalid Path in send call")?.ident.to_string();
-                                    return Ok(Some(Send(label, Box::new(End))));
-                                } else if let syn::Expr::Call(call) = arg {
-                                    match &*call.func {
-                                        syn::Expr::Path(path) => {
-                                            let label = path.path.segments.last().ok_or("Invalid Path in send call")?.ident.to_string();
-                                            return Ok(Some(Send(label, Box::new(End))));
-                                        },
-                                        _ => return Err(format!("Invalid send call: {:?}", arg.span().source_text().unwrap()))
-                                    }
-                                } else  {
-                                    return Err(format!("Invalid send call: {:?}", arg.span().source_text().unwrap()));
-                                }
-                            } else if method_name == "receive" {
-                                // We need to find label from the turbofish used in the method call
-                                let turbofish = method_call.turbofish.as_ref().ok_or("Receive call has no turbofish")?;
-                                let label = turbofish.args.first().unwrap();
-                                if let syn::GenericArgument::Type(ty) = label {
-                                    if let syn::Type::Path(path) = ty {
-                                        if let Some(ident) = path.path.get_ident() {
-                                            return Ok(Some(Receive(ident.to_string(), Box::new(End))));
-                                        } else {
-                                            return Err("Invalid receive call".to_string());
-                                        }
-                                    } else {
-                                        return Err("Invalid receive call".to_string());
-                                    }
-                                } else {
-                                    return Err("Invalid receive call".to_string());
-                                }
-                            } 
-                            else if method_name == "branch" {
-                                return Ok(None)
-                            }
-                            else {
-                                return Err("Invalid method call".to_string());
-                            }
-                    } else {
-                        Ok(None)
-                    }
-                } else {
-                    Ok(None)
-                }
+            let session_call = if is_session_receiver(&method_call.receiver, sessions) {
+                session_method_type(method_call, messages)
             } else {
                 Ok(None)
             };
+            let (arg_combined_pst, session_call) = InferErrors::join(arg_combined_pst, session_call)?;
 
-            Ok(Some(arg_combined_pst.map_end_to(session_call?.unwrap_or(PartialLocalType::End))))
+            Ok(Some(arg_combined_pst.map_end_to(session_call.unwrap_or(PartialLocalType::End))))
         },
         syn::Expr::While(while_expr) => {
-            let new_rec_id = rec_id + 1;
-            println!("Parsing while loop");
-            let cond_type = gen_session_type(&while_expr.cond, session_ident, new_rec_id)?;
-            // println!("Cond type: {:?}", &cond_type);
-            let body_type = infer_block_session_type(&while_expr.body, new_rec_id)?;
+            let new_rec_id = ids.fresh();
+            let labels = with_label(labels, &while_expr.label, new_rec_id);
+            if let syn::Expr::Let(let_expr) = &*while_expr.cond {
+                // Each time round, the loop goes on if the pattern matches and is left otherwise
+                let body_type = infer_block_session_type(&while_expr.body, sessions, new_rec_id, &labels, ids, messages, helpers)
+                    .map(|body_type| body_type.map_end_to(X(new_rec_id)));
+                let choice = pattern_choice(&let_expr.pat, &let_expr.expr, body_type, Ok(End), sessions, new_rec_id, &labels, ids, messages, helpers)?;
+                return Ok(Some(RecX(new_rec_id, Box::new(choice))));
+            }
+            let (cond_type, body_type) = InferErrors::join(
+                gen_session_type(&while_expr.cond, sessions, new_rec_id, &labels, ids, messages, helpers),
+                infer_block_session_type(&while_expr.body, sessions, new_rec_id, &labels, ids, messages, helpers),
+            )?;
             let body_type_with_x = body_type.map_end_to(X(new_rec_id));
             let block_type_with_choice = InternalChoice(vec![body_type_with_x, End]);
             let block_with_cond =  if let Some(cond_type) = cond_type {
@@ -123,52 +123,67 @@ pub fn gen_session_type(expr: &syn::Expr, session_ident: &str, rec_id: i32) -> R
             Ok(Some(RecX(new_rec_id, Box::new(block_with_cond))))
         },
         syn::Expr::ForLoop(for_expr) => {
-            let new_rec_id = rec_id + 1;
-            println!("Parsing for loop");
-            let pat_type = gen_session_type(&for_expr.expr, session_ident, new_rec_id)?.unwrap_or(End);
-            let body_type = infer_block_session_type(&for_expr.body, new_rec_id)?;
+            let new_rec_id = ids.fresh();
+            let labels = with_label(labels, &for_expr.label, new_rec_id);
+            let (pat_type, body_type) = InferErrors::join(
+                gen_session_type(&for_expr.expr, sessions, new_rec_id, &labels, ids, messages, helpers),
+                infer_block_session_type(&for_expr.body, sessions, new_rec_id, &labels, ids, messages, helpers),
+            )?;
             let body_type_with_x = body_type.map_end_to(X(new_rec_id));
             let block_type_with_choice = RecX(new_rec_id, Box::new(InternalChoice(vec![body_type_with_x, End])));
-            let block_with_pat = pat_type.map_end_to(block_type_with_choice);
+            let block_with_pat = pat_type.unwrap_or(End).map_end_to(block_type_with_choice);
             Ok(Some(block_with_pat))
         },
         syn::Expr::Match(match_expr) => {
-            println!("Parsing match");
-
-            let expr_type = gen_session_type(&match_expr.expr, session_ident, rec_id)?.unwrap_or(End);
-
-            println!("Parsed match expr type {:?}", expr_type);
-
-            let mut session_choices = vec![];
-            for arm in &match_expr.arms {
-                match &arm.pat {
-                    syn::Pat::TupleStruct(tuple_struct) => {
-                        let label = tuple_struct.path.segments.last().unwrap().ident.to_string();
-                        let cont = gen_session_type(&arm.body, session_ident, rec_id)?.unwrap_or(End);
-                        session_choices.push(Receive(label, Box::new(cont)));
-                    },
-                    syn::Pat::Path(path) => {
-                        let label = path.path.segments.last().unwrap().ident.to_string();
-                        let cont = gen_session_type(&arm.body, session_ident, rec_id)?.unwrap_or(End);
-                        session_choices.push(Receive(label, Box::new(cont)));
-                    },
-                    _ => {
-                        return Err("Invalid match arm".to_string());
-                    }
-                }
-                // println!("{} => {}", arm.pat.span().unwrap().source_text().unwrap(), arm.body.span().unwrap().source_text().unwrap());
-            }
-            Ok(Some(expr_type.map_end_to(ExternalChoice(session_choices))))
+
+            let expr_type = gen_session_type(&match_expr.expr, sessions, rec_id, labels, ids, messages, helpers);
+            let Some(call) = branch_call(&match_expr.expr, sessions) else {
+                // Matching on local data is a choice the function makes, like an `if`, whose guards run before the arm's body
+                let arm_types = InferErrors::collect(match_expr.arms.iter().map(|arm| {
+                    let guard = match &arm.pat {
+                        syn::Pat::Guard(guarded) => Some(&*guarded.guard),
+                        _ => None,
+                    };
+                    sequence_exprs(guard.into_iter().chain([&*arm.body]), sessions, rec_id, labels, ids, messages, helpers)
+                }));
+                let (expr_type, arm_types) = InferErrors::join(expr_type, arm_types)?;
+                return Ok(Some(expr_type.unwrap_or(End).map_end_to(InternalChoice(arm_types))));
+            };
+            let peer = call_peer(call);
+            let (expr_type, peer) = InferErrors::join(expr_type, peer.map_err(InferErrors::from))?;
+            let expr_type = expr_type.unwrap_or(End);
+
+            tracing::trace!(?expr_type, "inferred match scrutinee");
+
+            let arms = InferErrors::collect(match_expr.arms.iter().map(|arm| {
+                let (pat, guard) = match &arm.pat {
+                    syn::Pat::Guard(guarded) => (&*guarded.pat, Some(&*guarded.guard)),
+                    pat => (pat, None),
+                };
+                let labels_matched = pattern_labels(pat).map_err(InferErrors::from);
+                let guard_type = guard.map_or(Ok(None), |guard| gen_session_type(guard, sessions, rec_id, labels, ids, messages, helpers));
+                let body_type = gen_session_type(&arm.body, sessions, rec_id, labels, ids, messages, helpers);
+                let ((labels_matched, guard_type), body_type) = InferErrors::join(InferErrors::join(labels_matched, guard_type), body_type)?;
+                Ok(BranchArm { labels: labels_matched, guard: guard.map(|_| guard_type.unwrap_or(End)), body: body_type.unwrap_or(End) })
+            }))?;
+            let message_type = turbofish_type(call).expect("inferring the branch call checked its turbofish");
+            Ok(Some(expr_type.map_end_to(branch_choice(peer, message_type, &arms))))
         },
         syn::Expr::If(if_expr) => {
-            println!("Parsing if");
-            let cond_type = gen_session_type(&if_expr.cond, session_ident, rec_id)?;
-            let then_type = infer_block_session_type(&if_expr.then_branch, rec_id)?;
             let else_type = match &if_expr.else_branch {
-                Some((_, else_block)) => gen_session_type(else_block.as_ref(), session_ident, rec_id)?.unwrap_or(End),
-                None => End
+                Some((_, else_block)) => gen_session_type(else_block.as_ref(), sessions, rec_id, labels, ids, messages, helpers),
+                None => Ok(None)
             };
-            let if_type_with_choice = InternalChoice(vec![then_type, else_type]);
+            if let syn::Expr::Let(let_expr) = &*if_expr.cond {
+                let then_type = infer_block_session_type(&if_expr.then_branch, sessions, rec_id, labels, ids, messages, helpers);
+                let else_type = else_type.map(|else_type| else_type.unwrap_or(End));
+                return Ok(Some(pattern_choice(&let_expr.pat, &let_expr.expr, then_type, else_type, sessions, rec_id, labels, ids, messages, helpers)?));
+            }
+            let ((cond_type, then_type), else_type) = InferErrors::join(InferErrors::join(
+                gen_session_type(&if_expr.cond, sessions, rec_id, labels, ids, messages, helpers),
+                infer_block_session_type(&if_expr.then_branch, sessions, rec_id, labels, ids, messages, helpers),
+            ), else_type)?;
+            let if_type_with_choice = InternalChoice(vec![then_type, else_type.unwrap_or(End)]);
             let if_type_with_cond = if let Some(cond_type) = cond_type {
                 cond_type.map_end_to(if_type_with_choice)
             } else {
@@ -177,173 +192,756 @@ pub fn gen_session_type(expr: &syn::Expr, session_ident: &str, rec_id: i32) -> R
             Ok(Some(if_type_with_cond))
         },
         syn::Expr::Break(break_expr) => {
-            println!("Parsing break");
-            Ok(Some(Break))
+            let (target, value_type) = InferErrors::join(
+                loop_target(&break_expr.label, rec_id, labels, break_expr.span()).map_err(InferErrors::from),
+                break_expr.expr.as_ref().map_or(Ok(None), |expr| gen_session_type(expr, sessions, rec_id, labels, ids, messages, helpers)),
+            )?;
+            Ok(Some(value_type.unwrap_or(End).map_end_to(Break(target))))
+        },
+        syn::Expr::Continue(continue_expr) => {
+            // Going back to the start of the loop is recursing
+            Ok(Some(X(loop_target(&continue_expr.label, rec_id, labels, continue_expr.span())?)))
+        },
+        syn::Expr::Return(return_expr) => {
+            let value_type = match &return_expr.expr {
+                Some(expr) => gen_session_type(expr, sessions, rec_id, labels, ids, messages, helpers)?.unwrap_or(End),
+                None => End,
+            };
+            Ok(Some(value_type.map_end_to(Return)))
+        },
+        syn::Expr::Try(try_expr) => {
+            let expr_type = gen_session_type(&try_expr.expr, sessions, rec_id, labels, ids, messages, helpers)?.unwrap_or(End);
+            // Either the function carries on with the unwrapped value, or it returns the error
+            Ok(Some(expr_type.map_end_to(InternalChoice(vec![End, Return]))))
+        },
+        syn::Expr::Tuple(tuple_expr) => {
+            Ok(Some(sequence_exprs(&tuple_expr.elems, sessions, rec_id, labels, ids, messages, helpers)?))
         },
         syn::Expr::Loop(loop_expr) => {
-            let new_rec_id = rec_id + 1;
-            println!("Parsing loop");
-            let body_type = infer_block_session_type(&loop_expr.body, new_rec_id)?;
+            let new_rec_id = ids.fresh();
+            let labels = with_label(labels, &loop_expr.label, new_rec_id);
+            let body_type = infer_block_session_type(&loop_expr.body, sessions, new_rec_id, &labels, ids, messages, helpers)?;
             let body_type_with_x = body_type.map_end_to(X(new_rec_id));
             Ok(Some(RecX(new_rec_id, Box::new(body_type_with_x))))
         },
         syn::Expr::Let(let_expr) => {
-            println!("Parsing let");
-            let rhs_type = gen_session_type(&let_expr.expr, session_ident, rec_id)?;
+            let rhs_type = gen_session_type(&let_expr.expr, sessions, rec_id, labels, ids, messages, helpers)?;
             let rhs_type = rhs_type.unwrap_or(End);
             Ok(Some(rhs_type))
         },
         syn::Expr::Assign(assign_expr) => {
-            println!("Parsing assign");
-            let rhs_type = gen_session_type(&assign_expr.right, session_ident, rec_id)?;
+            let rhs_type = gen_session_type(&assign_expr.right, sessions, rec_id, labels, ids, messages, helpers)?;
             let rhs_type = rhs_type.unwrap_or(End);
             Ok(Some(rhs_type))
         },
         syn::Expr::Struct(struct_expr) => {
-            println!("Parsing struct");
-            let mut fields = vec![];
-            for field in &struct_expr.fields {
-                let field_type = gen_session_type(&field.expr, session_ident, rec_id)?;
-                let field_type = field_type.unwrap_or(End);
-                fields.push(field_type)
-            }
-            Ok(Some(sequence_session_types(fields)?))
+            Ok(Some(sequence_exprs(struct_expr.fields.iter().map(|field| &field.expr), sessions, rec_id, labels, ids, messages, helpers)?))
         },
         syn::Expr::Binary(binary_expr) => {
-            println!("Parsing binary");
-            let lhs_type = gen_session_type(&binary_expr.left, session_ident, rec_id)?;
-            let lhs_type = lhs_type.unwrap_or(End);
-            let rhs_type = gen_session_type(&binary_expr.right, session_ident, rec_id)?;
-            let rhs_type = rhs_type.unwrap_or(End);
-            Ok(Some(sequence_session_types(vec![lhs_type, rhs_type])?))
+            Ok(Some(sequence_exprs([&*binary_expr.left, &*binary_expr.right], sessions, rec_id, labels, ids, messages, helpers)?))
         },
         syn::Expr::Unary(unary_expr) => {
-            println!("Parsing unary");
-            let rhs_type = gen_session_type(&unary_expr.expr, session_ident, rec_id)?;
+            let rhs_type = gen_session_type(&unary_expr.expr, sessions, rec_id, labels, ids, messages, helpers)?;
             let rhs_type = rhs_type.unwrap_or(End);
             Ok(Some(rhs_type))
         },
         syn::Expr::Range(range_expr) => {
-            println!("Parsing range");
-            let lhs_type = match &range_expr.start {
-                Some(start) => gen_session_type(start, session_ident, rec_id)?,
-                None => None
-            };
-            let lhs_type = lhs_type.unwrap_or(End);
-            let rhs_type = match &range_expr.end {
-                Some(start) => gen_session_type(start, session_ident, rec_id)?,
-                None => None
-            };
-            let rhs_type = rhs_type.unwrap_or(End);
-            Ok(Some(sequence_session_types(vec![lhs_type, rhs_type])?))
+            let bounds = range_expr.start.iter().chain(range_expr.end.iter()).map(|bound| &**bound);
+            Ok(Some(sequence_exprs(bounds, sessions, rec_id, labels, ids, messages, helpers)?))
         },
         syn::Expr::Lit(_) => Ok(None),
         syn::Expr::Path(_) => Ok(None),
         syn::Expr::Block(block) => {
-            println!("Parsing block");
-            Ok(Some(infer_block_session_type(&block.block, rec_id)?))
+            Ok(Some(infer_block_session_type(&block.block, sessions, rec_id, labels, ids, messages, helpers)?))
         },
         syn::Expr::Group(group) => {
-            println!("Parsing group");
-            gen_session_type(&group.expr, session_ident, rec_id)
+            gen_session_type(&group.expr, sessions, rec_id, labels, ids, messages, helpers)
         },
         syn::Expr::Paren(paren) => {
-            println!("Parsing paren");
-            gen_session_type(&paren.expr, session_ident, rec_id)
+            gen_session_type(&paren.expr, sessions, rec_id, labels, ids, messages, helpers)
         },
         syn::Expr::Field(_) => Ok(None),
-        _ => Err(format!("Unsupported Rust construct: {}, of type {:?}", 
-                expr.span().source_text().unwrap_or(String::from("ERROR printing expr")),
-                expr.to_token_stream()
-            )),
-    }
-}
-
-fn get_session_arg(args: &Punctuated<FnArg, Comma>) -> Option<String> {
-    for arg in args {
-        match arg {
-            FnArg::Typed(pat_type) => {
-                let pat = &*pat_type.pat;
-                let ty = &*pat_type.ty;
-                if let syn::Pat::Ident(ident) = pat {
-                    if ident.ident == "session" {
-                        if let syn::Type::Path(path) = ty {
-                            if let Some(ident) = path.path.get_ident() {
-                                return Some(ident.to_string());
-                            }
-                        }
-                    }
+        syn::Expr::Reference(reference) => gen_session_type(&reference.expr, sessions, rec_id, labels, ids, messages, helpers),
+        // The future is awaited where it is written, so it happens in the same order as a blocking call would
+        syn::Expr::Await(await_expr) => gen_session_type(&await_expr.base, sessions, rec_id, labels, ids, messages, helpers),
+        _ => Err(InferError::new(expr.span(), ErrorKind::UnsupportedConstruct(construct_name(expr))).into()),
+    }
+}
+
+/// The labelled loops around an expression, innermost last, with the id of their recursion.
+pub type Labels = [(String, i32)];
+
+/// The names the session goes by in the current scope: its parameter, and the bindings that borrow or move it.
+pub type Sessions = [String];
+
+/// Hands out the recursion ids of a function, so every loop in it binds a different one.
+#[derive(Default)]
+pub struct RecIds(Cell<i32>);
+
+impl RecIds {
+    fn fresh(&self) -> i32 {
+        let id = self.0.get() + 1;
+        self.0.set(id);
+        id
+    }
+
+    /// How many ids were handed out, which are `1..=count`.
+    pub fn count(&self) -> i32 {
+        self.0.get()
+    }
+}
+
+/// Where the label of a message comes from, for labelling a send of it.
+#[derive(Clone)]
+enum MessageSource {
+    /// A constructor, whose label is known here
+    Constructor(syn::Path),
+    /// A value of a message type, whose labels are only known at runtime
+    Type(syn::Type),
+    /// The result of calling a function, or awaiting the future it returns
+    Returned(syn::Path, bool),
+}
+
+/// The message sources of the locals in scope, innermost last, so a send of a local is labelled by what it holds rather than its name.
+/// A local that holds something else is bound to `None`, shadowing any message of the same name.
+/// Also gathers the message types the function sends and receives, whose labels give the sorts of the inferred ones.
+#[derive(Default)]
+pub struct Messages {
+    locals: RefCell<Vec<(String, Option<MessageSource>)>>,
+    types: RefCell<Vec<syn::Type>>,
+    // The function's generic parameters, which the generated functions cannot name
+    generics: Vec<syn::Ident>,
+}
+
+impl Messages {
+    /// The parameters of a function, by their types.
+    pub fn of_params(sig: &syn::Signature) -> Messages {
+        let generics = sig.generics.params.iter().map(|param| match param {
+            syn::GenericParam::Type(param) => param.ident.clone(),
+            syn::GenericParam::Lifetime(param) => param.lifetime.ident.clone(),
+            syn::GenericParam::Const(param) => param.ident.clone(),
+        });
+        let messages = Messages { generics: generics.collect(), ..Messages::default() };
+        for input in &sig.inputs {
+            if let syn::FnArg::Typed(pat_type) = input {
+                messages.bind(&syn::Pat::Type(pat_type.clone()), None);
+            }
+        }
+        messages
+    }
+
+    fn enter(&self) -> usize {
+        self.locals.borrow().len()
+    }
+
+    /// Forgets the locals bound since `enter` returned `scope`.
+    fn leave(&self, scope: usize) {
+        self.locals.borrow_mut().truncate(scope);
+    }
+
+    /// Whether `tokens` can be pasted into the generated functions, which are not generic and have no `Self`.
+    fn nameable(&self, tokens: &impl ToTokens) -> bool {
+        fn nameable(tokens: proc_macro2::TokenStream, generics: &[syn::Ident]) -> bool {
+            tokens.into_iter().all(|token| match token {
+                proc_macro2::TokenTree::Ident(ident) => ident != "Self" && ident != "impl" && !generics.contains(&ident),
+                proc_macro2::TokenTree::Group(group) => nameable(group.stream(), generics),
+                _ => true,
+            })
+        }
+        nameable(tokens.to_token_stream(), &self.generics)
+    }
+
+    /// Types that cannot be named in the generated functions are left out, so their labels have no sorts.
+    fn uses(&self, ty: syn::Type) {
+        if !self.nameable(&ty) {
+            return;
+        }
+        let mut types = self.types.borrow_mut();
+        if !types.iter().any(|used| type_name(used) == type_name(&ty)) {
+            types.push(ty);
+        }
+    }
+
+    /// The message types the function sends and receives, in the order they appear.
+    pub fn types(&self) -> Vec<syn::Type> {
+        self.types.borrow().clone()
+    }
+
+    /// Binds the local of `let pat = init`, by its type annotation if it has one and otherwise by what `init` builds.
+    fn bind(&self, pat: &syn::Pat, init: Option<&syn::Expr>) {
+        let (pat, source) = match pat {
+            syn::Pat::Type(pat_type) if self.nameable(&pat_type.ty) => (&*pat_type.pat, Some(MessageSource::Type((*pat_type.ty).clone()))),
+            syn::Pat::Type(pat_type) => (&*pat_type.pat, None),
+            pat => (pat, init.and_then(|init| self.source(init))),
+        };
+        if let syn::Pat::Ident(pat) = pat {
+            self.locals.borrow_mut().push((pat.ident.to_string(), source));
+        }
+    }
+
+    fn lookup(&self, name: &syn::Ident) -> Option<MessageSource> {
+        self.locals.borrow().iter().rev()
+            .find(|(local, _)| name == local)
+            .and_then(|(_, source)| source.clone())
+    }
+
+    /// Where the label of the message `expr` evaluates to comes from: a constructor, a local, or a function returning it.
+    fn source(&self, expr: &syn::Expr) -> Option<MessageSource> {
+        match expr {
+            syn::Expr::Struct(struct_expr) => Some(MessageSource::Constructor(struct_expr.path.clone())),
+            syn::Expr::Path(path) => match path.path.get_ident() {
+                Some(ident) if !is_type_name(ident) => self.lookup(ident),
+                _ => Some(MessageSource::Constructor(path.path.clone())),
+            },
+            syn::Expr::Call(call) => match &*call.func {
+                syn::Expr::Path(path) if is_type_name(&path.path.segments.last()?.ident) => Some(MessageSource::Constructor(path.path.clone())),
+                syn::Expr::Path(path) if self.nameable(&path.path) => Some(MessageSource::Returned(path.path.clone(), false)),
+                _ => None,
+            },
+            syn::Expr::Await(await_expr) => match self.source(&await_expr.base)? {
+                MessageSource::Returned(path, false) => Some(MessageSource::Returned(path, true)),
+                _ => None,
+            },
+            syn::Expr::Paren(paren) => self.source(&paren.expr),
+            syn::Expr::Group(group) => self.source(&group.expr),
+            _ => None,
+        }
+    }
+}
+
+fn with_label(labels: &Labels, label: &Option<syn::Label>, rec_id: i32) -> Vec<(String, i32)> {
+    let mut labels = labels.to_vec();
+    if let Some(label) = label {
+        labels.push((label.name.ident.to_string(), rec_id));
+    }
+    labels
+}
+
+/// The id of the recursion a `break` or `continue` leaves or restarts, the innermost loop's unless it is labelled.
+/// Outside any loop `rec_id` is 0, and there is nothing for it to leave.
+fn loop_target(label: &Option<syn::Lifetime>, rec_id: i32, labels: &Labels, span: Span) -> Result<i32, InferError> {
+    let Some(label) = label else {
+        return match rec_id {
+            0 => Err(InferError::new(span, ErrorKind::UnsupportedConstruct("break or continue outside a loop"))),
+            rec_id => Ok(rec_id),
+        };
+    };
+    labels.iter().rev()
+        .find(|(name, _)| label.ident == name)
+        .map(|(_, id)| *id)
+        .ok_or_else(|| InferError::new(label.span(), ErrorKind::UnsupportedConstruct("labelled block")))
+}
+
+/// What kind of expression `expr` is, for error messages and logs.
+fn construct_name(expr: &syn::Expr) -> &'static str {
+    match expr {
+        syn::Expr::Array(_) => "array",
+        syn::Expr::Assign(_) => "assignment",
+        syn::Expr::Async(_) => "async block",
+        syn::Expr::Await(_) => "await",
+        syn::Expr::Binary(_) => "binary operator",
+        syn::Expr::Block(_) => "block",
+        syn::Expr::Break(_) => "break",
+        syn::Expr::Call(_) => "call",
+        syn::Expr::Closure(_) => "closure",
+        syn::Expr::Const(_) => "const block",
+        syn::Expr::Continue(_) => "continue",
+        syn::Expr::Field(_) => "field access",
+        syn::Expr::ForLoop(_) => "for loop",
+        syn::Expr::Group(_) => "group",
+        syn::Expr::If(_) => "if",
+        syn::Expr::Index(_) => "indexing",
+        syn::Expr::Let(_) => "let",
+        syn::Expr::Lit(_) => "literal",
+        syn::Expr::Loop(_) => "loop",
+        syn::Expr::Macro(_) => "macro",
+        syn::Expr::Match(_) => "match",
+        syn::Expr::MethodCall(_) => "method call",
+        syn::Expr::Paren(_) => "parentheses",
+        syn::Expr::Path(_) => "path",
+        syn::Expr::Range(_) => "range",
+        syn::Expr::Reference(_) => "reference",
+        syn::Expr::Repeat(_) => "array repeat",
+        syn::Expr::Return(_) => "return",
+        syn::Expr::Struct(_) => "struct literal",
+        syn::Expr::Try(_) => "`?` operator",
+        syn::Expr::TryBlock(_) => "try block",
+        syn::Expr::Tuple(_) => "tuple",
+        syn::Expr::Unary(_) => "unary operator",
+        syn::Expr::Unsafe(_) => "unsafe block",
+        syn::Expr::While(_) => "while loop",
+        syn::Expr::Yield(_) => "yield",
+        _ => "expression",
+    }
+}
+
+fn is_session_receiver(receiver: &syn::Expr, sessions: &Sessions) -> bool {
+    if let syn::Expr::Path(path) = receiver {
+        if let Some(ident) = path.path.get_ident() {
+            return sessions.iter().any(|session| ident == session);
+        }
+    }
+    false
+}
+
+/// Infers the effect of calling one of `Session`'s methods. The `_to`/`_from` variants take the peer's role as their first argument.
+/// A send is labelled by what its argument is built from, or else by the message type its turbofish names.
+fn session_method_type(method_call: &syn::ExprMethodCall, messages: &Messages) -> Result<Option<PartialLocalType>, InferErrors> {
+    use PartialLocalType::*;
+
+    let method_name = method_call.method.to_string();
+    let invalid_send = |span| InferErrors::from(InferError::new(span, ErrorKind::InvalidSendArgument));
+    if method_name == "send" || method_name == "send_to" {
+        let (peer, arg) = if method_name == "send_to" {
+            (call_peer(method_call)?, method_call.args.iter().nth(1).ok_or_else(|| invalid_send(method_call.span()))?)
+        } else {
+            (Participant::anonymous(), method_call.args.first().ok_or_else(|| invalid_send(method_call.span()))?)
+        };
+        let turbofish = match method_call.turbofish.as_ref().and_then(|turbofish| turbofish.args.first()) {
+            Some(syn::GenericArgument::Type(ty)) => Some(ty),
+            Some(arg) => return Err(InferError::new(arg.span(), ErrorKind::InvalidReceiveType).into()),
+            None => None,
+        };
+        // A constructor names its variant, where the turbofish could only say the message is one of its type's.
+        // But a turbofish with generic arguments names them where the constructor usually leaves them to be inferred
+        let turbofish = turbofish.filter(|ty| messages.nameable(ty)).map(|ty| MessageSource::Type(ty.clone()));
+        let source = match turbofish {
+            Some(turbofish) if has_generic_arguments(&turbofish) => Some(turbofish),
+            turbofish => messages.source(arg).or(turbofish),
+        }.ok_or_else(|| invalid_send(arg.span()))?;
+        let send = match source {
+            MessageSource::Constructor(path) => {
+                let label = constructor_label(&path).ok_or_else(|| invalid_send(arg.span()))?;
+                if let Some(ty) = constructor_type(&path) {
+                    messages.uses(ty);
+                }
+                Send(peer, label, Box::new(End))
+            },
+            MessageSource::Type(ty) => {
+                let labels = quote::quote! { <#ty as ::session::Message>::qualified_labels() };
+                SendAny(peer, Tokens(labels), Box::new(End))
+            },
+            MessageSource::Returned(path, awaited) => {
+                let labels = if awaited {
+                    quote::quote! { ::session::ilt::awaited_labels(&#path) }
+                } else {
+                    quote::quote! { ::session::ilt::returned_labels(&#path) }
+                };
+                SendAny(peer, Tokens(labels), Box::new(End))
+            },
+        };
+        Ok(Some(send))
+    } else if method_name == "receive" || method_name == "receive_from" {
+        let peer = if method_name == "receive_from" {
+            call_peer(method_call)?
+        } else {
+            Participant::anonymous()
+        };
+        // We need to find label from the turbofish used in the method call
+        let turbofish = method_call.turbofish.as_ref()
+            .ok_or_else(|| InferError::new(method_call.method.span(), ErrorKind::MissingTurbofish(method_name.clone())))?;
+        let message_type = turbofish_type(method_call).ok_or_else(|| InferError::new(turbofish.span(), ErrorKind::InvalidReceiveType))?;
+        messages.uses(message_type.clone());
+        match type_label(message_type) {
+            Some(label) => Ok(Some(Receive(peer, label, Box::new(End)))),
+            // Any label of the type, which for a struct is the one it has
+            None => Ok(Some(ReceiveOthers(peer, Tokens(message_type.to_token_stream()), Vec::new(), Box::new(End)))),
+        }
+    } else if method_name == "branch" || method_name == "branch_from" {
+        // The labels come from the arms of the enclosing match
+        let Some(turbofish) = &method_call.turbofish else {
+            return Err(InferError::new(method_call.method.span(), ErrorKind::MissingTurbofish(method_name)).into());
+        };
+        let Some(message_type) = turbofish_type(method_call) else {
+            return Err(InferError::new(turbofish.span(), ErrorKind::InvalidReceiveType).into());
+        };
+        messages.uses(message_type.clone());
+        Ok(None)
+    } else {
+        Err(InferError::new(method_call.method.span(), ErrorKind::UnknownSessionMethod(method_name)).into())
+    }
+}
+
+/// Finds the `s.branch::<T>()` (or `s.branch_from`) call a match is scrutinising, if any, awaited or not.
+fn branch_call<'a>(expr: &'a syn::Expr, sessions: &Sessions) -> Option<&'a syn::ExprMethodCall> {
+    match expr {
+        syn::Expr::MethodCall(method_call) if is_session_receiver(&method_call.receiver, sessions) => {
+            let method_name = method_call.method.to_string();
+            if method_name == "branch" || method_name == "branch_from" {
+                Some(method_call)
+            } else {
+                None
+            }
+        },
+        syn::Expr::Paren(paren) => branch_call(&paren.expr, sessions),
+        syn::Expr::Group(group) => branch_call(&group.expr, sessions),
+        syn::Expr::Await(await_expr) => branch_call(&await_expr.base, sessions),
+        _ => None,
+    }
+}
+
+/// The message type a `receive::<T>()` or `branch::<T>()` call receives, from its turbofish.
+/// It can be any path, tuple or array type, such as `proto::Order` or `Batch<Item>`.
+fn turbofish_type(method_call: &syn::ExprMethodCall) -> Option<&syn::Type> {
+    match method_call.turbofish.as_ref()?.args.first()? {
+        syn::GenericArgument::Type(ty) => message_type(ty),
+        _ => None,
+    }
+}
+
+fn message_type(ty: &syn::Type) -> Option<&syn::Type> {
+    match ty {
+        syn::Type::Paren(paren) => message_type(&paren.elem),
+        syn::Type::Group(group) => message_type(&group.elem),
+        syn::Type::Path(path) if path.qself.is_none() => Some(ty),
+        syn::Type::Tuple(_) | syn::Type::Array(_) => Some(ty),
+        _ => None,
+    }
+}
+
+/// The label of a struct of type `ty`, if it can be told from the type alone: the name a plain path ends with, as derived and default `Message` impls both have it.
+/// A type with generic arguments could be a struct labelled `Batch<Item>` or an enum, so its labels are left to its `Message::qualified_labels`,
+/// which for a struct are written the same way, see `struct_label`.
+fn type_label(ty: &syn::Type) -> Option<Label> {
+    let syn::Type::Path(path) = ty else {
+        return None;
+    };
+    if path.path.segments.iter().any(|segment| !segment.arguments.is_none()) {
+        return None;
+    }
+    Some(Label::message(&struct_label(ty)))
+}
+
+/// The label a struct of type `ty` has, as its `wire::TypeName` writes it: with generic arguments and without paths, `Batch<Item>` for `proto::Batch<proto::Item>`.
+fn struct_label(ty: &syn::Type) -> String {
+    session::wire::strip_paths(&type_name(ty))
+}
+
+/// The label a message constructor sends: `Response::Accept` for `Response::Accept(..)`, and `Hello` for `Hello`.
+/// The segment before the last is taken to be an enum if it is capitalised, as types are, and a module otherwise.
+/// A struct constructor with generic arguments, `Wrapper::<u32>(7)`, is labelled with them, as the struct is.
+fn constructor_label(path: &syn::Path) -> Option<Label> {
+    let mut segments = path.segments.iter().rev();
+    let last = segments.next()?;
+    match segments.next() {
+        Some(ty) if is_type_name(&ty.ident) => Some(Label::variant(&ty.ident.to_string(), &last.ident.to_string())),
+        _ => {
+            let mut last = last.clone();
+            if let syn::PathArguments::AngleBracketed(args) = &mut last.arguments {
+                args.colon2_token = None;
+            }
+            Some(Label::message(&struct_label(&syn::parse_quote! { #last })))
+        },
+    }
+}
+
+/// The message type a constructor builds: the enum of a variant, or the struct itself.
+/// `Self` cannot be named outside the function, so its variants are left to the labels of the other types.
+fn constructor_type(path: &syn::Path) -> Option<syn::Type> {
+    if path.segments.iter().any(|segment| segment.ident == "Self") {
+        return None;
+    }
+    let mut path = path.clone();
+    if constructor_label(&path)?.variant.is_some() {
+        path.segments.pop();
+        path.segments.pop_punct();
+    }
+    Some(syn::parse_quote! { #path })
+}
+
+fn has_generic_arguments(source: &MessageSource) -> bool {
+    match source {
+        MessageSource::Type(syn::Type::Path(path)) => path.path.segments.iter().any(|segment| !segment.arguments.is_none()),
+        _ => false,
+    }
+}
+
+/// Whether `ident` names a type or variant, which are capitalised, rather than a local, function or module.
+fn is_type_name(ident: &syn::Ident) -> bool {
+    ident.to_string().starts_with(char::is_uppercase)
+}
+
+/// The label of a variant (or, for a struct, the type itself) named in a pattern on a message of type `message`.
+fn pattern_label(message_type: &syn::Type, name: &str) -> Label {
+    let message = match message_type {
+        syn::Type::Path(path) => path.path.segments.last().map_or_else(|| message_type.to_token_stream().to_string(), |segment| segment.ident.to_string()),
+        _ => message_type.to_token_stream().to_string(),
+    };
+    if message == name {
+        Label::message(&struct_label(message_type))
+    } else {
+        Label::variant(&message, name)
+    }
+}
+
+/// The labels a pattern on a received message matches, from the variants or structs it names, `None` for a wildcard or binding that matches any.
+/// Each comes with whether the pattern only matches some messages with that label, because of a refutable pattern in its fields.
+fn pattern_labels(pat: &syn::Pat) -> Result<Vec<(Option<String>, bool)>, InferError> {
+    let label = |path: &syn::Path| Some(path.segments.last().unwrap().ident.to_string());
+    match pat {
+        syn::Pat::TupleStruct(tuple_struct) => Ok(vec![(label(&tuple_struct.path), !tuple_struct.elems.iter().all(is_irrefutable))]),
+        syn::Pat::Struct(struct_pat) => Ok(vec![(label(&struct_pat.path), !struct_pat.fields.iter().all(|field| is_irrefutable(&field.pat)))]),
+        syn::Pat::Path(path) => Ok(vec![(label(&path.path), false)]),
+        syn::Pat::Or(or_pat) => {
+            let mut labels = Vec::new();
+            for case in &or_pat.cases {
+                labels.extend(pattern_labels(case)?);
+            }
+            Ok(labels)
+        },
+        syn::Pat::Ident(ident) => match &ident.subpat {
+            Some((_, subpat)) => pattern_labels(subpat),
+            None => Ok(vec![(None, false)]),
+        },
+        syn::Pat::Wild(_) => Ok(vec![(None, false)]),
+        syn::Pat::Paren(paren) => pattern_labels(&paren.pat),
+        pat => Err(InferError::new(pat.span(), ErrorKind::InvalidMatchArm)),
+    }
+}
+
+/// Whether a pattern matches any value of its type. Patterns naming a struct or variant are assumed not to, as we cannot tell which they are.
+fn is_irrefutable(pat: &syn::Pat) -> bool {
+    match pat {
+        syn::Pat::Wild(_) | syn::Pat::Rest(_) => true,
+        syn::Pat::Ident(ident) => ident.subpat.as_ref().is_none_or(|(_, subpat)| is_irrefutable(subpat)),
+        syn::Pat::Tuple(tuple) => tuple.elems.iter().all(is_irrefutable),
+        syn::Pat::Paren(paren) => is_irrefutable(&paren.pat),
+        syn::Pat::Reference(reference) => is_irrefutable(&reference.pat),
+        syn::Pat::Type(pat_type) => is_irrefutable(&pat_type.pat),
+        _ => false,
+    }
+}
+
+/// The choice a refutable pattern (`if let`, `while let` or `let else`) makes on `scrutinee`, going on as `matched` or `unmatched`.
+/// On a `branch::<T>()` call it is an external choice between the pattern's label and every other label of `T`, and on local data an internal one.
+#[allow(clippy::too_many_arguments)]
+fn pattern_choice(
+    pat: &syn::Pat,
+    scrutinee: &syn::Expr,
+    matched: Result<PartialLocalType, InferErrors>,
+    unmatched: Result<PartialLocalType, InferErrors>,
+    sessions: &Sessions,
+    rec_id: i32,
+    labels: &Labels,
+    ids: &RecIds,
+    messages: &Messages,
+    helpers: &[syn::Path],
+) -> Result<PartialLocalType, InferErrors> {
+    use PartialLocalType::*;
+
+    let scrutinee_type = gen_session_type(scrutinee, sessions, rec_id, labels, ids, messages, helpers);
+    let conts = InferErrors::join(matched, unmatched);
+    let Some(call) = branch_call(scrutinee, sessions) else {
+        let (scrutinee_type, (matched, unmatched)) = InferErrors::join(scrutinee_type, conts)?;
+        return Ok(scrutinee_type.unwrap_or(End).map_end_to(InternalChoice(vec![matched, unmatched])));
+    };
+    let labels_matched = pattern_labels(pat).map_err(InferErrors::from);
+    let peer = call_peer(call).map_err(InferErrors::from);
+    let ((scrutinee_type, (matched, unmatched)), (labels_matched, peer)) = InferErrors::join(
+        InferErrors::join(scrutinee_type, conts),
+        InferErrors::join(labels_matched, peer),
+    )?;
+    let message_type = turbofish_type(call).expect("inferring the branch call checked its turbofish");
+    // The same as a match with the pattern's arm and a wildcard one
+    let arms = [
+        BranchArm { labels: labels_matched, guard: None, body: matched },
+        BranchArm { labels: vec![(None, false)], guard: None, body: unmatched },
+    ];
+    Ok(scrutinee_type.unwrap_or(End).map_end_to(branch_choice(peer, message_type, &arms)))
+}
+
+/// An arm of a match on a `branch::<T>()` call.
+struct BranchArm {
+    /// The labels its pattern matches, `None` for any label, and whether only some messages with the label match
+    labels: Vec<(Option<String>, bool)>,
+    /// The effects of its guard, if it has one
+    guard: Option<PartialLocalType>,
+    body: PartialLocalType,
+}
+
+impl BranchArm {
+    /// Whether the arm matches messages with `label` (`None` for a label no arm names), and if so whether it might not match all of them.
+    fn matches(&self, label: Option<&str>) -> Option<bool> {
+        let refutable = self.labels.iter()
+            .filter(|(matched, _)| matched.is_none() || (label.is_some() && matched.as_deref() == label))
+            .map(|(_, refutable)| *refutable)
+            .reduce(|a, b| a && b)?;
+        Some(self.guard.is_some() || refutable)
+    }
+}
+
+/// The external choice a match on a `branch::<T>()` call makes, with a branch for each label its arms name, and one for every other label of `T` if an arm matches any label.
+/// A message goes to the first arm that matches it, so an arm that only matches some messages with a label is an internal choice between it and the arms after it.
+fn branch_choice(peer: Participant, message_type: &syn::Type, arms: &[BranchArm]) -> PartialLocalType {
+    use PartialLocalType::*;
+
+    let mut named: Vec<String> = Vec::new();
+    for (label, _) in arms.iter().flat_map(|arm| &arm.labels) {
+        if let Some(label) = label {
+            if !named.contains(label) {
+                named.push(label.clone());
+            }
+        }
+    }
+    let cont = |label: Option<&str>| {
+        let mut candidates = Vec::new();
+        for arm in arms {
+            if let Some(conditional) = arm.matches(label) {
+                candidates.push(arm);
+                if !conditional {
+                    break;
                 }
             }
-            _ => {}
         }
+        // The match is exhaustive, so the last arm that can match a message does
+        let mut candidates = candidates.into_iter().rev();
+        let Some(last) = candidates.next() else {
+            return End;
+        };
+        let guarded = |arm: &BranchArm, cont: PartialLocalType| match &arm.guard {
+            Some(guard) => guard.map_end_to(cont),
+            None => cont,
+        };
+        let last = guarded(last, last.body.clone());
+        candidates.fold(last, |rest, arm| guarded(arm, InternalChoice(vec![arm.body.clone(), rest])))
+    };
+    let mut choices: Vec<PartialLocalType> = named.iter()
+        .map(|label| Receive(peer.clone(), pattern_label(message_type, label), Box::new(cont(Some(label)))))
+        .collect();
+    if arms.iter().any(|arm| arm.matches(None).is_some()) {
+        let handled = named.iter().map(|label| pattern_label(message_type, label)).collect();
+        choices.push(ReceiveOthers(peer, Tokens(message_type.to_token_stream()), handled, Box::new(cont(None))));
     }
-    None
+    ExternalChoice(choices)
 }
 
-fn sequence_session_types(mut actions: Vec<PartialLocalType>) -> Result<PartialLocalType, String> {
+/// The type of the else block of a `let else`, which cannot carry on after the `let`.
+/// If it does not return, break or continue, it panics, which leaves the function as well.
+fn diverging(else_type: PartialLocalType) -> PartialLocalType {
+    else_type.map_end_to(PartialLocalType::Return)
+}
+
+/// The peer a session method talks to. Only the role-addressed methods name one, and it has to be a string literal so we can read it here.
+fn call_peer(method_call: &syn::ExprMethodCall) -> Result<Participant, InferError> {
+    if !method_call.method.to_string().ends_with("_to") && !method_call.method.to_string().ends_with("_from") {
+        return Ok(Participant::anonymous());
+    }
+    match method_call.args.first() {
+        Some(syn::Expr::Lit(syn::ExprLit { lit: syn::Lit::Str(role), .. })) => Ok(Participant::named(&role.value())),
+        Some(arg) => Err(InferError::new(arg.span(), ErrorKind::RoleNotLiteral(method_call.method.to_string()))),
+        None => Err(InferError::new(method_call.span(), ErrorKind::RoleNotLiteral(method_call.method.to_string()))),
+    }
+}
+
+/// Finds the parameter holding the session: the one typed `Session`, `AsyncSession` or one of `aliases`, possibly behind a reference.
+pub fn get_session_arg(sig: &syn::Signature, aliases: &[syn::Ident]) -> syn::Result<String> {
+    let mut session_args = sig.inputs.iter().filter_map(|arg| match arg {
+        syn::FnArg::Typed(pat_type) if is_session_type(&pat_type.ty, aliases) => Some(pat_type),
+        _ => None,
+    });
+    let Some(session_arg) = session_args.next() else {
+        return Err(syn::Error::new_spanned(
+            &sig.ident,
+            "No session parameter, expected one of type `Session` or `AsyncSession` (or an alias given with `#[infer_session_type(session = Alias)]`)",
+        ));
+    };
+    if let Some(other) = session_args.next() {
+        return Err(syn::Error::new_spanned(other, "Ambiguous session parameter, only one parameter can be a session"));
+    }
+    match strip_pat_type(&session_arg.pat) {
+        syn::Pat::Ident(pat) => Ok(pat.ident.to_string()),
+        pat => Err(syn::Error::new_spanned(pat, "The session parameter must be bound to a name")),
+    }
+}
+
+fn is_session_type(ty: &syn::Type, aliases: &[syn::Ident]) -> bool {
+    match ty {
+        syn::Type::Reference(reference) => is_session_type(&reference.elem, aliases),
+        syn::Type::Paren(paren) => is_session_type(&paren.elem, aliases),
+        syn::Type::Group(group) => is_session_type(&group.elem, aliases),
+        syn::Type::Path(path) => path.path.segments.last().is_some_and(|segment| {
+            segment.ident == "Session" || segment.ident == "AsyncSession" || aliases.contains(&segment.ident)
+        }),
+        _ => false,
+    }
+}
+
+fn strip_pat_type(pat: &syn::Pat) -> &syn::Pat {
+    match pat {
+        syn::Pat::Type(pat_type) => &pat_type.pat,
+        pat => pat,
+    }
+}
+
+/// Whether `path` calls one of the `helpers` named in the attribute. Only the function's name is compared,
+/// as the same function can be reached by different paths (`handshake`, `self::handshake`, `crate::proto::handshake`).
+fn is_helper(path: &syn::Path, helpers: &[syn::Path]) -> bool {
+    let name = |path: &syn::Path| path.segments.last().map(|segment| segment.ident.clone());
+    helpers.iter().any(|helper| name(helper) == name(path))
+}
+
+/// Whether `expr` is the session itself (`s`) or a borrow of it (`&mut s`), so binding it moves or reborrows the session.
+fn refers_to_session(expr: &syn::Expr, sessions: &Sessions) -> bool {
+    match expr {
+        syn::Expr::Path(_) => is_session_receiver(expr, sessions),
+        syn::Expr::Reference(reference) => refers_to_session(&reference.expr, sessions),
+        syn::Expr::Paren(paren) => refers_to_session(&paren.expr, sessions),
+        syn::Expr::Group(group) => refers_to_session(&group.expr, sessions),
+        _ => false,
+    }
+}
+
+fn sequence_session_types(mut actions: Vec<PartialLocalType>) -> PartialLocalType {
 
     let mut session_type = PartialLocalType::End;
     actions.reverse();
     for action in actions {
         match action {
-            PartialLocalType::Send(label, cont) => {
-                session_type = PartialLocalType::Send(label, Box::new(cont.map_end_to(session_type.clone().into())))
+            PartialLocalType::Send(p, label, cont) => {
+                session_type = PartialLocalType::Send(p, label, Box::new(cont.map_end_to(session_type.clone())))
             }
-            PartialLocalType::Receive(label, cont) => {
-                session_type = PartialLocalType::Receive(label, Box::new(cont.map_end_to(session_type.clone().into())))
+            PartialLocalType::Receive(p, label, cont) => {
+                session_type = PartialLocalType::Receive(p, label, Box::new(cont.map_end_to(session_type.clone())))
             }
             PartialLocalType::RecX(id, cont) => {
-                session_type = PartialLocalType::RecX(id, Box::new(cont.map_break_to(PartialLocalType::End).map_end_to(session_type.clone().into())))
+                session_type = PartialLocalType::RecX(id, Box::new(cont.map_break_to(id, PartialLocalType::End).map_end_to(session_type.clone())))
+            },
+            PartialLocalType::Fragment(name, cont) => {
+                session_type = PartialLocalType::Fragment(name, Box::new(cont.map_end_to(session_type.clone())))
+            },
+            PartialLocalType::ReceiveOthers(p, ty, handled, cont) => {
+                session_type = PartialLocalType::ReceiveOthers(p, ty, handled, Box::new(cont.map_end_to(session_type.clone())))
+            },
+            PartialLocalType::SendAny(p, labels, cont) => {
+                session_type = PartialLocalType::SendAny(p, labels, Box::new(cont.map_end_to(session_type.clone())))
             },
             PartialLocalType::InternalChoice(choices) => {
                 let mut new_choices = vec![];
                 for choice in choices {
-                    new_choices.push(choice.map_end_to(session_type.clone().into()));
+                    new_choices.push(choice.map_end_to(session_type.clone()));
                 }
                 session_type = PartialLocalType::InternalChoice(new_choices);
             },
             PartialLocalType::ExternalChoice(choices) => {
                 let mut new_choices = vec![];
                 for choice in choices {
-                    new_choices.push(choice.map_end_to(session_type.clone().into()));
+                    new_choices.push(choice.map_end_to(session_type.clone()));
                 }
                 session_type = PartialLocalType::ExternalChoice(new_choices);
             },
             PartialLocalType::End => (),
             PartialLocalType::X(id) => {
-                println!("Warning: X-recursion overriding rest of session type sequence");
+                tracing::warn!("X-recursion overriding rest of session type sequence");
                 session_type = PartialLocalType::X(id);
             },
-            PartialLocalType::Break => {
-                println!("Warning: Break overriding rest of session type sequence");
-                session_type = PartialLocalType::Break;
+            PartialLocalType::Break(id) => {
+                tracing::warn!("Break overriding rest of session type sequence");
+                session_type = PartialLocalType::Break(id);
+            }
+            PartialLocalType::Return => {
+                session_type = PartialLocalType::Return;
             }
         }
     }
-    Ok(session_type)
-}
-
-// fn map_end_to(session_type: &LocalType, new_end: LocalType) -> LocalType {
-//     match session_type {
-//         End => new_end,
-//         Send(label, cont) => Send(label.clone(), Box::new(map_end_to(cont, new_end))),
-//         Receive(label, cont) => Receive(label.clone(), Box::new(map_end_to(cont, new_end))),
-//         RecX(cont) => RecX(Box::new(map_end_to(cont, new_end))),
-//         InternalChoice(choices) => {
-//             let mut new_choices = vec![];
-//             for choice in choices {
-//                 new_choices.push(map_end_to(&choice, new_end.clone()));
-//             }
-//             InternalChoice(new_choices)
-//         },
-//         ExternalChoice(choices) => {
-//             let mut new_choices = vec![];
-//             for choice in choices {
-//                 new_choices.push(map_end_to(&choice, new_end.clone()));
-//             }
-//             ExternalChoice(new_choices)
-//         },
-//         X => X
-//     }
-// }
\ No newline at end of file
+    session_type
+}
@@ -1,47 +1,26 @@
-use macros::infer_session_type;
-use session::*;
+#![allow(dead_code, while_true)]
 
+use macros::{infer_session_type, Message};
+use session::{ilt::LocalType, session_type::{Label, MPSTLocalType, Participant}, wire::TypeName, *};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Message)]
 struct Hello;
+#[derive(Serialize, Deserialize, Message)]
 struct Olleh;
+#[derive(Serialize, Deserialize, Message)]
 struct Bye;
+#[derive(Serialize, Deserialize, Message)]
 struct Query;
+#[derive(Serialize, Deserialize, Message)]
 struct Response;
 
+#[derive(Serialize, Deserialize, Message)]
 enum Status {
     Healthy,
     Sick
 }
 
-impl Message for Hello {
-    fn receive() -> Self {
-        Hello
-    }
-}
-
-impl Message for Olleh {
-    fn receive() -> Self {
-        Olleh
-    }
-}
-
-impl Message for Bye {
-    fn receive() -> Self {
-        Bye
-    }
-}
-
-impl Message for Response {
-    fn receive() -> Self {
-        Response
-    }
-}
-
-impl Message for Status {
-    fn receive() -> Self {
-        Status::Healthy
-    }
-}
-
 #[infer_session_type]
 fn example(mut s: Session) {
     s.send(Hello);
@@ -128,9 +107,590 @@ fn example_external_choice(mut s: Session) {
     }
 }
 
+#[infer_session_type]
+fn example_role_addressed(mut s: Session) {
+    s.send_to("B", Hello);
+    match s.branch_from::<Status>("C") {
+        Status::Healthy => {
+            s.receive_from::<Bye>("B");
+        },
+        Status::Sick => {
+            s.send_to("C", Bye);
+        }
+    }
+}
+
+#[test]
+fn role_addressed_calls_name_the_peer() {
+    let b = Participant::named("B");
+    let c = Participant::named("C");
+    assert_eq!(
+        get_session_type_example_role_addressed(),
+        Ok(LocalType::Send(b.clone(), Label::message("Hello"), Box::new(LocalType::ExternalChoice(vec![
+            LocalType::Receive(c.clone(), Label::variant("Status", "Healthy"), Box::new(LocalType::Receive(b, Label::message("Bye"), Box::new(LocalType::End)))),
+            LocalType::Receive(c.clone(), Label::variant("Status", "Sick"), Box::new(LocalType::Send(c, Label::message("Bye"), Box::new(LocalType::End)))),
+        ]))))
+    );
+}
+
+#[test]
+fn monitor_follows_inferred_type() {
+    let mut sessions = channel::connect(&[Participant::named("A"), Participant::named("B"), Participant::named("C")]);
+    let mut c = sessions.pop().unwrap();
+    let _b = sessions.pop().unwrap();
+    let mut a = monitor::MonitoredSession::new(sessions.pop().unwrap(), get_mpst_session_type_example_role_addressed().unwrap());
+
+    a.send_to("B", Hello).unwrap();
+    c.send_to("A", Status::Sick);
+    assert!(matches!(a.branch_from::<Status>("C"), Ok(Status::Sick)));
+    // The Sick branch replies to C, not B
+    assert!(matches!(a.send_to("B", Bye), Err(monitor::Violation::WrongPeer { .. })));
+    a.send_to("C", Bye).unwrap();
+    a.finish().unwrap();
+}
+
+#[infer_session_type]
+async fn example_external_choice_async(mut s: AsyncSession) {
+    s.send(Hello).await;
+    s.receive::<Olleh>().await;
+    println!("Hello world");
+
+    match s.branch::<Status>().await {
+        Status::Healthy => {
+            let mut i = 0;
+            while i < 10 {
+                s.send(Query).await;
+                s.receive::<Response>().await;
+                i+=1;
+            }
+            s.send(Bye).await;
+        },
+        Status::Sick => {
+            s.send(Bye).await;
+        }
+    }
+}
+
+#[test]
+fn async_fn_matches_blocking_fn() {
+    assert_eq!(get_session_type_example_external_choice_async(), get_session_type_example_external_choice());
+    assert_eq!(get_mpst_session_type_example_external_choice_async(), get_mpst_session_type_example_external_choice());
+}
+
+#[infer_session_type]
+fn example_named_session(chan: &mut Session, greeting: &str) {
+    chan.send(Hello);
+    println!("{}", greeting);
+    let t = chan;
+    t.receive::<Bye>();
+}
+
+type Chan = Session;
+
+#[infer_session_type(session = Chan)]
+fn example_session_alias(id: u32, mut c: Chan) {
+    c.send(Hello);
+    println!("{}", id);
+    let mut moved = c;
+    moved.receive::<Bye>();
+}
+
+#[infer_session_type]
+fn example_reborrowed_session(mut s: Session) {
+    let t = &mut s;
+    t.send(Hello);
+    s.receive::<Bye>();
+}
+
+#[test]
+fn session_parameter_found_by_type() {
+    let expected = Ok(LocalType::Send(Participant::anonymous(), Label::message("Hello"), Box::new(
+        LocalType::Receive(Participant::anonymous(), Label::message("Bye"), Box::new(LocalType::End))
+    )));
+    assert_eq!(get_session_type_example_named_session(), expected);
+    assert_eq!(get_session_type_example_session_alias(), expected);
+    // The session is still used by its own name once the borrow ends
+    assert_eq!(get_session_type_example_reborrowed_session(), expected);
+}
+
+#[infer_session_type]
+fn handshake(s: &mut Session) {
+    s.send(Hello);
+    s.receive::<Olleh>();
+}
+
+#[infer_session_type(helper = handshake)]
+fn example_with_helper(mut s: Session) {
+    handshake(&mut s);
+    let mut i = 0;
+    while i < 10 {
+        s.send(Query);
+        s.receive::<Response>();
+        i+=1;
+    }
+    s.send(Bye);
+}
+
+#[infer_session_type(helper = pong)]
+fn ping(s: &mut Session) {
+    s.send(Query);
+    match s.branch::<Status>() {
+        Status::Healthy => pong(s),
+        Status::Sick => {}
+    }
+}
+
+// A helper is recognised by its name, whichever path it is called by
+#[infer_session_type(helper = ping)]
+fn pong(s: &mut Session) {
+    s.send(Hello);
+    self::ping(s);
+}
+
+#[test]
+fn helpers_spliced_at_call_site() {
+    assert_eq!(get_session_type_example_with_helper(), get_session_type_example_internal_choice());
+    assert_eq!(get_mpst_session_type_example_with_helper(), get_mpst_session_type_example_internal_choice());
+
+    // The recursion between the helpers loops back to the outermost call
+    let anon = Participant::anonymous;
+    let expected = LocalType::RecX(1, Box::new(LocalType::Send(anon(), Label::message("Query"), Box::new(LocalType::ExternalChoice(vec![
+        LocalType::Receive(anon(), Label::variant("Status", "Healthy"), Box::new(LocalType::Send(anon(), Label::message("Hello"), Box::new(LocalType::X(1))))),
+        LocalType::Receive(anon(), Label::variant("Status", "Sick"), Box::new(LocalType::End)),
+    ])))));
+    assert_eq!(get_session_type_ping(), Ok(expected));
+    assert!(get_mpst_session_type_ping().is_ok());
+}
+
+fn show_progress(_s: &mut Session) {}
+
+#[infer_session_type]
+fn example_plain_calls(mut s: Session) {
+    s.send(Hello);
+    show_progress(&mut s);
+    drop(s);
+}
+
+#[test]
+fn calls_not_named_as_helpers_do_nothing() {
+    let expected = LocalType::Send(Participant::anonymous(), Label::message("Hello"), Box::new(LocalType::End));
+    assert_eq!(get_session_type_example_plain_calls(), Ok(expected));
+}
+
+fn check(ok: bool) -> Result<(), String> {
+    if ok { Ok(()) } else { Err(String::from("Not ok")) }
+}
+
+#[infer_session_type]
+fn example_early_exit(mut s: Session, give_up: bool) -> Result<(), String> {
+    s.send(Hello);
+    check(give_up)?;
+    if give_up {
+        return Ok(());
+    }
+    s.receive::<Bye>();
+    Ok(())
+}
+
+#[infer_session_type]
+fn example_exit_after_protocol(mut s: Session, give_up: bool) -> Result<(), String> {
+    s.send(Hello);
+    check(give_up)?;
+    Ok(())
+}
+
+#[infer_session_type]
+fn example_return_from_loop(mut s: Session, done: bool) {
+    loop {
+        s.send(Query);
+        if done {
+            return;
+        }
+        s.receive::<Response>();
+    }
+}
+
+#[infer_session_type]
+fn maybe_greet(s: &mut Session, skip: bool) {
+    if skip {
+        return;
+    }
+    s.send(Hello);
+}
+
+#[infer_session_type(helper = maybe_greet)]
+fn example_helper_returns(mut s: Session) {
+    maybe_greet(&mut s, true);
+    s.send(Bye);
+}
+
+#[test]
+fn early_exits_end_the_type() {
+    let anon = Participant::anonymous;
+    // `?` can exit, then `return` can
+    let expected = LocalType::Send(anon(), Label::message("Hello"), Box::new(LocalType::InternalChoice(vec![
+        LocalType::InternalChoice(vec![
+            LocalType::End,
+            LocalType::Receive(anon(), Label::message("Bye"), Box::new(LocalType::End)),
+        ]),
+        LocalType::End,
+    ])));
+    assert_eq!(get_session_type_example_early_exit(), Ok(expected));
+
+    // Exiting once the protocol is over is the same as carrying on, so there is no choice to make
+    let hello = LocalType::Send(anon(), Label::message("Hello"), Box::new(LocalType::End));
+    assert_eq!(get_session_type_example_exit_after_protocol(), Ok(hello));
+    assert!(get_mpst_session_type_example_exit_after_protocol().is_ok());
+
+    // Returning leaves the loop too, rather than carrying on after it
+    let expected = LocalType::RecX(1, Box::new(LocalType::Send(anon(), Label::message("Query"), Box::new(LocalType::InternalChoice(vec![
+        LocalType::End,
+        LocalType::Receive(anon(), Label::message("Response"), Box::new(LocalType::X(1))),
+    ])))));
+    assert_eq!(get_session_type_example_return_from_loop(), Ok(expected));
+
+    // A helper returning only ends the helper
+    let bye = LocalType::Send(anon(), Label::message("Bye"), Box::new(LocalType::End));
+    let expected = LocalType::InternalChoice(vec![bye.clone(), LocalType::Send(anon(), Label::message("Hello"), Box::new(bye))]);
+    assert_eq!(get_session_type_example_helper_returns(), Ok(expected));
+}
+
+#[infer_session_type]
+fn example_continue(mut s: Session, skip: bool) {
+    let mut i = 0;
+    while i < 10 {
+        i += 1;
+        s.send(Query);
+        if skip {
+            continue;
+        }
+        s.receive::<Response>();
+    }
+    s.send(Bye);
+}
+
+#[infer_session_type]
+fn example_labelled_loops(mut s: Session, done: bool) {
+    'outer: loop {
+        s.send(Query);
+        while true {
+            if done {
+                continue 'outer;
+            }
+            match s.branch::<Status>() {
+                Status::Healthy => {}
+                Status::Sick => break 'outer,
+            }
+        }
+    }
+    s.send(Bye);
+}
+
+#[test]
+fn continue_and_labels_target_their_loop() {
+    let anon = Participant::anonymous;
+    let expected = LocalType::RecX(1, Box::new(LocalType::InternalChoice(vec![
+        LocalType::Send(anon(), Label::message("Query"), Box::new(LocalType::InternalChoice(vec![
+            LocalType::X(1),
+            LocalType::Receive(anon(), Label::message("Response"), Box::new(LocalType::X(1))),
+        ]))),
+        LocalType::Send(anon(), Label::message("Bye"), Box::new(LocalType::End)),
+    ])));
+    assert_eq!(get_session_type_example_continue(), Ok(expected));
+
+    // Both jumps leave the inner loop, and the break carries on after the outer one
+    let expected = LocalType::RecX(1, Box::new(LocalType::Send(anon(), Label::message("Query"), Box::new(
+        LocalType::RecX(2, Box::new(LocalType::InternalChoice(vec![
+            LocalType::InternalChoice(vec![
+                LocalType::X(1),
+                LocalType::ExternalChoice(vec![
+                    LocalType::Receive(anon(), Label::variant("Status", "Healthy"), Box::new(LocalType::X(2))),
+                    LocalType::Receive(anon(), Label::variant("Status", "Sick"), Box::new(LocalType::Send(anon(), Label::message("Bye"), Box::new(LocalType::End)))),
+                ]),
+            ]),
+            LocalType::X(1),
+        ])))
+    ))));
+    assert_eq!(get_session_type_example_labelled_loops(), Ok(expected));
+}
+
+#[infer_session_type]
+fn example_sibling_loops(mut s: Session) {
+    for _ in 0..3 {
+        s.send(Query);
+    }
+    for _ in 0..3 {
+        s.receive::<Response>();
+    }
+}
+
+#[test]
+fn sibling_loops_get_distinct_ids() {
+    let anon = Participant::anonymous;
+    let expected = LocalType::RecX(1, Box::new(LocalType::InternalChoice(vec![
+        LocalType::Send(anon(), Label::message("Query"), Box::new(LocalType::X(1))),
+        LocalType::RecX(2, Box::new(LocalType::InternalChoice(vec![
+            LocalType::Receive(anon(), Label::message("Response"), Box::new(LocalType::X(2))),
+            LocalType::End,
+        ]))),
+    ])));
+    assert_eq!(get_session_type_example_sibling_loops(), Ok(expected));
+}
+
+#[infer_session_type]
+fn example_if_let(mut s: Session) {
+    if let Status::Sick = s.branch::<Status>() {
+        s.send(Bye);
+    } else {
+        s.send(Query);
+    }
+    s.send(Hello);
+}
+
+#[infer_session_type]
+fn example_while_let(mut s: Session) {
+    while let Status::Healthy = s.branch::<Status>() {
+        s.send(Query);
+    }
+    s.send(Bye);
+}
+
+#[infer_session_type]
+fn example_let_else(mut s: Session) {
+    let Status::Healthy = s.branch::<Status>() else {
+        s.send(Bye);
+        return;
+    };
+    s.send(Query);
+}
+
+#[infer_session_type]
+fn example_local_let(mut s: Session, reply: Option<u32>) {
+    if let Some(0) = reply {
+        s.send(Query);
+    }
+    let Some(_) = reply else {
+        return;
+    };
+    s.send(Bye);
+}
+
+#[test]
+fn let_patterns_are_choices() {
+    let anon = Participant::anonymous;
+    let send = |label: &str, cont| LocalType::Send(anon(), Label::from(label), Box::new(cont));
+    let receive = |label: &str, cont| LocalType::Receive(anon(), Label::from(label), Box::new(cont));
+
+    // On a branch, the pattern's variant against every other one
+    let expected = LocalType::ExternalChoice(vec![
+        receive("Status::Sick", send("Bye", send("Hello", LocalType::End))),
+        receive("Status::Healthy", send("Query", send("Hello", LocalType::End))),
+    ]);
+    assert_eq!(get_session_type_example_if_let(), Ok(expected));
+    assert!(get_mpst_session_type_example_if_let().is_ok());
+
+    let expected = LocalType::RecX(1, Box::new(LocalType::ExternalChoice(vec![
+        receive("Status::Healthy", send("Query", LocalType::X(1))),
+        receive("Status::Sick", send("Bye", LocalType::End)),
+    ])));
+    assert_eq!(get_session_type_example_while_let(), Ok(expected));
+
+    let expected = LocalType::ExternalChoice(vec![
+        receive("Status::Healthy", send("Query", LocalType::End)),
+        receive("Status::Sick", send("Bye", LocalType::End)),
+    ]);
+    assert_eq!(get_session_type_example_let_else(), Ok(expected));
+
+    // On local data, a choice the function makes itself
+    let expected = LocalType::InternalChoice(vec![
+        send("Query", LocalType::InternalChoice(vec![send("Bye", LocalType::End), LocalType::End])),
+        LocalType::InternalChoice(vec![send("Bye", LocalType::End), LocalType::End]),
+    ]);
+    assert_eq!(get_session_type_example_local_let(), Ok(expected));
+}
+
+#[infer_session_type]
+fn example_local_match(mut s: Session, mode: u32) {
+    match mode {
+        0 => s.send(Hello),
+        n if n > 10 => {
+            s.send(Query);
+            s.receive::<Response>();
+        }
+        _ => {}
+    }
+    s.send(Bye);
+}
+
+#[test]
+fn local_match_is_internal_choice() {
+    let anon = Participant::anonymous;
+    let send = |label: &str, cont| LocalType::Send(anon(), Label::from(label), Box::new(cont));
+    let expected = LocalType::InternalChoice(vec![
+        send("Hello", send("Bye", LocalType::End)),
+        send("Query", LocalType::Receive(anon(), Label::message("Response"), Box::new(send("Bye", LocalType::End)))),
+        send("Bye", LocalType::End),
+    ]);
+    assert_eq!(get_session_type_example_local_match(), Ok(expected));
+}
+
+#[infer_session_type]
+fn example_branch_patterns(mut s: Session) {
+    match s.branch::<Reply>() {
+        Reply::Accept(true) => s.send(Hello),
+        Reply::Counter { price } if price > 10 => s.send(Bye),
+        Reply::Accept(_) | Reply::Counter { .. } => s.send(Query),
+        _ => {}
+    }
+}
+
+#[test]
+fn branch_arms_with_patterns() {
+    let anon = Participant::anonymous;
+    let send = |label: &str| LocalType::Send(anon(), Label::from(label), Box::new(LocalType::End));
+    let receive = |label: &str, cont| LocalType::Receive(anon(), Label::from(label), Box::new(cont));
+    // An arm that might not match falls through to the next one that does, and `_` gets the labels no arm names
+    let expected = LocalType::ExternalChoice(vec![
+        receive("Reply::Accept", LocalType::InternalChoice(vec![send("Hello"), send("Query")])),
+        receive("Reply::Counter", LocalType::InternalChoice(vec![send("Bye"), send("Query")])),
+        receive("Reply::Reject", LocalType::End),
+    ]);
+    assert_eq!(get_session_type_example_branch_patterns(), Ok(expected));
+    // The inferred labels take their sorts from `Reply`, and labels are equal whatever their sorts, so the sorts are checked as printed
+    assert_eq!(
+        get_session_type_example_branch_patterns().unwrap().to_string(),
+        "ExternalChoice(Receive(?, Reply::Accept(bool), InternalChoice(Send(?, Hello, end), Send(?, Query, end), )), \
+         Receive(?, Reply::Counter { price: u32 }, InternalChoice(Send(?, Bye, end), Send(?, Query, end), )), Receive(?, Reply::Reject, end), )"
+    );
+    let Ok(MPSTLocalType::Branch(_, choices)) = get_mpst_session_type_example_branch_patterns() else {
+        panic!("a match on a branch is a branch");
+    };
+    assert_eq!(choices[1].0.to_string(), "Reply::Counter { price: u32 }");
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Message)]
+enum Reply {
+    Accept(bool),
+    Counter { price: u32 },
+    Reject
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, Message)]
+struct Wrapper<T>(T);
+
+#[test]
+fn derived_labels() {
+    assert_eq!(Hello::labels(), vec![String::from("Hello")]);
+    assert_eq!(Reply::labels(), vec![String::from("Accept"), String::from("Counter"), String::from("Reject")]);
+    assert_eq!(Reply::Counter { price: 3 }.qualified_label(), Label::variant("Reply", "Counter"));
+    assert_eq!(Wrapper(3).qualified_label(), Label::message("Wrapper<i32>"));
+    let sorted: Vec<String> = Reply::qualified_labels().iter().map(Label::to_string).collect();
+    assert_eq!(sorted, ["Reply::Accept(bool)", "Reply::Counter { price: u32 }", "Reply::Reject"]);
+    // The sort of a generic type is the one it is instantiated with
+    assert_eq!(Wrapper(3).qualified_label().to_string(), "Wrapper<i32>(i32)");
+    assert_eq!(Wrapper::<String>::qualified_labels()[0].to_string(), "Wrapper<String>(String)");
+    assert_eq!(Reply::Counter { price: 3 }.label(), "Counter");
+    assert_eq!(Reply::Reject.label(), "Reject");
+    assert_eq!(Wrapper(3).label(), "Wrapper<i32>");
+}
+
+fn make_greeting() -> Hello {
+    Hello
+}
+
+async fn make_query() -> Query {
+    Query
+}
+
+#[infer_session_type]
+fn example_typed_sends(mut s: Session, status: Status) {
+    let greeting = Hello;
+    s.send(greeting);
+    s.send(make_greeting());
+    let farewell: Bye = Bye;
+    s.send(farewell);
+    s.send(status);
+    s.send::<Status>(Status::Sick);
+}
+
+#[infer_session_type]
+async fn example_awaited_send(mut s: AsyncSession) {
+    s.send(make_query().await).await;
+}
+
+#[test]
+fn sends_labelled_by_message_type() {
+    let anon = Participant::anonymous;
+    let send = |label: &str, cont| LocalType::Send(anon(), Label::from(label), Box::new(cont));
+    // A local of an enum type could be any of its variants, but a constructor names one even with a turbofish
+    let statuses = |cont: LocalType| LocalType::InternalChoice(vec![send("Status::Healthy", cont.clone()), send("Status::Sick", cont)]);
+    let expected = send("Hello", send("Hello", send("Bye", statuses(send("Status::Sick", LocalType::End)))));
+    assert_eq!(get_session_type_example_typed_sends(), Ok(expected));
+    assert!(get_mpst_session_type_example_typed_sends().is_ok());
+    assert_eq!(get_session_type_example_awaited_send(), Ok(send("Query", LocalType::End)));
+}
+
+mod proto {
+    use super::*;
+
+    #[derive(Serialize, Deserialize, Message)]
+    pub struct Order;
+}
+
+#[derive(Serialize, Deserialize)]
+struct Batch<T>(Vec<T>);
+
+impl Message for Batch<Hello> {}
+
+impl<T: TypeName> TypeName for Batch<T> {
+    fn type_name() -> String {
+        format!("Batch<{}>", T::type_name())
+    }
+}
+
+#[infer_session_type]
+fn example_compound_receives(mut s: Session, batch: Batch<Hello>) {
+    s.receive::<proto::Order>();
+    s.receive::<Wrapper<u32>>();
+    s.receive::<Batch<Hello>>();
+    s.send(proto::Order);
+    s.send(batch);
+    s.send(Wrapper::<u32>(7));
+    s.send::<Wrapper<u32>>(Wrapper(8));
+}
+
+#[test]
+fn compound_receive_types() {
+    let anon = Participant::anonymous;
+    let send = |label: &str, cont| LocalType::Send(anon(), Label::from(label), Box::new(cont));
+    let receive = |label: &str, cont| LocalType::Receive(anon(), Label::from(label), Box::new(cont));
+    // A generic type is labelled with its arguments, whether its `Message` impl is derived or not
+    let wrappers = send("Wrapper<u32>", send("Wrapper<u32>", LocalType::End));
+    let expected = receive("Order", receive("Wrapper<u32>", receive("Batch<Hello>", send("Order", send("Batch<Hello>", wrappers)))));
+    assert_eq!(get_session_type_example_compound_receives(), Ok(expected));
+    // Sorts are checked as printed, as labels are equal whatever their sorts. Batch's own impl leaves its sort unknown
+    assert_eq!(
+        get_session_type_example_compound_receives().unwrap().to_string(),
+        "Receive(?, Order, Receive(?, Wrapper<u32>(u32), Receive(?, Batch<Hello>, Send(?, Order, Send(?, Batch<Hello>, Send(?, Wrapper<u32>(u32), Send(?, Wrapper<u32>(u32), end)))))))"
+    );
+    assert_eq!(Label::from("Batch<(u32, u32)>(Vec<(u32, u32)>)").sort.as_deref(), Some("(Vec<(u32, u32)>)"));
+}
+
+#[test]
+fn branch_decodes_sent_variant() {
+    let mut sessions = channel::connect(&[Participant::named("A"), Participant::named("B")]);
+    let mut b = sessions.pop().unwrap();
+    let mut a = sessions.pop().unwrap();
+
+    a.send(Reply::Counter { price: 5 });
+    a.send(Reply::Reject);
+    assert_eq!(b.branch::<Reply>(), Reply::Counter { price: 5 });
+    assert_eq!(b.branch::<Reply>(), Reply::Reject);
+}
+
 #[test]
 fn it_works() {
-    println!("{}", get_session_type_example_external_choice());
+    println!("{}", get_session_type_example_external_choice().unwrap());
     println!("{}", get_mpst_session_type_example_external_choice().unwrap());
     // let result = add(2, 2);
     // assert_eq!(result, 4);
@@ -0,0 +1,5 @@
+#[test]
+fn inference_errors() {
+    let cases = trybuild::TestCases::new();
+    cases.compile_fail("tests/ui/*.rs");
+}
@@ -0,0 +1,14 @@
+use macros::{infer_session_type, Message};
+use serde::{Deserialize, Serialize};
+use session::Session;
+
+#[derive(Serialize, Deserialize, Message)]
+struct Hello;
+
+#[infer_session_type]
+fn two_mistakes(mut s: Session, role: &str) {
+    let _hello: Hello = s.branch();
+    s.send_to(role, Hello);
+}
+
+fn main() {}
@@ -0,0 +1,14 @@
+use macros::infer_session_type;
+use session::{Message, Session};
+
+#[infer_session_type]
+fn forward<T: Message>(mut s: Session, message: T) {
+    s.send(message);
+}
+
+#[infer_session_type]
+fn forward_any(mut s: Session, message: impl Message) {
+    s.send(message);
+}
+
+fn main() {}
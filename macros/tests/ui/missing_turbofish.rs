@@ -0,0 +1,13 @@
+use macros::{infer_session_type, Message};
+use serde::{Deserialize, Serialize};
+use session::Session;
+
+#[derive(Serialize, Deserialize, Message)]
+struct Hello;
+
+#[infer_session_type]
+fn wait_for_hello(mut s: Session) {
+    let _hello: Hello = s.receive();
+}
+
+fn main() {}
@@ -0,0 +1,14 @@
+use macros::{infer_session_type, Message};
+use serde::{Deserialize, Serialize};
+use session::Session;
+
+#[derive(Serialize, Deserialize, Message)]
+struct Hello;
+
+#[infer_session_type]
+fn greet_later(mut s: Session) {
+    let greet = |s: &mut Session| s.send(Hello);
+    greet(&mut s);
+}
+
+fn main() {}
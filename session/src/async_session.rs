@@ -0,0 +1,129 @@
+use crate::{session_type::Participant, transport::{AsyncTransport, SessionError}, Message};
+
+/// `Session` for async participants: the same methods, awaited.
+/// A receive that has nothing to return yet yields to the executor instead of blocking the thread.
+pub struct AsyncSession {
+    role: Participant,
+    // Where `send` goes when there is more than one peer
+    peer: Option<Participant>,
+    transport: Box<dyn AsyncTransport>,
+}
+
+impl AsyncSession {
+    pub fn new(role: Participant, transport: Box<dyn AsyncTransport>) -> AsyncSession {
+        AsyncSession {
+            role,
+            peer: None,
+            transport,
+        }
+    }
+
+    pub fn role(&self) -> &Participant {
+        &self.role
+    }
+
+    /// Sets the peer that `send` delivers to. Only needed when this endpoint is connected to more than one peer.
+    pub fn set_peer(&mut self, peer: Participant) {
+        self.peer = Some(peer);
+    }
+
+    pub async fn send<T: Message>(&mut self, msg: T) {
+        let result = self.default_peer().and_then(|peer| self.send_frame(&peer, &msg));
+        if let Err(err) = result {
+            panic!("{} failed to send: {}", self.role, err);
+        }
+    }
+
+    pub async fn send_to<T: Message>(&mut self, role: &str, msg: T) {
+        if let Err(err) = self.send_frame(&Participant::named(role), &msg) {
+            panic!("{} failed to send: {}", self.role, err);
+        }
+    }
+
+    pub async fn receive<T: Message>(&mut self) -> T {
+        match self.try_receive(None).await {
+            Ok(msg) => msg,
+            Err(err) => panic!("{} failed to receive: {}", self.role, err),
+        }
+    }
+
+    pub async fn receive_from<T: Message>(&mut self, role: &str) -> T {
+        match self.try_receive(Some(&Participant::named(role))).await {
+            Ok(msg) => msg,
+            Err(err) => panic!("{} failed to receive: {}", self.role, err),
+        }
+    }
+
+    pub async fn branch<T: Message>(&mut self) -> T {
+        match self.try_receive(None).await {
+            Ok(msg) => msg,
+            Err(err) => panic!("{} failed to branch: {}", self.role, err),
+        }
+    }
+
+    pub async fn branch_from<T: Message>(&mut self, role: &str) -> T {
+        match self.try_receive(Some(&Participant::named(role))).await {
+            Ok(msg) => msg,
+            Err(err) => panic!("{} failed to branch: {}", self.role, err),
+        }
+    }
+
+    fn send_frame<T: Message>(&mut self, to: &Participant, msg: &T) -> Result<(), SessionError> {
+        let frame = msg.encode().map_err(SessionError::Encode)?;
+        self.transport.send(to, frame)
+    }
+
+    async fn try_receive<T: Message>(&mut self, from: Option<&Participant>) -> Result<T, SessionError> {
+        let envelope = std::future::poll_fn(|cx| self.transport.poll_receive(cx, from)).await?;
+        T::decode(&envelope.frame).map_err(|error| {
+            self.transport.rejected(&envelope, &error);
+            SessionError::Rejected { from: envelope.from, error }
+        })
+    }
+
+    fn default_peer(&self) -> Result<Participant, SessionError> {
+        if let Some(peer) = &self.peer {
+            return Ok(peer.clone());
+        }
+        match self.transport.peers().as_slice() {
+            [peer] => Ok(peer.clone()),
+            peers => Err(SessionError::AmbiguousPeer(peers.to_vec())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{channel, tests::{Hello, Response}};
+
+    #[test]
+    fn async_sessions() {
+        let roles = [Participant::named("A"), Participant::named("B")];
+        let mut sessions = channel::connect_async(&roles);
+        let mut b = sessions.pop().unwrap();
+        let mut a = sessions.pop().unwrap();
+
+        // Both participants share one thread, so B's receive has to yield for A to run
+        let b = async move {
+            b.receive::<Hello>().await;
+            b.send(Response::Accept(true)).await;
+        };
+        let a = async move {
+            a.send(Hello).await;
+            assert_eq!(a.branch::<Response>().await, Response::Accept(true));
+        };
+        futures::executor::block_on(futures::future::join(b, a));
+    }
+
+    #[test]
+    #[should_panic(expected = "disconnected")]
+    fn dropped_async_peer_fails_receive() {
+        let roles = [Participant::named("A"), Participant::named("B")];
+        let mut sessions = channel::connect_async(&roles);
+        let mut b = sessions.pop().unwrap();
+        drop(sessions);
+
+        futures::executor::block_on(b.receive::<Hello>());
+    }
+}
@@ -0,0 +1,169 @@
+use std::{collections::BTreeMap, sync::mpsc::{self, Sender}, task::{Context, Poll}};
+
+use futures::channel::mpsc::{self as async_mpsc, UnboundedReceiver, UnboundedSender};
+
+use crate::{session_type::Participant, transport::{AsyncTransport, Delivery, Envelope, Mailbox, SessionError, Transport}, wire::Frame, AsyncSession, Session};
+
+/// In-process backend, every participant owns an inbox and a sender into each peer's inbox.
+pub struct ChannelTransport {
+    role: Participant,
+    outboxes: BTreeMap<Participant, Sender<Delivery>>,
+    mailbox: Mailbox,
+}
+
+/// Connects every role to every other role over in-process channels.
+/// Sessions are returned in the same order as `roles`, ready to be moved onto their own threads.
+pub fn connect(roles: &[Participant]) -> Vec<Session> {
+    let (senders, receivers): (Vec<_>, Vec<_>) = roles.iter().map(|_| mpsc::channel()).unzip();
+
+    roles.iter().zip(receivers).map(|(role, inbox)| {
+        let outboxes: BTreeMap<Participant, Sender<Delivery>> = roles.iter().zip(&senders)
+            .filter(|(peer, _)| *peer != role)
+            .map(|(peer, sender)| (peer.clone(), sender.clone()))
+            .collect();
+        let mailbox = Mailbox::new(inbox, outboxes.keys().cloned());
+        Session::new(role.clone(), Box::new(ChannelTransport {
+            role: role.clone(),
+            outboxes,
+            mailbox,
+        }))
+    }).collect()
+}
+
+/// In-process backend for `AsyncSession`, laid out like `ChannelTransport` but over channels that wake a waiting task.
+pub struct AsyncChannelTransport {
+    role: Participant,
+    outboxes: BTreeMap<Participant, UnboundedSender<Delivery>>,
+    mailbox: Mailbox<UnboundedReceiver<Delivery>>,
+}
+
+/// `connect` for async participants. The sessions can be driven by any executor, on one thread or several.
+pub fn connect_async(roles: &[Participant]) -> Vec<AsyncSession> {
+    let (senders, receivers): (Vec<_>, Vec<_>) = roles.iter().map(|_| async_mpsc::unbounded()).unzip();
+
+    roles.iter().zip(receivers).map(|(role, inbox)| {
+        let outboxes: BTreeMap<Participant, UnboundedSender<Delivery>> = roles.iter().zip(&senders)
+            .filter(|(peer, _)| *peer != role)
+            .map(|(peer, sender)| (peer.clone(), sender.clone()))
+            .collect();
+        let mailbox = Mailbox::new(inbox, outboxes.keys().cloned());
+        AsyncSession::new(role.clone(), Box::new(AsyncChannelTransport {
+            role: role.clone(),
+            outboxes,
+            mailbox,
+        }))
+    }).collect()
+}
+
+impl Transport for ChannelTransport {
+    fn peers(&self) -> Vec<Participant> {
+        self.mailbox.peers()
+    }
+
+    fn send(&mut self, to: &Participant, frame: Frame) -> Result<(), SessionError> {
+        let outbox = self.outboxes.get(to).ok_or(SessionError::UnknownPeer(to.clone()))?;
+        outbox.send(Delivery::Message(Envelope { from: self.role.clone(), frame }))
+            .map_err(|_| SessionError::Disconnected(Some(to.clone())))
+    }
+
+    fn receive(&mut self, from: Option<&Participant>) -> Result<Envelope, SessionError> {
+        self.mailbox.take(from)
+    }
+}
+
+impl Drop for ChannelTransport {
+    fn drop(&mut self) {
+        for outbox in self.outboxes.values() {
+            // The peer may already be gone, in which case there is nobody left to tell
+            let _ = outbox.send(Delivery::Closed(self.role.clone()));
+        }
+    }
+}
+
+impl AsyncTransport for AsyncChannelTransport {
+    fn peers(&self) -> Vec<Participant> {
+        self.mailbox.peers()
+    }
+
+    fn send(&mut self, to: &Participant, frame: Frame) -> Result<(), SessionError> {
+        let outbox = self.outboxes.get(to).ok_or(SessionError::UnknownPeer(to.clone()))?;
+        outbox.unbounded_send(Delivery::Message(Envelope { from: self.role.clone(), frame }))
+            .map_err(|_| SessionError::Disconnected(Some(to.clone())))
+    }
+
+    fn poll_receive(&mut self, cx: &mut Context<'_>, from: Option<&Participant>) -> Poll<Result<Envelope, SessionError>> {
+        self.mailbox.poll_take(cx, from)
+    }
+}
+
+impl Drop for AsyncChannelTransport {
+    fn drop(&mut self) {
+        for outbox in self.outboxes.values() {
+            let _ = outbox.unbounded_send(Delivery::Closed(self.role.clone()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::{Deserialize, Serialize};
+    use crate::{tests::{type_names, Bye, Hello, Response}, Message};
+
+    #[test]
+    fn values_are_delivered() {
+        #[derive(Serialize, Deserialize)]
+        struct Quote(u32);
+
+        impl Message for Quote {}
+        type_names!(Quote);
+
+        let roles = [Participant::new(Some(String::from("A"))), Participant::new(Some(String::from("B")))];
+        let mut sessions = connect(&roles);
+        let mut b = sessions.pop().unwrap();
+        let mut a = sessions.pop().unwrap();
+
+        a.send(Quote(42));
+        assert_eq!(b.receive::<Quote>().0, 42);
+        a.send(Response::Reject(-1));
+        assert_eq!(b.branch::<Response>(), Response::Reject(-1));
+    }
+
+    #[test]
+    fn role_addressed_messages() {
+        let roles = [Participant::named("A"), Participant::named("B"), Participant::named("C")];
+        let mut sessions = connect(&roles);
+        let mut c = sessions.pop().unwrap();
+        let mut b = sessions.pop().unwrap();
+        let mut a = sessions.pop().unwrap();
+
+        c.send_to("A", Bye);
+        b.send_to("A", Hello);
+        // The message from C arrived first, but is held back until it is asked for
+        a.receive_from::<Hello>("B");
+        a.receive_from::<Bye>("C");
+    }
+
+    #[test]
+    #[should_panic(expected = "Rejected message from A")]
+    fn wrong_message_type_panics() {
+        let roles = [Participant::new(Some(String::from("A"))), Participant::new(Some(String::from("B")))];
+        let mut sessions = connect(&roles);
+        let mut b = sessions.pop().unwrap();
+        let mut a = sessions.pop().unwrap();
+
+        a.send(Hello);
+        b.receive::<Bye>();
+    }
+
+    #[test]
+    #[should_panic(expected = "disconnected")]
+    fn dropped_peer_fails_receive() {
+        let roles = [Participant::new(Some(String::from("A"))), Participant::new(Some(String::from("B")))];
+        let mut sessions = connect(&roles);
+        let mut b = sessions.pop().unwrap();
+        drop(sessions);
+
+        b.receive::<Hello>();
+    }
+}
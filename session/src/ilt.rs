@@ -1,11 +1,11 @@
-use std::{fmt::Display, collections::HashSet};
+use std::{fmt::Display, collections::HashSet, future::IntoFuture};
 
-use crate::session_type::{MPSTLocalType, Participant};
+use crate::{session_type::{Label, MPSTLocalType, Participant}, Message};
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum LocalType {
-    Send(String, Box<LocalType>),
-    Receive(String, Box<LocalType>),
+    Send(Participant, Label, Box<LocalType>),
+    Receive(Participant, Label, Box<LocalType>),
     InternalChoice(Vec<LocalType>),
     ExternalChoice(Vec<LocalType>),
     RecX(i32, Box<LocalType>),
@@ -15,36 +15,126 @@ pub enum LocalType {
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum PartialLocalType {
-    Send(String, Box<PartialLocalType>),
-    Receive(String, Box<PartialLocalType>),
+    Send(Participant, Label, Box<PartialLocalType>),
+    Receive(Participant, Label, Box<PartialLocalType>),
     InternalChoice(Vec<PartialLocalType>),
     ExternalChoice(Vec<PartialLocalType>),
     RecX(i32, Box<PartialLocalType>),
     X(i32),
-    Break,
+    /// Leaves the loop of the recursion with this id
+    Break(i32),
+    /// An early exit from the function, which nothing after it in the function can follow
+    Return,
+    /// A call passing the session to a helper, an annotated function named with `helper = path` in the caller's attribute,
+    /// which holds the path to the function generated for the helper, see `fragment_ident`.
+    /// It can only be resolved at runtime, by splicing in the callee's type, see `PartialLocalType::fragment`.
+    Fragment(Tokens, Box<PartialLocalType>),
+    /// Receives any label of the message type other than those listed, from a `let` pattern or match arms that name the others,
+    /// or from a receive of a type whose label cannot be told from its name, which lists none.
+    /// The labels are only known at runtime, from `Message::qualified_labels`, see `PartialLocalType::receive_others`.
+    ReceiveOthers(Participant, Tokens, Vec<Label>, Box<PartialLocalType>),
+    /// Sends any of the labels the expression gives, from a send whose message type is known but not its variant.
+    /// The labels are only known at runtime, see `PartialLocalType::send_any`.
+    SendAny(Participant, Tokens, Box<PartialLocalType>),
     End
 }
 
+/// Code of the annotated function that the generated code uses as it is, such as a message type.
+/// Two are the same if they print the same, as tokens have no equality of their own.
+#[derive(Debug, Clone)]
+pub struct Tokens(pub proc_macro2::TokenStream);
+
+impl PartialEq for Tokens {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.to_string() == other.0.to_string()
+    }
+}
+
+impl Eq for Tokens {}
+
+impl Display for Tokens {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl quote::ToTokens for Tokens {
+    fn to_tokens(&self, tokens: &mut proc_macro2::TokenStream) {
+        self.0.to_tokens(tokens)
+    }
+}
+
+/// The state of splicing fragments into a type.
+#[derive(Debug, Default)]
+pub struct Fragments {
+    /// The fragments being spliced, innermost last, with the id a recursive call to each becomes
+    active: Vec<(&'static str, i32)>,
+    /// The last recursion id handed out, so every binder in the spliced type has its own
+    last_id: i32,
+}
+
+/// Functions of up to six arguments, by what they return, so a function's message type can be found from the function alone.
+pub trait Returns<Args> {
+    type Output;
+}
+
+macro_rules! impl_returns {
+    ($($arg:ident),*) => {
+        impl<F, R, $($arg),*> Returns<($($arg,)*)> for F where F: Fn($($arg),*) -> R {
+            type Output = R;
+        }
+    };
+}
+
+impl_returns!();
+impl_returns!(A);
+impl_returns!(A, B);
+impl_returns!(A, B, C);
+impl_returns!(A, B, C, D);
+impl_returns!(A, B, C, D, E);
+impl_returns!(A, B, C, D, E, G);
+
+/// The labels of the message `f` returns, for a send of its result.
+pub fn returned_labels<F: Returns<Args>, Args>(_f: &F) -> Vec<Label> where F::Output: Message {
+    F::Output::qualified_labels()
+}
+
+/// The labels of the message the future `f` returns resolves to, for a send of its awaited result.
+pub fn awaited_labels<F: Returns<Args>, Args>(_f: &F) -> Vec<Label> where F::Output: IntoFuture, <F::Output as IntoFuture>::Output: Message {
+    <F::Output as IntoFuture>::Output::qualified_labels()
+}
+
+/// The function `#[infer_session_type]` generates to build the type of the annotated function `name`.
+pub fn fragment_ident(name: &str) -> syn::Ident {
+    quote::format_ident!("__session_fragment_{}", name)
+}
+
 impl PartialLocalType {
-    pub fn map_break_to(&self, new_break: Self) -> Self {
+    /// Replaces the breaks out of the loop of recursion `rec_id`. Breaks out of other loops are kept for those to replace.
+    pub fn map_break_to(&self, rec_id: i32, new_break: Self) -> Self {
         use PartialLocalType::*;
 
         match self {
-            Break => new_break,
-            Send(label, cont) => Send(label.clone(), Box::new(cont.map_break_to(new_break))),
-            Receive(label, cont) => Receive(label.clone(), Box::new(cont.map_break_to(new_break))),
-            RecX(id, cont) => RecX(*id, Box::new(cont.map_break_to(new_break))),
+            Break(id) if *id == rec_id => new_break,
+            Break(id) => Break(*id),
+            Return => Return,
+            Send(p, label, cont) => Send(p.clone(), label.clone(), Box::new(cont.map_break_to(rec_id, new_break))),
+            Receive(p, label, cont) => Receive(p.clone(), label.clone(), Box::new(cont.map_break_to(rec_id, new_break))),
+            RecX(id, cont) => RecX(*id, Box::new(cont.map_break_to(rec_id, new_break))),
+            Fragment(name, cont) => Fragment(name.clone(), Box::new(cont.map_break_to(rec_id, new_break))),
+            ReceiveOthers(p, ty, handled, cont) => ReceiveOthers(p.clone(), ty.clone(), handled.clone(), Box::new(cont.map_break_to(rec_id, new_break))),
+            SendAny(p, labels, cont) => SendAny(p.clone(), labels.clone(), Box::new(cont.map_break_to(rec_id, new_break))),
             InternalChoice(choices) => {
                 let mut new_choices = vec![];
                 for choice in choices {
-                    new_choices.push(choice.map_break_to(new_break.clone()));
+                    new_choices.push(choice.map_break_to(rec_id, new_break.clone()));
                 }
                 InternalChoice(new_choices)
             },
             ExternalChoice(choices) => {
                 let mut new_choices = vec![];
                 for choice in choices {
-                    new_choices.push(choice.map_break_to(new_break.clone()));
+                    new_choices.push(choice.map_break_to(rec_id, new_break.clone()));
                 }
                 ExternalChoice(new_choices)
             },
@@ -57,10 +147,14 @@ impl PartialLocalType {
         use PartialLocalType::*;
 
         match self {
-            Break => Break,
-            Send(label, cont) => Send(label.clone(), Box::new(cont.map_end_to(new_end))),
-            Receive(label, cont) => Receive(label.clone(), Box::new(cont.map_end_to(new_end))),
+            Break(id) => Break(*id),
+            Return => Return,
+            Send(p, label, cont) => Send(p.clone(), label.clone(), Box::new(cont.map_end_to(new_end))),
+            Receive(p, label, cont) => Receive(p.clone(), label.clone(), Box::new(cont.map_end_to(new_end))),
             RecX(id, cont) => RecX(*id, Box::new(cont.map_end_to(new_end))),
+            Fragment(name, cont) => Fragment(name.clone(), Box::new(cont.map_end_to(new_end))),
+            ReceiveOthers(p, ty, handled, cont) => ReceiveOthers(p.clone(), ty.clone(), handled.clone(), Box::new(cont.map_end_to(new_end))),
+            SendAny(p, labels, cont) => SendAny(p.clone(), labels.clone(), Box::new(cont.map_end_to(new_end))),
             InternalChoice(choices) => {
                 let mut new_choices = vec![];
                 for choice in choices {
@@ -80,12 +174,39 @@ impl PartialLocalType {
         }
     }
 
+    /// Replaces the early exits, at the end of the function they exit.
+    /// A choice that only decides between carrying on and exiting where both do the same, as a `?` after the last session action does, is no choice.
+    pub fn map_return_to(&self, new_return: Self) -> Self {
+        use PartialLocalType::*;
+
+        match self {
+            Return => new_return,
+            Send(p, label, cont) => Send(p.clone(), label.clone(), Box::new(cont.map_return_to(new_return))),
+            Receive(p, label, cont) => Receive(p.clone(), label.clone(), Box::new(cont.map_return_to(new_return))),
+            RecX(id, cont) => RecX(*id, Box::new(cont.map_return_to(new_return))),
+            Fragment(name, cont) => Fragment(name.clone(), Box::new(cont.map_return_to(new_return))),
+            ReceiveOthers(p, ty, handled, cont) => ReceiveOthers(p.clone(), ty.clone(), handled.clone(), Box::new(cont.map_return_to(new_return))),
+            SendAny(p, labels, cont) => SendAny(p.clone(), labels.clone(), Box::new(cont.map_return_to(new_return))),
+            InternalChoice(choices) => {
+                let mut choices: Vec<Self> = choices.iter().map(|choice| choice.map_return_to(new_return.clone())).collect();
+                if choices.iter().all(|choice| *choice == choices[0]) {
+                    return choices.remove(0);
+                }
+                InternalChoice(choices)
+            },
+            ExternalChoice(choices) => ExternalChoice(choices.iter().map(|choice| choice.map_return_to(new_return.clone())).collect()),
+            X(id) => X(*id),
+            Break(id) => Break(*id),
+            End => End
+        }
+    }
+
     pub fn of_local_type(ty: LocalType) -> Self {
         use PartialLocalType::*;
 
         match ty {
-            LocalType::Send(label, cont) => Send(label, Box::new(PartialLocalType::of_local_type(*cont))),
-            LocalType::Receive(label, cont) => Receive(label, Box::new(PartialLocalType::of_local_type(*cont))),
+            LocalType::Send(p, label, cont) => Send(p, label, Box::new(PartialLocalType::of_local_type(*cont))),
+            LocalType::Receive(p, label, cont) => Receive(p, label, Box::new(PartialLocalType::of_local_type(*cont))),
             LocalType::RecX(id, cont) => RecX(id, Box::new(PartialLocalType::of_local_type(*cont))),
             LocalType::InternalChoice(choices) => {
                 let mut new_choices = vec![];
@@ -108,13 +229,13 @@ impl PartialLocalType {
 
     pub fn to_local_type(&self) -> Result<LocalType, String> {
         match self {
-            PartialLocalType::Send(label, ty) => {
+            PartialLocalType::Send(p, label, ty) => {
                 let ty = ty.to_local_type()?;
-                Ok(LocalType::Send(label.to_owned(), Box::new(ty)))
+                Ok(LocalType::Send(p.clone(), label.to_owned(), Box::new(ty)))
             },
-            PartialLocalType::Receive(label, ty) => {
+            PartialLocalType::Receive(p, label, ty) => {
                 let ty = ty.to_local_type()?;
-                Ok(LocalType::Receive(label.to_owned(), Box::new(ty)))
+                Ok(LocalType::Receive(p.clone(), label.to_owned(), Box::new(ty)))
             },
             PartialLocalType::InternalChoice(choices) => {
                 let mut local_choices = Vec::new();
@@ -126,7 +247,11 @@ impl PartialLocalType {
             PartialLocalType::ExternalChoice(choices) => {
                 let mut local_choices = Vec::new();
                 for choice in choices {
-                    local_choices.push(choice.to_local_type()?);
+                    // Receiving the other labels of a message type is itself a choice, of the same peer's labels
+                    match choice.to_local_type()? {
+                        LocalType::ExternalChoice(others) => local_choices.extend(others),
+                        choice => local_choices.push(choice),
+                    }
                 }
                 Ok(LocalType::ExternalChoice(local_choices))
             },
@@ -135,10 +260,155 @@ impl PartialLocalType {
                 Ok(LocalType::RecX(*id, Box::new(ty)))
             },
             PartialLocalType::X(id) => Ok(LocalType::X(*id)),
-            PartialLocalType::Break => Err(String::from("Break is not a valid local type. Please remove before converting to local type")),
+            PartialLocalType::Break(_) => Err(String::from("Break is not a valid local type. Please remove before converting to local type")),
+            PartialLocalType::Return => Err(String::from("Return is not a valid local type. Please map it to the end of the function before converting to local type")),
+            PartialLocalType::Fragment(path, _) => Err(format!("The call to {} is only resolved at runtime, splice it in before converting to local type", path)),
+            PartialLocalType::ReceiveOthers(_, ty, _, _) => Err(format!("The labels of {} are only known at runtime, expand them before converting to local type", ty)),
+            PartialLocalType::SendAny(_, labels, _) => Err(format!("The labels of {} are only known at runtime, expand them before converting to local type", labels)),
             PartialLocalType::End => Ok(LocalType::End)
         }
     }
+
+    /// Whether part of the type can only be built at runtime, from other functions or from message types.
+    pub fn resolved_at_runtime(&self) -> bool {
+        self.any(&|ty| matches!(ty, PartialLocalType::Fragment(..) | PartialLocalType::ReceiveOthers(..) | PartialLocalType::SendAny(..)))
+    }
+
+    fn any(&self, pred: &impl Fn(&PartialLocalType) -> bool) -> bool {
+        use PartialLocalType::*;
+
+        pred(self) || match self {
+            Send(_, _, cont) | Receive(_, _, cont) | RecX(_, cont) | Fragment(_, cont) | ReceiveOthers(_, _, _, cont) | SendAny(_, _, cont) => cont.any(pred),
+            InternalChoice(choices) | ExternalChoice(choices) => choices.iter().any(|choice| choice.any(pred)),
+            X(_) | Break(_) | Return | End => false,
+        }
+    }
+
+    /// Builds the type of the fragment `name`, whose body binds the recursion ids `1..=rec_count`.
+    /// `body` is given the offset that makes those ids fresh, and the ids are then `offset + 1..=offset + rec_count`.
+    /// A fragment that is already being spliced is a recursive call, so it becomes an `X`, and its outermost call a `RecX`.
+    pub fn fragment(name: &'static str, fragments: &mut Fragments, rec_count: i32, body: impl FnOnce(&mut Fragments, i32) -> PartialLocalType) -> PartialLocalType {
+        if let Some((_, id)) = fragments.active.iter().find(|(fragment, _)| *fragment == name) {
+            return PartialLocalType::X(*id);
+        }
+        let offset = fragments.last_id;
+        let id = offset + rec_count + 1;
+        fragments.last_id = id;
+        fragments.active.push((name, id));
+        let ty = body(fragments, offset);
+        fragments.active.pop();
+        if ty.any(&|ty| *ty == PartialLocalType::X(id)) {
+            PartialLocalType::RecX(id, Box::new(ty))
+        } else {
+            ty
+        }
+    }
+
+    /// Receives every label in `labels` but those `handled` elsewhere, each continuing as `cont`. A single label, such as a struct's, is a plain receive.
+    pub fn receive_others(p: Participant, labels: Vec<Label>, handled: &[Label], cont: PartialLocalType) -> PartialLocalType {
+        let mut others: Vec<PartialLocalType> = labels.into_iter()
+            .filter(|label| !handled.contains(label))
+            .map(|label| PartialLocalType::Receive(p.clone(), label, Box::new(cont.clone())))
+            .collect();
+        if others.len() == 1 {
+            return others.remove(0);
+        }
+        PartialLocalType::ExternalChoice(others)
+    }
+
+    /// Sends any label in `labels`, each continuing as `cont`. A single label, such as a struct's, is a plain send.
+    pub fn send_any(p: Participant, mut labels: Vec<Label>, cont: PartialLocalType) -> PartialLocalType {
+        if labels.len() == 1 {
+            return PartialLocalType::Send(p, labels.remove(0), Box::new(cont));
+        }
+        let sends = labels.into_iter()
+            .map(|label| PartialLocalType::Send(p.clone(), label, Box::new(cont.clone())))
+            .collect();
+        PartialLocalType::InternalChoice(sends)
+    }
+
+    /// The expression that builds this type in the body of a fragment, where `fragments` and `offset` name those given to `fragment`'s `body`,
+    /// and `sorts` names the labels whose sorts the type's labels take, see `Label::to_syn_ast`.
+    /// Recursion ids are offset by `offset`, and fragment calls call the callee's generated function.
+    pub fn to_syn_ast(&self, sorts: &syn::Ident, offset: &syn::Ident, fragments: &syn::Ident) -> syn::Expr {
+        let to_syn_ast = |ty: &PartialLocalType| ty.to_syn_ast(sorts, offset, fragments);
+        match self {
+            PartialLocalType::Send(p, label, ty) => {
+                let p = p.to_syn_ast();
+                let label = label.to_syn_ast(sorts);
+                let ty = to_syn_ast(ty);
+                syn::parse_quote! {
+                    ::session::ilt::PartialLocalType::Send(#p, #label, Box::new(#ty))
+                }
+            },
+            PartialLocalType::Receive(p, label, ty) => {
+                let p = p.to_syn_ast();
+                let label = label.to_syn_ast(sorts);
+                let ty = to_syn_ast(ty);
+                syn::parse_quote! {
+                    ::session::ilt::PartialLocalType::Receive(#p, #label, Box::new(#ty))
+                }
+            },
+            PartialLocalType::InternalChoice(choices) => {
+                let syn_choices = choices.iter().map(to_syn_ast);
+                syn::parse_quote! {
+                    ::session::ilt::PartialLocalType::InternalChoice(vec![#(#syn_choices),*])
+                }
+            },
+            PartialLocalType::ExternalChoice(choices) => {
+                let syn_choices = choices.iter().map(to_syn_ast);
+                syn::parse_quote! {
+                    ::session::ilt::PartialLocalType::ExternalChoice(vec![#(#syn_choices),*])
+                }
+            },
+            PartialLocalType::RecX(rec_id, ty) => {
+                let ty = to_syn_ast(ty);
+                syn::parse_quote! {
+                    ::session::ilt::PartialLocalType::RecX(#offset + #rec_id, Box::new(#ty))
+                }
+            },
+            PartialLocalType::X(rec_id) => syn::parse_quote! {
+                ::session::ilt::PartialLocalType::X(#offset + #rec_id)
+            },
+            PartialLocalType::Break(rec_id) => syn::parse_quote! {
+                ::session::ilt::PartialLocalType::Break(#offset + #rec_id)
+            },
+            PartialLocalType::Return => syn::parse_quote! {
+                ::session::ilt::PartialLocalType::Return
+            },
+            PartialLocalType::Fragment(path, cont) => {
+                let cont = to_syn_ast(cont);
+                syn::parse_quote! {
+                    #path(#fragments).map_end_to(#cont)
+                }
+            },
+            PartialLocalType::ReceiveOthers(p, ty, handled, cont) => {
+                let p = p.to_syn_ast();
+                let handled = handled.iter().map(|label| label.to_syn_ast(sorts));
+                let cont = to_syn_ast(cont);
+                syn::parse_quote! {
+                    ::session::ilt::PartialLocalType::receive_others(#p, <#ty as ::session::Message>::qualified_labels(), &[#(#handled),*], #cont)
+                }
+            },
+            PartialLocalType::SendAny(p, labels, cont) => {
+                let p = p.to_syn_ast();
+                let cont = to_syn_ast(cont);
+                syn::parse_quote! {
+                    ::session::ilt::PartialLocalType::send_any(#p, #labels, #cont)
+                }
+            },
+            PartialLocalType::End => syn::parse_quote! {
+                ::session::ilt::PartialLocalType::End
+            }
+        }
+    }
+}
+
+fn flatten_internal(choices: &[LocalType]) -> Vec<&LocalType> {
+    choices.iter().flat_map(|choice| match choice {
+        LocalType::InternalChoice(nested) => flatten_internal(nested),
+        choice => vec![choice],
+    }).collect()
 }
 
 impl From<LocalType> for PartialLocalType {
@@ -150,20 +420,29 @@ impl From<LocalType> for PartialLocalType {
 impl LocalType {
     pub fn to_session_type(&self) -> Result<MPSTLocalType, String> {
         match self {
-            LocalType::Send(label, ty) => {
+            LocalType::Send(p, label, ty) => {
                 let ty = ty.to_session_type()?;
-                Ok(MPSTLocalType::Select(Participant::anonymous(), vec![(label.clone(), ty)]))
+                Ok(MPSTLocalType::Select(p.clone(), vec![(label.clone(), ty)]))
             },
-            LocalType::Receive(label, ty) => {
+            LocalType::Receive(p, label, ty) => {
                 let ty = ty.to_session_type()?;
-                Ok(MPSTLocalType::Branch(Participant::anonymous(), vec![(label.clone(), ty)]))
+                Ok(MPSTLocalType::Branch(p.clone(), vec![(label.clone(), ty)]))
             },
             LocalType::InternalChoice(choices) => {
                 let mut session_choices = Vec::new();
                 let mut unique_labels = HashSet::new();
-                for choice in choices {
+                let mut peer = None;
+                // A choice between choices, such as sending any variant of an enum in one branch of an `if`, is a choice between all their sends
+                for choice in flatten_internal(choices) {
                     match choice {
-                        LocalType::Send(label, cont) => {
+                        LocalType::Send(p, label, cont) => {
+                            match &peer {
+                                None => peer = Some(p.clone()),
+                                Some(peer) if peer != p => {
+                                    return Err(format!("Choice must select from a single participant, found {} and {}", peer, p));
+                                },
+                                _ => (),
+                            }
                             if unique_labels.contains(label) {
                                 return Err(format!("Label {} is not unique", label));
                             }
@@ -176,15 +455,22 @@ impl LocalType {
                         }
                     }
                 }
-                Ok(MPSTLocalType::Select(Participant::anonymous(), session_choices))
+                Ok(MPSTLocalType::Select(peer.unwrap_or(Participant::anonymous()), session_choices))
             },
             LocalType::ExternalChoice(choices) => {
-                // unimplemented!("External choice not implemented");
                 let mut session_choices = Vec::new();
                 let mut unique_labels = HashSet::new();
+                let mut peer = None;
                 for choice in choices {
                     match choice {
-                        LocalType::Receive(label, cont) => {
+                        LocalType::Receive(p, label, cont) => {
+                            match &peer {
+                                None => peer = Some(p.clone()),
+                                Some(peer) if peer != p => {
+                                    return Err(format!("Choice must branch on a single participant, found {} and {}", peer, p));
+                                },
+                                _ => (),
+                            }
                             if unique_labels.contains(label) {
                                 return Err(format!("Label {} is not unique", label));
                             }
@@ -197,7 +483,7 @@ impl LocalType {
                         }
                     }
                 }
-                Ok(MPSTLocalType::Branch(Participant::anonymous(), session_choices))
+                Ok(MPSTLocalType::Branch(peer.unwrap_or(Participant::anonymous()), session_choices))
             },
             LocalType::RecX(id, ty) => {
                 let ty = ty.to_session_type()?;
@@ -208,24 +494,29 @@ impl LocalType {
         }
     }
 
-    pub fn to_syn_ast(&self) -> syn::Expr {
+    /// The expression that builds this type, where `sorts` names the labels its labels take their sorts from, see `Label::to_syn_ast`.
+    pub fn to_syn_ast(&self, sorts: &syn::Ident) -> syn::Expr {
         match self {
-            LocalType::Send(label, ty) => {
-                let ty = ty.to_syn_ast();
+            LocalType::Send(p, label, ty) => {
+                let p = p.to_syn_ast();
+                let label = label.to_syn_ast(sorts);
+                let ty = ty.to_syn_ast(sorts);
                 syn::parse_quote! {
-                    ::session::ilt::LocalType::Send(String::from(#label), Box::new(#ty))
+                    ::session::ilt::LocalType::Send(#p, #label, Box::new(#ty))
                 }
             },
-            LocalType::Receive(label, ty) => {
-                let ty = ty.to_syn_ast();
+            LocalType::Receive(p, label, ty) => {
+                let p = p.to_syn_ast();
+                let label = label.to_syn_ast(sorts);
+                let ty = ty.to_syn_ast(sorts);
                 syn::parse_quote! {
-                    ::session::ilt::LocalType::Receive(String::from(#label), Box::new(#ty))
+                    ::session::ilt::LocalType::Receive(#p, #label, Box::new(#ty))
                 }
             },
             LocalType::InternalChoice(choices) => {
                 let mut syn_choices = Vec::new();
                 for choice in choices {
-                    syn_choices.push(choice.to_syn_ast());
+                    syn_choices.push(choice.to_syn_ast(sorts));
                 }
                 syn::parse_quote! {
                     ::session::ilt::LocalType::InternalChoice(vec![#(#syn_choices),*])
@@ -234,14 +525,14 @@ impl LocalType {
             LocalType::ExternalChoice(choices) => {
                 let mut syn_choices = Vec::new();
                 for choice in choices {
-                    syn_choices.push(choice.to_syn_ast());
+                    syn_choices.push(choice.to_syn_ast(sorts));
                 }
                 syn::parse_quote! {
                     ::session::ilt::LocalType::ExternalChoice(vec![#(#syn_choices),*])
                 }
             },
             LocalType::RecX(id, ty) => {
-                let ty = ty.to_syn_ast();
+                let ty = ty.to_syn_ast(sorts);
                 syn::parse_quote! {
                     ::session::ilt::LocalType::RecX(#id, Box::new(#ty))
                 }
@@ -256,30 +547,35 @@ impl LocalType {
     }
 }
 
+/// With `{:#}`, labels are printed without their message type.
 impl Display for LocalType {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // The nested labels and types are formatted with `f` itself, so they keep its flags
+        let choices = |f: &mut std::fmt::Formatter<'_>, name: &str, choices: &[LocalType]| {
+            write!(f, "{}(", name)?;
+            for choice in choices {
+                Display::fmt(choice, f)?;
+                write!(f, ", ")?;
+            }
+            write!(f, ")")
+        };
         match self {
-            LocalType::Send(label, ty) => write!(f, "Send(?, {}, {})", label, ty),
-            LocalType::Receive(label, ty) => write!(f, "Receive(?, {}, {})", label, ty),
-            LocalType::RecX(id, ty) => write!(f, "μX[{}].{}", id, ty),
+            LocalType::Send(p, label, ty) | LocalType::Receive(p, label, ty) => {
+                let name = if matches!(self, LocalType::Send(..)) { "Send" } else { "Receive" };
+                write!(f, "{}({}, ", name, p)?;
+                Display::fmt(label, f)?;
+                write!(f, ", ")?;
+                Display::fmt(ty, f)?;
+                write!(f, ")")
+            },
+            LocalType::RecX(id, ty) => {
+                write!(f, "μX[{}].", id)?;
+                Display::fmt(ty, f)
+            },
             LocalType::X(id) => write!(f, "X[{}]", id),
             LocalType::End => write!(f, "end"),
-            LocalType::InternalChoice(choices) => {
-                let mut result = String::from("InternalChoice(");
-                for choice in choices {
-                    result.push_str(&format!("{}, ", choice));
-                }
-                result.push_str(")");
-                write!(f, "{}", result)
-            },
-            LocalType::ExternalChoice(choices) => {
-                let mut result = String::from("ExternalChoice(");
-                for choice in choices {
-                    result.push_str(&format!("{}, ", choice));
-                }
-                result.push_str(")");
-                write!(f, "{}", result)
-            }
+            LocalType::InternalChoice(internal) => choices(f, "InternalChoice", internal),
+            LocalType::ExternalChoice(external) => choices(f, "ExternalChoice", external),
         }
     }
-}
\ No newline at end of file
+}
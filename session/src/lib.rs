@@ -1,55 +1,258 @@
 pub mod ilt;
 pub mod session_type;
+pub mod transport;
+pub mod channel;
+pub mod net;
+pub mod async_session;
+pub mod monitor;
+pub mod trace;
+pub mod simulate;
+#[cfg(any(test, feature = "log"))]
+pub mod log;
+pub mod wire;
+pub mod syntax;
 
-pub struct Session;
+pub use serde;
+pub use async_session::AsyncSession;
 
-pub trait Message {
-    fn receive() -> Self;
+use session_type::{Label, Participant};
+use trace::{EventKind, Recorder};
+use transport::{Envelope, SessionError, Transport};
+use wire::{Frame, TypeName, WireError};
+
+/// One participant's endpoint of a running protocol.
+pub struct Session {
+    role: Participant,
+    // Where `send` goes when there is more than one peer
+    peer: Option<Participant>,
+    transport: Box<dyn Transport>,
+    recorder: Option<Recorder>,
+}
+
+/// A value that can be exchanged in a session. On the wire it is framed with its label,
+/// which is the same label the macro infers for it, so receivers can reject messages the protocol does not expect there.
+///
+/// The defaults fit structs, which have a single label: their type name, as `wire::TypeName` gives it.
+/// Enums are sent under one label per variant, and must override `labels` and `label`, as well as `qualified_labels` and `qualified_label` to name their type.
+/// `#[derive(macros::Message)]` writes all of them for you, and `TypeName` too.
+pub trait Message: serde::Serialize + serde::de::DeserializeOwned + TypeName {
+    /// Every label a value of this type can be sent under
+    fn labels() -> Vec<String> {
+        vec![Self::type_name()]
+    }
+
+    /// The label this value is sent under
+    fn label(&self) -> String {
+        Self::type_name()
+    }
+
+    /// Every label of this type as the inferred types have it, along with the type it belongs to and the sort of its payload.
+    /// The default takes each of `labels` to be a type name, and leaves the sorts unknown.
+    fn qualified_labels() -> Vec<Label> {
+        Self::labels().iter().map(|label| Label::message(label)).collect()
+    }
+
+    /// The label of this value as the inferred types have it
+    fn qualified_label(&self) -> Label {
+        Label::message(&self.label())
+    }
+
+    fn encode(&self) -> Result<Frame, WireError> {
+        Ok(Frame {
+            label: self.label(),
+            payload: wire::encode_payload(self)?,
+        })
+    }
+
+    fn decode(frame: &Frame) -> Result<Self, WireError> {
+        let expected = Self::labels();
+        if !expected.contains(&frame.label) {
+            return Err(WireError::UnexpectedLabel { expected, found: frame.label.clone() });
+        }
+        let msg: Self = wire::decode_payload(&frame.payload)?;
+        // The payload of one variant can be valid for another, so check it decoded as the variant that was announced
+        if msg.label() != frame.label {
+            return Err(WireError::UnexpectedLabel { expected: vec![msg.label()], found: frame.label.clone() });
+        }
+        Ok(msg)
+    }
 }
 
 impl Session {
-    pub fn new() -> Session {
-        Session {}
+    pub fn new(role: Participant, transport: Box<dyn Transport>) -> Session {
+        Session {
+            role,
+            peer: None,
+            transport,
+            recorder: None,
+        }
+    }
+
+    pub fn role(&self) -> &Participant {
+        &self.role
+    }
+
+    /// Records every message this endpoint sends or receives from now on into `out`, as a trace `trace::replay` can play back.
+    pub fn record(&mut self, out: impl std::io::Write + Send + 'static) {
+        self.recorder = Some(Recorder::new(out));
+    }
+
+    /// Sets the peer that `send` delivers to. Only needed when this endpoint is connected to more than one peer.
+    pub fn set_peer(&mut self, peer: Participant) {
+        self.peer = Some(peer);
     }
 
-    pub fn send<T>(&mut self, _msg: T) {
-        println!("Sending message");
+    pub fn send<T: Message>(&mut self, msg: T) {
+        let result = self.default_peer().and_then(|peer| self.send_frame(&peer, &msg));
+        if let Err(err) = result {
+            panic!("{} failed to send: {}", self.role, err);
+        }
+    }
+
+    pub fn send_to<T: Message>(&mut self, role: &str, msg: T) {
+        if let Err(err) = self.send_frame(&Participant::named(role), &msg) {
+            panic!("{} failed to send: {}", self.role, err);
+        }
     }
 
     pub fn receive<T: Message>(&mut self) -> T {
-        println!("Receiving message");
-        T::receive()
+        match self.try_receive(None, EventKind::Receive) {
+            Ok(msg) => msg,
+            Err(err) => panic!("{} failed to receive: {}", self.role, err),
+        }
+    }
+
+    pub fn receive_from<T: Message>(&mut self, role: &str) -> T {
+        match self.try_receive(Some(&Participant::named(role)), EventKind::Receive) {
+            Ok(msg) => msg,
+            Err(err) => panic!("{} failed to receive: {}", self.role, err),
+        }
     }
 
     pub fn branch<T: Message>(&mut self) -> T {
-        println!("Sending message");
-        T::receive()
+        match self.try_receive(None, EventKind::Branch) {
+            Ok(msg) => msg,
+            Err(err) => panic!("{} failed to branch: {}", self.role, err),
+        }
+    }
+
+    pub fn branch_from<T: Message>(&mut self, role: &str) -> T {
+        match self.try_receive(Some(&Participant::named(role)), EventKind::Branch) {
+            Ok(msg) => msg,
+            Err(err) => panic!("{} failed to branch: {}", self.role, err),
+        }
+    }
+
+    fn send_frame<T: Message>(&mut self, to: &Participant, msg: &T) -> Result<(), SessionError> {
+        let frame = msg.encode().map_err(SessionError::Encode)?;
+        self.trace(to, EventKind::Send, &msg.qualified_label(), &frame);
+        self.transport.send(to, frame)
+    }
+
+    fn try_receive<T: Message>(&mut self, from: Option<&Participant>, kind: EventKind) -> Result<T, SessionError> {
+        let (envelope, _) = self.receive_envelope::<T>(from, kind)?;
+        T::decode(&envelope.frame).map_err(|error| SessionError::Rejected { from: envelope.from, error })
+    }
+
+    /// Takes the next message before it is decoded, recording it even if it turns out to be rejected.
+    /// Also returns its label as the inferred types have it, which the frame only has the short form of.
+    fn receive_envelope<T: Message>(&mut self, from: Option<&Participant>, kind: EventKind) -> Result<(Envelope, Label), SessionError> {
+        let envelope = self.transport.receive(from)?;
+        let label = T::qualified_labels().into_iter()
+            .find(|label| label.short() == envelope.frame.label)
+            .unwrap_or_else(|| Label::message(&envelope.frame.label));
+        self.trace(&envelope.from, kind, &label, &envelope.frame);
+        Ok((envelope, label))
+    }
+
+    fn trace(&mut self, peer: &Participant, kind: EventKind, label: &Label, frame: &Frame) {
+        if let Some(recorder) = &mut self.recorder {
+            if let Err(err) = recorder.record(&self.role, peer, kind, label, frame) {
+                panic!("{} failed to record: {}", self.role, err);
+            }
+        }
+    }
+
+    fn default_peer(&self) -> Result<Participant, SessionError> {
+        if let Some(peer) = &self.peer {
+            return Ok(peer.clone());
+        }
+        match self.transport.peers().as_slice() {
+            [peer] => Ok(peer.clone()),
+            peers => Err(SessionError::AmbiguousPeer(peers.to_vec())),
+        }
     }
 }
 
+/// The messages the tests of every module exchange, and the tests of `Session` itself.
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
+    use serde::{Deserialize, Serialize};
 
-    struct Hello;
-    struct Bye;
+    #[derive(Serialize, Deserialize)]
+    pub(crate) struct Hello;
+    #[derive(Serialize, Deserialize)]
+    pub(crate) struct Bye;
 
-    impl Message for Hello {
-        fn receive() -> Self {
-            Hello
-        }
+    impl Message for Hello {}
+    impl Message for Bye {}
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    pub(crate) enum Response {
+        Accept(bool),
+        Reject(i32),
     }
 
-    impl Message for Bye {
-        fn receive() -> Self {
-            Bye
+    impl Message for Response {
+        fn labels() -> Vec<String> {
+            vec![String::from("Accept"), String::from("Reject")]
+        }
+
+        fn label(&self) -> String {
+            match self {
+                Response::Accept(_) => String::from("Accept"),
+                Response::Reject(_) => String::from("Reject"),
+            }
+        }
+
+        fn qualified_labels() -> Vec<Label> {
+            vec![Label::variant("Response", "Accept"), Label::variant("Response", "Reject")]
         }
+
+        fn qualified_label(&self) -> Label {
+            Label::variant("Response", &self.label())
+        }
+    }
+
+    /// Names the test messages as the derive would
+    macro_rules! type_names {
+        ($($ty:ident),*) => {
+            $(impl $crate::wire::TypeName for $ty {
+                fn type_name() -> String {
+                    String::from(stringify!($ty))
+                }
+            })*
+        };
     }
 
+    pub(crate) use type_names;
+
+    type_names!(Hello, Bye, Response);
+
     #[test]
     fn it_works() {
-        let mut s = Session::new();
-        s.send(Hello);
-        s.receive::<Bye>();
+        let roles = [Participant::new(Some(String::from("A"))), Participant::new(Some(String::from("B")))];
+        let mut sessions = channel::connect(&roles);
+        let mut b = sessions.pop().unwrap();
+        let mut a = sessions.pop().unwrap();
+
+        let handle = std::thread::spawn(move || {
+            b.receive::<Hello>();
+            b.send(Bye);
+        });
+        a.send(Hello);
+        a.receive::<Bye>();
+        handle.join().unwrap();
     }
 }
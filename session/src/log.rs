@@ -0,0 +1,109 @@
+use std::{collections::BTreeMap, fmt::Debug, sync::{Arc, Mutex}};
+
+use tracing::{field::{Field, Visit}, span, Event, Level, Subscriber};
+use tracing_subscriber::{layer::{Context, SubscriberExt}, registry::LookupSpan, EnvFilter, Layer};
+
+/// The environment variable that turns logging on, with a filter in the same syntax as `RUST_LOG` (e.g. `MPST_LOG=debug`).
+pub const LOG_ENV: &str = "MPST_LOG";
+
+/// Logs the inference and merging to stderr if `MPST_LOG` is set. Nothing is logged otherwise.
+/// Returns whether logging was turned on. Calling it again, or after another subscriber was installed, does nothing.
+pub fn init_from_env() -> bool {
+    let Ok(filter) = EnvFilter::try_from_env(LOG_ENV) else {
+        return false;
+    };
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .try_init()
+        .is_ok()
+}
+
+/// A span an event happened in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogSpan {
+    pub name: String,
+    pub fields: BTreeMap<String, String>,
+}
+
+/// An event, as collected by `capture`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogEvent {
+    pub level: Level,
+    pub target: String,
+    pub message: String,
+    pub fields: BTreeMap<String, String>,
+    /// The spans the event happened in, outermost first
+    pub spans: Vec<LogSpan>,
+}
+
+impl LogEvent {
+    /// Whether the event happened inside a span called `name`.
+    pub fn in_span(&self, name: &str) -> bool {
+        self.spans.iter().any(|span| span.name == name)
+    }
+}
+
+/// Runs `f`, collecting every event logged on this thread while it runs, at every level.
+pub fn capture<R>(f: impl FnOnce() -> R) -> (R, Vec<LogEvent>) {
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let subscriber = tracing_subscriber::registry().with(CaptureLayer { events: events.clone() });
+    let result = tracing::subscriber::with_default(subscriber, f);
+    let events = std::mem::take(&mut *events.lock().unwrap());
+    (result, events)
+}
+
+struct CaptureLayer {
+    events: Arc<Mutex<Vec<LogEvent>>>,
+}
+
+#[derive(Default)]
+struct FieldVisitor {
+    message: String,
+    fields: BTreeMap<String, String>,
+}
+
+impl Visit for FieldVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.record(field, value.to_string());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        self.record(field, format!("{:?}", value));
+    }
+}
+
+impl FieldVisitor {
+    fn record(&mut self, field: &Field, value: String) {
+        if field.name() == "message" {
+            self.message = value;
+        } else {
+            self.fields.insert(field.name().to_string(), value);
+        }
+    }
+}
+
+impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for CaptureLayer {
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        let mut visitor = FieldVisitor::default();
+        attrs.record(&mut visitor);
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(LogSpan { name: attrs.metadata().name().to_string(), fields: visitor.fields });
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let mut visitor = FieldVisitor::default();
+        event.record(&mut visitor);
+        let spans = ctx.event_scope(event)
+            .map(|scope| scope.from_root().filter_map(|span| span.extensions().get::<LogSpan>().cloned()).collect())
+            .unwrap_or_default();
+        self.events.lock().unwrap().push(LogEvent {
+            level: *event.metadata().level(),
+            target: event.metadata().target().to_string(),
+            message: visitor.message,
+            fields: visitor.fields,
+            spans,
+        });
+    }
+}
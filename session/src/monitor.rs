@@ -0,0 +1,442 @@
+use std::fmt::Display;
+
+use crate::{session_type::{Label, MPSTLocalType, Participant}, trace::EventKind, transport::SessionError, Message, Session};
+
+/// Tracks where a participant is in its `MPSTLocalType`, and checks every send and receive against it.
+/// Anonymous participants in the type match any peer, as they do when merging.
+pub struct Monitor {
+    state: MPSTLocalType,
+    // The recursions entered so far, innermost last, for `X` to jump back to
+    recs: Vec<(i32, MPSTLocalType)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ActionKind {
+    Send,
+    Receive,
+}
+
+/// One message as the monitor saw it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Action {
+    pub kind: ActionKind,
+    pub peer: Participant,
+    pub label: Label,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Violation {
+    /// The protocol expected a different kind of action here (e.g. a receive where it expects a send, or anything after `End`)
+    UnexpectedAction { action: Action, expected: MPSTLocalType },
+    UnexpectedLabel { action: Action, expected: Vec<Label> },
+    WrongPeer { action: Action, expected: Participant },
+    /// A receive refused before waiting for a message, as the protocol receives the labels in `expected` from another peer here, or nothing at all (`None`).
+    /// `from` is `None` for a receive from any peer.
+    UnexpectedReceive { from: Option<Participant>, expected: Option<(Participant, Vec<Label>)> },
+    /// The peer hung up while the protocol still expected a message from it
+    PeerDropped { peer: Option<Participant>, expected: MPSTLocalType },
+    /// The session was finished before reaching `End`
+    Unfinished(MPSTLocalType),
+    /// The action followed the protocol, but the transport failed
+    Session(SessionError),
+}
+
+impl Display for Action {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.kind {
+            ActionKind::Send => write!(f, "send {} to {}", self.label, self.peer),
+            ActionKind::Receive => write!(f, "receive {} from {}", self.label, self.peer),
+        }
+    }
+}
+
+impl Display for Violation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Violation::UnexpectedAction { action, expected } => write!(f, "Cannot {} here, expected {}", action, expected),
+            Violation::UnexpectedLabel { action, expected } => {
+                write!(f, "Cannot {}, expected one of [", action)?;
+                for label in expected {
+                    write!(f, "{}, ", label)?;
+                }
+                write!(f, "]")
+            },
+            Violation::WrongPeer { action, expected } => write!(f, "Cannot {}, expected peer {}", action, expected),
+            Violation::UnexpectedReceive { from, expected } => {
+                match from {
+                    Some(from) => write!(f, "Cannot receive from {} here", from)?,
+                    None => write!(f, "Cannot receive here")?,
+                }
+                let Some((peer, labels)) = expected else {
+                    return write!(f, ", expected no receive");
+                };
+                write!(f, ", expected one of [")?;
+                for label in labels {
+                    write!(f, "{}, ", label)?;
+                }
+                write!(f, "] from {}", peer)
+            },
+            Violation::PeerDropped { peer: Some(peer), expected } => write!(f, "Peer {} disconnected, expected {}", peer, expected),
+            Violation::PeerDropped { peer: None, expected } => write!(f, "All peers disconnected, expected {}", expected),
+            Violation::Unfinished(remaining) => write!(f, "Session finished before the protocol ended, remaining {}", remaining),
+            Violation::Session(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for Violation {}
+
+impl From<SessionError> for Violation {
+    fn from(err: SessionError) -> Self {
+        Violation::Session(err)
+    }
+}
+
+impl Monitor {
+    pub fn new(local_type: MPSTLocalType) -> Monitor {
+        let mut monitor = Monitor {
+            state: local_type,
+            recs: Vec::new(),
+        };
+        monitor.unfold();
+        monitor
+    }
+
+    /// What the participant may do next: a `Select`, a `Branch`, or `End`.
+    /// It is only something else if the type loops without sending or receiving anything.
+    pub fn state(&self) -> &MPSTLocalType {
+        &self.state
+    }
+
+    /// A label without its message type, as read from the wire, matches any label with the same short form.
+    pub fn on_send(&mut self, to: &Participant, label: &Label) -> Result<(), Violation> {
+        self.step(Action { kind: ActionKind::Send, peer: to.clone(), label: label.clone() })
+    }
+
+    pub fn on_receive(&mut self, from: &Participant, label: &Label) -> Result<(), Violation> {
+        self.step(Action { kind: ActionKind::Receive, peer: from.clone(), label: label.clone() })
+    }
+
+    /// Checks the protocol has been run to completion.
+    pub fn finish(&self) -> Result<(), Violation> {
+        match self.state {
+            MPSTLocalType::End => Ok(()),
+            _ => Err(Violation::Unfinished(self.state.clone())),
+        }
+    }
+
+    /// Checks a receive is allowed here before waiting on it, so a receive the protocol does not expect fails instead of blocking.
+    /// `from` is `None` for a receive from any peer.
+    pub fn expect_receive(&self, from: Option<&Participant>) -> Result<(), Violation> {
+        let refused = |expected| Err(Violation::UnexpectedReceive { from: from.cloned(), expected });
+        match &self.state {
+            MPSTLocalType::Branch(peer, choices) => match from {
+                Some(from) if !peer.admits(from) => refused(Some((peer.clone(), choices.iter().map(|(label, _)| label.clone()).collect()))),
+                _ => Ok(()),
+            },
+            _ => refused(None),
+        }
+    }
+
+    fn step(&mut self, action: Action) -> Result<(), Violation> {
+        let (peer, choices) = match (&self.state, &action.kind) {
+            (MPSTLocalType::Select(peer, choices), ActionKind::Send) => (peer, choices),
+            (MPSTLocalType::Branch(peer, choices), ActionKind::Receive) => (peer, choices),
+            _ => return Err(Violation::UnexpectedAction { action, expected: self.state.clone() }),
+        };
+        if !peer.admits(&action.peer) {
+            return Err(Violation::WrongPeer { expected: peer.clone(), action });
+        }
+        let Some((_, cont)) = choices.iter().find(|(label, _)| label.admits(&action.label)) else {
+            return Err(Violation::UnexpectedLabel { expected: choices.iter().map(|(label, _)| label.clone()).collect(), action });
+        };
+        self.state = cont.clone();
+        self.unfold();
+        Ok(())
+    }
+
+    /// Enters recursions and follows `X`s until the next action is reached.
+    fn unfold(&mut self) {
+        // Re-entering the same recursion twice without an action means the loop is unguarded, so it is left at the `X`
+        let mut entered = Vec::new();
+        loop {
+            match &self.state {
+                MPSTLocalType::RecX { cont, id, .. } => {
+                    self.recs.push((*id, self.state.clone()));
+                    self.state = (**cont).clone();
+                },
+                MPSTLocalType::X(target, _) => {
+                    let Some(index) = self.recs.iter().rposition(|(id, _)| id == target) else {
+                        return;
+                    };
+                    if entered.contains(&self.recs[index].0) {
+                        return;
+                    }
+                    entered.push(self.recs[index].0);
+                    // Re-entering the recursion pushes it again
+                    self.state = self.recs[index].1.clone();
+                    self.recs.truncate(index);
+                },
+                _ => return,
+            }
+        }
+    }
+}
+
+/// A `Session` that checks every operation against the participant's local type as it runs.
+/// Operations return a `Violation` instead of going ahead when they do not follow the protocol.
+/// `finish` checks the protocol was run to completion. A session dropped before that, without a violation to say why, logs a warning.
+pub struct MonitoredSession {
+    session: Session,
+    monitor: Monitor,
+    // Whether a violation was returned or `finish` called, which leave nothing for dropping the session to report
+    reported: bool,
+}
+
+impl MonitoredSession {
+    pub fn new(session: Session, local_type: MPSTLocalType) -> MonitoredSession {
+        MonitoredSession {
+            session,
+            monitor: Monitor::new(local_type),
+            reported: false,
+        }
+    }
+
+    pub fn monitor(&self) -> &Monitor {
+        &self.monitor
+    }
+
+    pub fn send<T: Message>(&mut self, msg: T) -> Result<(), Violation> {
+        let peer = self.session.default_peer()?;
+        self.send_checked(&peer, &msg)
+    }
+
+    pub fn send_to<T: Message>(&mut self, role: &str, msg: T) -> Result<(), Violation> {
+        self.send_checked(&Participant::named(role), &msg)
+    }
+
+    pub fn receive<T: Message>(&mut self) -> Result<T, Violation> {
+        self.receive_checked(None, EventKind::Receive)
+    }
+
+    pub fn receive_from<T: Message>(&mut self, role: &str) -> Result<T, Violation> {
+        self.receive_checked(Some(&Participant::named(role)), EventKind::Receive)
+    }
+
+    pub fn branch<T: Message>(&mut self) -> Result<T, Violation> {
+        self.receive_checked(None, EventKind::Branch)
+    }
+
+    pub fn branch_from<T: Message>(&mut self, role: &str) -> Result<T, Violation> {
+        self.receive_checked(Some(&Participant::named(role)), EventKind::Branch)
+    }
+
+    /// Ends the session, checking the protocol has been run to completion.
+    pub fn finish(mut self) -> Result<(), Violation> {
+        self.reported = true;
+        self.monitor.finish()
+    }
+
+    fn send_checked<T: Message>(&mut self, to: &Participant, msg: &T) -> Result<(), Violation> {
+        let result = self.monitor.on_send(to, &msg.qualified_label())
+            .and_then(|()| Ok(self.session.send_frame(to, msg)?));
+        self.reported |= result.is_err();
+        result
+    }
+
+    fn receive_checked<T: Message>(&mut self, from: Option<&Participant>, kind: EventKind) -> Result<T, Violation> {
+        let result = self.try_receive_checked(from, kind);
+        self.reported |= result.is_err();
+        result
+    }
+
+    fn try_receive_checked<T: Message>(&mut self, from: Option<&Participant>, kind: EventKind) -> Result<T, Violation> {
+        self.monitor.expect_receive(from)?;
+        // Taking only the peer the protocol names leaves messages from other peers queued for later
+        let from = match (from, &self.monitor.state) {
+            (None, MPSTLocalType::Branch(peer, _)) if !peer.is_anonymous() => Some(peer.clone()),
+            (from, _) => from.cloned(),
+        };
+        let (envelope, label) = self.session.receive_envelope::<T>(from.as_ref(), kind).map_err(|err| match err {
+            SessionError::Disconnected(peer) => Violation::PeerDropped { peer, expected: self.monitor.state.clone() },
+            err => Violation::Session(err),
+        })?;
+        self.monitor.on_receive(&envelope.from, &label)?;
+        T::decode(&envelope.frame).map_err(|error| Violation::Session(SessionError::Rejected { from: envelope.from, error }))
+    }
+}
+
+impl Drop for MonitoredSession {
+    fn drop(&mut self) {
+        if !self.reported && self.monitor.finish().is_err() {
+            tracing::warn!(role = %self.session.role(), remaining = %self.monitor.state, "monitored session dropped before the protocol ended");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::{Deserialize, Serialize};
+    use crate::{channel, tests::{type_names, Bye, Hello, Response}};
+
+    fn a_and_b() -> (Session, Session) {
+        let mut sessions = channel::connect(&[Participant::named("A"), Participant::named("B")]);
+        let b = sessions.pop().unwrap();
+        (sessions.pop().unwrap(), b)
+    }
+
+    // mu X. B!Hello. B?{Accept. X, Reject. end}
+    fn a_type() -> MPSTLocalType {
+        let b = Participant::named("B");
+        MPSTLocalType::recX_with_id(Box::new(MPSTLocalType::send(b.clone(), Label::message("Hello"), MPSTLocalType::Branch(b, vec![
+            (Label::variant("Response", "Accept"), MPSTLocalType::x_with_id(1)),
+            (Label::variant("Response", "Reject"), MPSTLocalType::End),
+        ]))), 1)
+    }
+
+    #[test]
+    fn follows_recursion() {
+        let (a, mut b) = a_and_b();
+        let mut a = MonitoredSession::new(a, a_type());
+        a.send(Hello).unwrap();
+        b.send(Response::Accept(true));
+        a.branch::<Response>().unwrap();
+        a.send_to("B", Hello).unwrap();
+        b.send(Response::Reject(0));
+        a.branch_from::<Response>("B").unwrap();
+        a.finish().unwrap();
+    }
+
+    #[test]
+    fn wrong_label() {
+        let (a, _b) = a_and_b();
+        let mut a = MonitoredSession::new(a, a_type());
+        match a.send(Bye) {
+            Err(Violation::UnexpectedLabel { action, expected }) => {
+                assert_eq!(action.kind, ActionKind::Send);
+                assert_eq!(action.label, Label::message("Bye"));
+                assert_eq!(expected, vec![Label::message("Hello")]);
+            },
+            other => panic!("Expected a label violation, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn same_variant_of_another_enum() {
+        #[derive(Serialize, Deserialize)]
+        enum Verdict {
+            Accept(bool),
+        }
+
+        type_names!(Verdict);
+
+        impl Message for Verdict {
+            fn labels() -> Vec<String> {
+                vec![String::from("Accept")]
+            }
+
+            fn label(&self) -> String {
+                String::from("Accept")
+            }
+
+            fn qualified_labels() -> Vec<Label> {
+                vec![Label::variant("Verdict", "Accept")]
+            }
+        }
+
+        let (a, mut b) = a_and_b();
+        let mut a = MonitoredSession::new(a, a_type());
+        a.send(Hello).unwrap();
+        b.send(Verdict::Accept(true));
+        match a.branch::<Verdict>() {
+            Err(Violation::UnexpectedLabel { action, .. }) => assert_eq!(action.label, Label::variant("Verdict", "Accept")),
+            other => panic!("Expected a label violation, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn wrong_peer() {
+        let mut sessions = channel::connect(&[Participant::named("A"), Participant::named("B"), Participant::named("C")]);
+        sessions.pop();
+        let _b = sessions.pop().unwrap();
+        let mut a = MonitoredSession::new(sessions.pop().unwrap(), a_type());
+        assert!(matches!(a.send_to("C", Hello), Err(Violation::WrongPeer { expected, .. }) if expected == Participant::named("B")));
+    }
+
+    #[test]
+    fn receive_waits_for_the_expected_peer() {
+        let roles = [Participant::named("A"), Participant::named("B"), Participant::named("C")];
+        let mut sessions = channel::connect(&roles);
+        let mut c = sessions.pop().unwrap();
+        let mut b = sessions.pop().unwrap();
+        let a = sessions.pop().unwrap();
+        let a_type = MPSTLocalType::receive(roles[1].clone(), Label::message("Hello"), MPSTLocalType::receive(roles[2].clone(), Label::message("Bye"), MPSTLocalType::End));
+        let mut a = MonitoredSession::new(a, a_type);
+        c.set_peer(roles[0].clone());
+        c.send(Bye);
+        b.set_peer(roles[0].clone());
+        b.send(Hello);
+        // C's message arrived first, but stays queued until the protocol gets to it
+        a.receive::<Hello>().unwrap();
+        a.receive::<Bye>().unwrap();
+        a.finish().unwrap();
+    }
+
+    #[test]
+    fn unexpected_action() {
+        let (a, _b) = a_and_b();
+        let mut a = MonitoredSession::new(a, a_type());
+        assert!(matches!(a.receive::<Hello>(), Err(Violation::UnexpectedReceive { from: None, expected: None })));
+    }
+
+    #[test]
+    fn receive_from_wrong_peer() {
+        let mut sessions = channel::connect(&[Participant::named("A"), Participant::named("B"), Participant::named("C")]);
+        sessions.pop();
+        let _b = sessions.pop().unwrap();
+        let mut a = MonitoredSession::new(sessions.pop().unwrap(), a_type());
+        a.send_to("B", Hello).unwrap();
+        let err = a.branch_from::<Response>("C").unwrap_err();
+        let labels = vec![Label::variant("Response", "Accept"), Label::variant("Response", "Reject")];
+        assert_eq!(err, Violation::UnexpectedReceive { from: Some(Participant::named("C")), expected: Some((Participant::named("B"), labels)) });
+        assert_eq!(err.to_string(), "Cannot receive from C here, expected one of [Response::Accept, Response::Reject, ] from B");
+    }
+
+    #[test]
+    fn dropped_peer() {
+        let (a, b) = a_and_b();
+        let mut a = MonitoredSession::new(a, a_type());
+        a.send(Hello).unwrap();
+        drop(b);
+        assert!(matches!(a.branch::<Response>(), Err(Violation::PeerDropped { peer: Some(peer), .. }) if peer == Participant::named("B")));
+    }
+
+    #[test]
+    fn unfinished() {
+        let (a, _b) = a_and_b();
+        let mut a = MonitoredSession::new(a, a_type());
+        a.send(Hello).unwrap();
+        assert!(matches!(a.finish(), Err(Violation::Unfinished(MPSTLocalType::Branch(..)))));
+    }
+
+    #[test]
+    fn dropped_unfinished() {
+        let (a, _b) = a_and_b();
+        let ((), events) = crate::log::capture(|| {
+            let mut a = MonitoredSession::new(a, a_type());
+            a.send(Hello).unwrap();
+        });
+        let warnings: Vec<_> = events.iter().filter(|event| event.level == tracing::Level::WARN).collect();
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].message, "monitored session dropped before the protocol ended");
+        assert_eq!(warnings[0].fields["role"], "A");
+        // A session that reported why it stopped has nothing more to say
+        let (a, _b) = a_and_b();
+        let ((), events) = crate::log::capture(|| {
+            let mut a = MonitoredSession::new(a, a_type());
+            assert!(a.send(Bye).is_err());
+        });
+        assert!(events.is_empty());
+    }
+}
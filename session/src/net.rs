@@ -0,0 +1,312 @@
+use std::{collections::BTreeMap, io::{self, Read, Write}, net::{Shutdown, SocketAddr, TcpListener, TcpStream}, sync::mpsc::{self, Sender}, thread, time::{Duration, Instant}};
+
+use crate::{session_type::Participant, transport::{Delivery, Envelope, Mailbox, SessionError, Transport}, wire::Frame, Session};
+
+/// How long to keep retrying a peer that is not listening yet.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const CONNECT_RETRY: Duration = Duration::from_millis(20);
+/// The longest label or payload a frame may have, so a bad length prefix cannot make the reader allocate gigabytes.
+pub const MAX_FRAME_SECTION: usize = 16 * 1024 * 1024;
+
+/// Socket backend, with one stream per peer.
+///
+/// Every frame is written as the label then the payload, each prefixed with its length as a big-endian `u32`.
+/// A connection starts with the dialling side sending its role, framed the same way.
+pub struct SocketTransport<S: Stream> {
+    role: Participant,
+    streams: BTreeMap<Participant, S>,
+    mailbox: Mailbox,
+}
+
+/// A byte stream one peer is reached over.
+pub trait Stream: Read + Write + Send + Sized + 'static {
+    fn try_clone(&self) -> io::Result<Self>;
+    fn shutdown(&self) -> io::Result<()>;
+}
+
+impl Stream for TcpStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        TcpStream::try_clone(self)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        TcpStream::shutdown(self, Shutdown::Both)
+    }
+}
+
+#[cfg(unix)]
+impl Stream for std::os::unix::net::UnixStream {
+    fn try_clone(&self) -> io::Result<Self> {
+        std::os::unix::net::UnixStream::try_clone(self)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        std::os::unix::net::UnixStream::shutdown(self, Shutdown::Both)
+    }
+}
+
+/// Connects `me` to every other role in `table` over TCP, listening on `me`'s own address.
+/// Each process calls this with the same table, and it returns once all peers are connected.
+pub fn connect_tcp(me: &Participant, table: &BTreeMap<Participant, SocketAddr>) -> io::Result<Session> {
+    let addr = table.get(me).ok_or_else(|| not_in_table(me))?;
+    let listener = TcpListener::bind(addr)?;
+    connect(
+        me,
+        table,
+        || listener.accept().map(|(stream, _)| stream),
+        |addr| {
+            let stream = TcpStream::connect(addr)?;
+            stream.set_nodelay(true)?;
+            Ok(stream)
+        },
+    )
+}
+
+/// Connects `me` to every other role in `table` over Unix domain sockets, listening on `me`'s own path.
+/// A stale socket left at that path by an earlier run is removed first.
+#[cfg(unix)]
+pub fn connect_unix(me: &Participant, table: &BTreeMap<Participant, std::path::PathBuf>) -> io::Result<Session> {
+    use std::os::unix::{fs::FileTypeExt, net::{UnixListener, UnixStream}};
+
+    let path = table.get(me).ok_or_else(|| not_in_table(me))?;
+    if std::fs::metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    connect(
+        me,
+        table,
+        || listener.accept().map(|(stream, _)| stream),
+        |path| UnixStream::connect(path),
+    )
+}
+
+/// Roles dial every peer ordered before them and accept every peer ordered after them, so each pair connects exactly once.
+fn connect<A, S: Stream>(
+    me: &Participant,
+    table: &BTreeMap<Participant, A>,
+    mut accept: impl FnMut() -> io::Result<S>,
+    dial: impl Fn(&A) -> io::Result<S>,
+) -> io::Result<Session> {
+    if table.keys().any(Participant::is_anonymous) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Every role in the table needs a name"));
+    }
+    let mut streams = BTreeMap::new();
+    for (peer, addr) in table.range(..me) {
+        let mut stream = dial_with_retry(addr, &dial)?;
+        write_string(&mut stream, me.role().unwrap_or_default())?;
+        streams.insert(peer.clone(), stream);
+    }
+    while streams.len() < table.len() - 1 {
+        let mut stream = accept()?;
+        let peer = Participant::named(&read_string(&mut stream)?);
+        if peer <= *me || !table.contains_key(&peer) || streams.contains_key(&peer) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unexpected connection from {}", peer)));
+        }
+        streams.insert(peer, stream);
+    }
+
+    let (sender, inbox) = mpsc::channel();
+    for (peer, stream) in &streams {
+        spawn_reader(peer.clone(), stream.try_clone()?, sender.clone());
+    }
+    let mailbox = Mailbox::new(inbox, streams.keys().cloned());
+    Ok(Session::new(me.clone(), Box::new(SocketTransport {
+        role: me.clone(),
+        streams,
+        mailbox,
+    })))
+}
+
+fn dial_with_retry<A, S>(addr: &A, dial: &impl Fn(&A) -> io::Result<S>) -> io::Result<S> {
+    let start = Instant::now();
+    loop {
+        match dial(addr) {
+            Ok(stream) => return Ok(stream),
+            Err(_) if start.elapsed() < CONNECT_TIMEOUT => thread::sleep(CONNECT_RETRY),
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Forwards frames from one peer into the shared inbox until the stream closes.
+fn spawn_reader<S: Stream>(peer: Participant, mut stream: S, inbox: Sender<Delivery>) {
+    thread::spawn(move || {
+        while let Ok(frame) = read_frame(&mut stream) {
+            if inbox.send(Delivery::Message(Envelope { from: peer.clone(), frame })).is_err() {
+                return;
+            }
+        }
+        let _ = inbox.send(Delivery::Closed(peer));
+    });
+}
+
+fn not_in_table(me: &Participant) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, format!("{} has no address in the table", me))
+}
+
+/// Writes one frame as its length-prefixed label followed by its length-prefixed payload.
+pub fn write_frame(stream: &mut impl Write, frame: &Frame) -> io::Result<()> {
+    write_bytes(stream, frame.label.as_bytes())?;
+    write_bytes(stream, &frame.payload)?;
+    stream.flush()
+}
+
+/// Reads one frame written by `write_frame`, failing with `UnexpectedEof` if the stream closes first.
+pub fn read_frame(stream: &mut impl Read) -> io::Result<Frame> {
+    let label = read_string(stream)?;
+    let payload = read_bytes(stream)?;
+    Ok(Frame { label, payload })
+}
+
+fn write_bytes(stream: &mut impl Write, bytes: &[u8]) -> io::Result<()> {
+    if bytes.len() > MAX_FRAME_SECTION {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Frame section is too long"));
+    }
+    stream.write_all(&(bytes.len() as u32).to_be_bytes())?;
+    stream.write_all(bytes)
+}
+
+fn write_string(stream: &mut impl Write, string: &str) -> io::Result<()> {
+    write_bytes(stream, string.as_bytes())?;
+    stream.flush()
+}
+
+fn read_bytes(stream: &mut impl Read) -> io::Result<Vec<u8>> {
+    let mut len = [0; 4];
+    stream.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_FRAME_SECTION {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Frame section of {} bytes is too long", len)));
+    }
+    let mut bytes = vec![0; len];
+    stream.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn read_string(stream: &mut impl Read) -> io::Result<String> {
+    String::from_utf8(read_bytes(stream)?).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+impl<S: Stream> Transport for SocketTransport<S> {
+    fn peers(&self) -> Vec<Participant> {
+        self.mailbox.peers()
+    }
+
+    fn send(&mut self, to: &Participant, frame: Frame) -> Result<(), SessionError> {
+        let stream = self.streams.get_mut(to).ok_or(SessionError::UnknownPeer(to.clone()))?;
+        write_frame(stream, &frame).map_err(|_| SessionError::Disconnected(Some(to.clone())))
+    }
+
+    fn receive(&mut self, from: Option<&Participant>) -> Result<Envelope, SessionError> {
+        self.mailbox.take(from)
+    }
+}
+
+impl<S: Stream> Drop for SocketTransport<S> {
+    fn drop(&mut self) {
+        for (peer, stream) in &self.streams {
+            // Closing our end tells the peer we are done, and stops our reader thread
+            if let Err(err) = stream.shutdown() {
+                if err.kind() != io::ErrorKind::NotConnected {
+                    tracing::warn!(role = %self.role, %peer, %err, "could not close connection");
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::{Deserialize, Serialize};
+    use crate::{tests::{type_names, Hello, Response}, Message};
+
+    fn three_party_exchange(mut a: Session, mut b: Session, mut c: Session) {
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Quote(u32);
+
+        impl Message for Quote {}
+        type_names!(Quote);
+
+        let b = std::thread::spawn(move || {
+            b.receive_from::<Hello>("A");
+            b.send_to("C", Hello);
+        });
+        let c = std::thread::spawn(move || {
+            c.receive_from::<Hello>("B");
+            c.send_to("A", Quote(15));
+        });
+        a.send_to("B", Hello);
+        assert_eq!(a.receive_from::<Quote>("C"), Quote(15));
+        b.join().unwrap();
+        c.join().unwrap();
+    }
+
+    /// Connects each role from its own thread, as separate processes would
+    fn connect_over<A: Send + Sync + 'static>(
+        table: std::collections::BTreeMap<Participant, A>,
+        connect: fn(&Participant, &std::collections::BTreeMap<Participant, A>) -> std::io::Result<Session>,
+    ) -> Vec<Session> {
+        let table = std::sync::Arc::new(table);
+        let handles: Vec<_> = table.keys().cloned().map(|role| {
+            let table = table.clone();
+            std::thread::spawn(move || connect(&role, &table).unwrap())
+        }).collect();
+        handles.into_iter().map(|handle| handle.join().unwrap()).collect()
+    }
+
+    fn free_tcp_address() -> SocketAddr {
+        // Bind to any free port, then release it for the role to listen on
+        TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+    }
+
+    #[test]
+    fn tcp_sessions() {
+        let table = ["A", "B", "C"].iter().map(|role| (Participant::named(role), free_tcp_address())).collect();
+        let mut sessions = connect_over(table, connect_tcp);
+        let c = sessions.pop().unwrap();
+        let b = sessions.pop().unwrap();
+        let a = sessions.pop().unwrap();
+        three_party_exchange(a, b, c);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unix_socket_sessions() {
+        let dir = std::env::temp_dir().join(format!("session-unix-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let table = ["A", "B", "C"].iter().map(|role| (Participant::named(role), dir.join(role))).collect();
+        let mut sessions = connect_over(table, connect_unix);
+        let c = sessions.pop().unwrap();
+        let b = sessions.pop().unwrap();
+        let a = sessions.pop().unwrap();
+        three_party_exchange(a, b, c);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "disconnected")]
+    fn dropped_tcp_peer_fails_receive() {
+        let table = ["A", "B"].iter().map(|role| (Participant::named(role), free_tcp_address())).collect();
+        let mut sessions = connect_over(table, connect_tcp);
+        let mut b = sessions.pop().unwrap();
+        drop(sessions);
+
+        b.receive::<Hello>();
+    }
+
+    #[test]
+    fn frames_survive_the_byte_stream() {
+        let mut bytes = Vec::new();
+        write_frame(&mut bytes, &Response::Reject(-1).encode().unwrap()).unwrap();
+        write_frame(&mut bytes, &Hello.encode().unwrap()).unwrap();
+        let mut stream = bytes.as_slice();
+        assert_eq!(Response::decode(&read_frame(&mut stream).unwrap()), Ok(Response::Reject(-1)));
+        assert_eq!(read_frame(&mut stream).unwrap().label, "Hello");
+        assert!(read_frame(&mut stream).is_err());
+        // A length prefix over the limit is rejected before anything is allocated for it
+        let mut stream: &[u8] = &u32::MAX.to_be_bytes();
+        assert_eq!(read_frame(&mut stream).unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+    }
+}
@@ -1,12 +1,10 @@
-use std::{borrow::BorrowMut, fmt::Display, vec};
-use lazy_static::lazy_static;
-use parking_lot::Mutex;
+use std::{fmt::Display, vec};
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum MPSTLocalType {
-    Select(Participant, Vec<(String, MPSTLocalType)>),
+    Select(Participant, Vec<(Label, MPSTLocalType)>),
     /* Branch is receive with external choice */
-    Branch(Participant, Vec<(String, MPSTLocalType)>),
+    Branch(Participant, Vec<(Label, MPSTLocalType)>),
     RecX {
         cont: Box<MPSTLocalType>,
         id: i32,
@@ -14,34 +12,21 @@ pub enum MPSTLocalType {
         max_depth: Option<i32>,
     },
     // The bool is for whether the X has been "assigned" to a global recursive id. If it has, then we don't map it again.
-    X(Option<i32>, bool),
+    // Every RecX in a type binds a different id, so an X can never be captured by the wrong one.
+    X(i32, bool),
     End
 }
 
-// Store a global static integer that increments whenever a new recursive type is created
-lazy_static! {
-    static ref RECURSIVE_COUNTER: Mutex<i32> = Mutex::new(0);
-}
-
 impl MPSTLocalType {
-    pub fn receive(p: Participant, label: String, cont: MPSTLocalType) -> MPSTLocalType {
+    pub fn receive(p: Participant, label: Label, cont: MPSTLocalType) -> MPSTLocalType {
         Self::Branch(p, vec![(label, cont)])
     }
 
-    pub fn send(p: Participant, label: String, cont: MPSTLocalType) -> MPSTLocalType {
+    pub fn send(p: Participant, label: Label, cont: MPSTLocalType) -> MPSTLocalType {
         Self::Select(p, vec![(label, cont)])
     }
 
-    pub fn recX(cont: Box<MPSTLocalType>) -> MPSTLocalType {
-        *RECURSIVE_COUNTER.lock() += 1;
-        Self::RecX {
-            cont,
-            id: *RECURSIVE_COUNTER.lock(),
-            min_depth: None,
-            max_depth: None,
-        }
-    }
-
+    #[allow(non_snake_case)]
     pub fn recX_with_id(cont: Box<MPSTLocalType>, id: i32) -> Self {
         Self::RecX {
             cont,
@@ -51,38 +36,32 @@ impl MPSTLocalType {
         }
     }
 
-    pub fn x() -> Self {
-        Self::X(None, false)
-    }
-
     pub fn x_with_id(id: i32) -> Self {
-        Self::X(Some(id), false)
+        Self::X(id, false)
     }
 
     pub fn map_local_x_to_global_rec(&self, local_id: i32, global_id: i32) -> Self {
         match self {
-            MPSTLocalType::X(Some(id), false) if *id == local_id => {
-                MPSTLocalType::X(Some(global_id), true)
-            },
-            MPSTLocalType::X(None, false) => {
-                println!("WARNING: X should have been assigned a local id, assuming first recursive declaration");
-                MPSTLocalType::X(Some(global_id), true)
+            MPSTLocalType::X(id, false) if *id == local_id => {
+                MPSTLocalType::X(global_id, true)
             },
             MPSTLocalType::X(_, _) => self.clone(),
             MPSTLocalType::Select(p, choices) => {
-                let mut new_choices: Vec<(String, MPSTLocalType)> = Vec::new();
+                let mut new_choices: Vec<(Label, MPSTLocalType)> = Vec::new();
                 for (label, cont) in choices {
                     new_choices.push((label.clone(), cont.map_local_x_to_global_rec(local_id, global_id)));
                 }
                 MPSTLocalType::Select(p.clone(), new_choices)
             },
             MPSTLocalType::Branch(p, choices) => {
-                let mut new_choices: Vec<(String, MPSTLocalType)> = Vec::new();
+                let mut new_choices: Vec<(Label, MPSTLocalType)> = Vec::new();
                 for (label, cont) in choices {
                     new_choices.push((label.clone(), cont.map_local_x_to_global_rec(local_id, global_id)));
                 }
                 MPSTLocalType::Branch(p.clone(), new_choices)
             },
+            // A binder of the same id shadows the one being mapped
+            MPSTLocalType::RecX {id, ..} if *id == local_id => self.clone(),
             MPSTLocalType::RecX {cont, id, min_depth, max_depth} => {
                 MPSTLocalType::RecX { cont: Box::new(cont.map_local_x_to_global_rec(local_id, global_id)), id: *id, min_depth: *min_depth, max_depth: *max_depth }
             },
@@ -92,76 +71,66 @@ impl MPSTLocalType {
         }
     }
 
-    pub fn to_syn_ast(&self) -> syn::Expr {
+    /// The expression that builds this type, where `sorts` names the labels its labels take their sorts from, see `Label::to_syn_ast`.
+    pub fn to_syn_ast(&self, sorts: &syn::Ident) -> syn::Expr {
         match self {
             MPSTLocalType::Select(participant, choices) => {
-                println!("SEL Parse Start");
-                let participant: syn::Expr = match &participant.role {
-                    Some(role) => syn::parse_quote! { Some(String::from(#role)) },
-                    None => syn::parse_quote! { None }
-                };
+                let _span = tracing::trace_span!("to_syn_ast", kind = "select").entered();
+                let participant = participant.to_syn_ast();
                 let mut syn_choices: Vec<syn::Expr> = Vec::new();
                 for (label, ty) in choices {
-                    let ty = ty.to_syn_ast();
+                    let label = label.to_syn_ast(sorts);
+                    let ty = ty.to_syn_ast(sorts);
                     syn_choices.push(
                         syn::parse_quote! {
-                            (String::from(#label), #ty)
+                            (#label, #ty)
                         }
                     );
                 }
-                println!("SEL Parse End");
                 syn::parse_quote! {
                     ::session::session_type::MPSTLocalType::Select(
-                        ::session::session_type::Participant::new(#participant),
+                        #participant,
                         vec![#(#syn_choices),*]
                     )
                 }
             },
             MPSTLocalType::Branch(participant, choices) => {
-                println!("BRANCH Parse Start");
-                let participant: syn::Expr = match &participant.role {
-                    Some(role) => syn::parse_quote! { Some(String::from(#role)) },
-                    None => syn::parse_quote! { None }
-                };
+                let _span = tracing::trace_span!("to_syn_ast", kind = "branch").entered();
+                let participant = participant.to_syn_ast();
                 let mut syn_choices: Vec<syn::Expr> = Vec::new();
                 for (label, ty) in choices {
-                    let ty = ty.to_syn_ast();
+                    let label = label.to_syn_ast(sorts);
+                    let ty = ty.to_syn_ast(sorts);
                     syn_choices.push(
                         syn::parse_quote! {
-                            (String::from(#label), #ty)
+                            (#label, #ty)
                         }
                     );
                 }
-                println!("BRANCH Parse End");
                 syn::parse_quote! {
                     ::session::session_type::MPSTLocalType::Branch(
-                        ::session::session_type::Participant::new(#participant),
+                        #participant,
                         vec![#(#syn_choices),*]
                     )
                 }
             },
             MPSTLocalType::RecX {cont, id, min_depth, max_depth}=> {
-                println!("REC Parse Start");
-                let ty = cont.to_syn_ast();
-                println!("REC Parse End");
+                let _span = tracing::trace_span!("to_syn_ast", kind = "rec").entered();
+                let ty = cont.to_syn_ast(sorts);
                 let min_depth: syn::Expr = option_to_ast(min_depth);
                 let max_depth: syn::Expr = option_to_ast(max_depth);
                 syn::parse_quote! {
                     ::session::session_type::MPSTLocalType::RecX {cont: Box::new(#ty), id: #id, min_depth: #min_depth, max_depth: #max_depth}
                 }
             },
-            MPSTLocalType::X(depth, mapped) => {
-                println!("X Parse Start");
-                let depth: syn::Expr = match depth {
-                    Some(depth) => syn::parse_quote! { Some(#depth) },
-                    None => syn::parse_quote! { None }
-                };
+            MPSTLocalType::X(id, mapped) => {
+                let _span = tracing::trace_span!("to_syn_ast", kind = "x").entered();
                 syn::parse_quote! {
-                    ::session::session_type::MPSTLocalType::X(#depth, #mapped)
+                    ::session::session_type::MPSTLocalType::X(#id, #mapped)
                 }
             },
             MPSTLocalType::End => {
-                println!("END Parse Start");
+                let _span = tracing::trace_span!("to_syn_ast", kind = "end").entered();
                 syn::parse_quote! {
                     ::session::session_type::MPSTLocalType::End
                 }
@@ -170,33 +139,48 @@ impl MPSTLocalType {
     }
 }
 
+/// Prints the text syntax of `session::syntax`, e.g. `mu X1. B!{ Hello. A?{ Left. end, Right. X1 } }`, which parses back to the same type.
+/// With `{:#}`, labels are printed without their message type.
 impl Display for MPSTLocalType {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // The nested labels and types are formatted with `f` itself, so they keep its flags
         match self {
             MPSTLocalType::Select(participant, choices) => {
-                write!(f, "Select<{}, {{", participant)?;
-                for (label, cont) in choices {
-                    write!(f, "{}.{}, ", label, cont)?;
-                }
-                write!(f, "}}")?;
+                write!(f, "{}!", participant)?;
+                fmt_choices(choices, f)?;
             },
             MPSTLocalType::Branch(participant, choices) => {
-                write!(f, "Branch<{}, {{", participant)?;
-                for (label, cont) in choices {
-                    write!(f, "{}.{}, ", label, cont)?;
-                }
-                write!(f, "}}")?;
+                write!(f, "{}?", participant)?;
+                fmt_choices(choices, f)?;
             },
-            MPSTLocalType::RecX{cont, id, min_depth, max_depth} => {
-                write!(f, "Rec[{}]<{}>", id, cont)?;
+            MPSTLocalType::RecX{cont, id, ..} => {
+                write!(f, "mu X{}. ", id)?;
+                Display::fmt(cont, f)?;
             },
-            MPSTLocalType::X(depth, _mapped) => write!(f, "X({:?}, {})", depth, _mapped)?,
-            MPSTLocalType::End => write!(f, "End")?
+            MPSTLocalType::X(id, _) => write!(f, "X{}", id)?,
+            MPSTLocalType::End => write!(f, "end")?
         }
         Ok(())
     }
 }
 
+/// Prints `{ label. cont, ... }`, or `{}` without choices.
+pub fn fmt_choices<T: Display>(choices: &[(Label, T)], f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    if choices.is_empty() {
+        return write!(f, "{{}}");
+    }
+    write!(f, "{{ ")?;
+    for (i, (label, cont)) in choices.iter().enumerate() {
+        if i > 0 {
+            write!(f, ", ")?;
+        }
+        Display::fmt(label, f)?;
+        write!(f, ". ")?;
+        Display::fmt(cont, f)?;
+    }
+    write!(f, " }}")
+}
+
 fn option_to_ast<T: quote::ToTokens>(opt: &Option<T>) -> syn::Expr {
     match opt {
         Some(val) => {
@@ -207,15 +191,17 @@ fn option_to_ast<T: quote::ToTokens>(opt: &Option<T>) -> syn::Expr {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, serde::Serialize, serde::Deserialize)]
+#[serde(transparent)]
 pub struct Participant {
-    role: Option<String>
+    // Boxed for the same reason as `Label`'s strings
+    role: Option<Box<str>>
 }
 
 impl Participant {
     pub fn new(role: Option<String>) -> Participant {
         Participant {
-            role
+            role: role.map(String::into_boxed_str)
         }
     }
 
@@ -224,6 +210,35 @@ impl Participant {
             role: None
         }
     }
+
+    pub fn named(role: &str) -> Participant {
+        Participant {
+            role: Some(role.into())
+        }
+    }
+
+    pub fn role(&self) -> Option<&str> {
+        self.role.as_deref()
+    }
+
+    pub fn is_anonymous(&self) -> bool {
+        self.role.is_none()
+    }
+
+    /// Whether a local type addressed to `self` can be talking to `other`. Anonymous participants can talk to anyone.
+    pub fn admits(&self, other: &Participant) -> bool {
+        self.is_anonymous() || self == other
+    }
+
+    pub fn to_syn_ast(&self) -> syn::Expr {
+        match &self.role {
+            Some(role) => {
+                let role: &str = role;
+                syn::parse_quote! { ::session::session_type::Participant::named(#role) }
+            },
+            None => syn::parse_quote! { ::session::session_type::Participant::anonymous() }
+        }
+    }
 }
 
 impl Display for Participant {
@@ -233,4 +248,222 @@ impl Display for Participant {
             None => write!(f, "?")
         }
     }
-}
\ No newline at end of file
+}
+
+/// The label of a message: the message type, and the variant for an enum's.
+/// Two enums with a variant of the same name have different labels, so they cannot be mistaken for each other.
+/// It also carries the sort of the message's payload if it is known, which labels are compared without: see `Label::sort_agrees`.
+#[derive(Debug, Clone)]
+pub struct Label {
+    // Boxed strings keep labels small, as every monitor violation and divergence carries one
+    pub message: Box<str>,
+    pub variant: Option<Box<str>>,
+    /// The payload as it is declared after the name, e.g. `(u32)` or `{ price: u32 }`, and empty for a unit struct or variant
+    pub sort: Option<Box<str>>,
+}
+
+impl Label {
+    /// The label of a struct, which is its type name.
+    pub fn message(message: &str) -> Label {
+        Label {
+            message: message.into(),
+            variant: None,
+            sort: None,
+        }
+    }
+
+    /// The label of an enum's variant.
+    pub fn variant(message: &str, variant: &str) -> Label {
+        Label {
+            message: message.into(),
+            variant: Some(variant.into()),
+            sort: None,
+        }
+    }
+
+    pub fn with_sort(mut self, sort: &str) -> Label {
+        self.sort = Some(sort.into());
+        self
+    }
+
+    /// This label with the sort of the same label in `sorts`, the labels of the message types it could be from, if it has none and one of them is it.
+    pub fn sorted(mut self, sorts: &[Label]) -> Label {
+        if self.sort.is_none() {
+            self.sort = sorts.iter().find(|label| **label == self).and_then(|label| label.sort.clone());
+        }
+        self
+    }
+
+    /// The label without its message type, as it is sent on the wire: the variant, or the type name of a struct.
+    pub fn short(&self) -> &str {
+        self.variant.as_deref().unwrap_or(&self.message)
+    }
+
+    /// Whether `other` is this label, or its short form, which is all a label read back from the wire or a trace has.
+    pub fn admits(&self, other: &Label) -> bool {
+        self == other || (other.variant.is_none() && &*other.message == self.short())
+    }
+
+    /// Whether the two labels could be the same message, which they cannot if both know their sort and those differ.
+    pub fn sort_agrees(&self, other: &Label) -> bool {
+        match (&self.sort, &other.sort) {
+            (Some(sort), Some(other)) => sort == other,
+            _ => true,
+        }
+    }
+
+    /// The expression that builds this label. If its sort is not known here, it is looked up in the labels the variable `sorts` holds.
+    pub fn to_syn_ast(&self, sorts: &syn::Ident) -> syn::Expr {
+        let message: &str = &self.message;
+        let label: syn::Expr = match &self.variant {
+            Some(variant) => {
+                let variant: &str = variant;
+                syn::parse_quote! { ::session::session_type::Label::variant(#message, #variant) }
+            },
+            None => syn::parse_quote! { ::session::session_type::Label::message(#message) },
+        };
+        match &self.sort {
+            Some(sort) => {
+                let sort: &str = sort;
+                syn::parse_quote! { #label.with_sort(#sort) }
+            },
+            None => syn::parse_quote! { #label.sorted(&#sorts) },
+        }
+    }
+
+    fn key(&self) -> (&str, Option<&str>) {
+        (&self.message, self.variant.as_deref())
+    }
+}
+
+impl PartialEq for Label {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for Label {}
+
+impl std::hash::Hash for Label {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.key().hash(state)
+    }
+}
+
+impl PartialOrd for Label {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Label {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+/// Reads a label as it is displayed, `Response::Accept`, `Hello` or `Quote(u32)`.
+impl From<&str> for Label {
+    fn from(label: &str) -> Self {
+        // The sort starts at the first bracket outside the generic arguments of the name, as in `Batch<(u32, u32)>(Vec<(u32, u32)>)`
+        let mut depth = 0;
+        let sort_start = label.char_indices().find(|(_, c)| {
+            match c {
+                '<' => depth += 1,
+                '>' => depth -= 1,
+                _ => (),
+            }
+            depth == 0 && (*c == '(' || *c == '{')
+        });
+        let (name, sort) = match sort_start.map(|(start, _)| start) {
+            Some(start) => (label[..start].trim_end(), Some(&label[start..])),
+            None => (label, None),
+        };
+        // The variant follows the last `::` outside the generic arguments, so `Vec<proto::Item>` is a message
+        let mut depth = 0;
+        let separator = name.char_indices().filter(|(i, c)| {
+            match c {
+                '<' | '(' | '[' => depth += 1,
+                '>' | ')' | ']' => depth -= 1,
+                _ => (),
+            }
+            depth == 0 && name[*i..].starts_with("::")
+        }).last();
+        let label = match separator.map(|(i, _)| i) {
+            Some(i) => Label::variant(&name[..i], &name[i + 2..]),
+            None => Label::message(name),
+        };
+        match sort {
+            Some(sort) => label.with_sort(sort),
+            None => label,
+        }
+    }
+}
+
+/// Labels are written as they are displayed, so traces stay readable.
+impl serde::Serialize for Label {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Label {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let label = String::deserialize(deserializer)?;
+        Ok(Label::from(label.as_str()))
+    }
+}
+
+/// Prints `Response::Accept(bool)`, or only `Accept(bool)` with `{:#}`. The sort is left out if it is not known.
+impl Display for Label {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.variant {
+            Some(variant) if !f.alternate() => write!(f, "{}::{}", self.message, variant)?,
+            _ => write!(f, "{}", self.short())?,
+        }
+        match self.sort.as_deref() {
+            Some(sort) if sort.starts_with('{') => write!(f, " {}", sort),
+            Some(sort) => write!(f, "{}", sort),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{tests::{Hello, Response}, Message};
+
+    #[test]
+    fn labels_name_their_type() {
+        let accept = Label::variant("Response", "Accept");
+        assert_eq!(accept.to_string(), "Response::Accept");
+        assert_eq!(format!("{:#}", accept), "Accept");
+        assert_eq!(Label::from("Response::Accept"), accept);
+        assert_eq!(Label::from("Hello"), Label::message("Hello"));
+        // Paths in generic arguments are part of the message
+        assert_eq!(Label::from("Vec<proto::Item>"), Label::message("Vec<proto::Item>"));
+        assert_eq!(Label::from("Batch<(a::B, [c::D; 2])>::Full(u32)"), Label::variant("Batch<(a::B, [c::D; 2])>", "Full").with_sort("(u32)"));
+        assert_ne!(accept, Label::variant("Verdict", "Accept"));
+        // A short label could be any type's
+        assert!(accept.admits(&Label::from("Accept")));
+        assert!(!accept.admits(&Label::variant("Verdict", "Accept")));
+        assert_eq!(Response::Reject(1).qualified_label(), Label::variant("Response", "Reject"));
+        assert_eq!(Hello.qualified_label(), Label::message("Hello"));
+    }
+
+    #[test]
+    fn labels_carry_sorts() {
+        let quote = Label::from("Quote(u32)");
+        assert_eq!(quote.sort.as_deref(), Some("(u32)"));
+        assert_eq!(quote.to_string(), "Quote(u32)");
+        let counter = Label::from("Reply::Counter { price: u32 }");
+        assert_eq!(counter, Label::variant("Reply", "Counter").with_sort("{ price: u32 }"));
+        assert_eq!(format!("{:#}", counter), "Counter { price: u32 }");
+        // The sort is not part of which message a label is, but two known sorts have to agree
+        assert_eq!(quote, Label::from("Quote(String)"));
+        assert!(!quote.sort_agrees(&Label::from("Quote(String)")));
+        assert!(quote.sort_agrees(&Label::message("Quote")));
+        assert_eq!(Label::message("Quote").sorted(std::slice::from_ref(&quote)).sort, quote.sort);
+    }
+}
@@ -0,0 +1,396 @@
+use std::{collections::{BTreeMap, VecDeque}, fmt::Display, future::Future, panic::AssertUnwindSafe, pin::Pin, sync::{Arc, Mutex}, task::{Context, Poll, Waker}};
+
+use crate::{session_type::Participant, transport::{AsyncTransport, Envelope, SessionError}, wire::{Frame, WireError}, AsyncSession};
+
+type Program = Box<dyn Fn(AsyncSession) -> Pin<Box<dyn Future<Output = ()>>>>;
+
+/// Runs async participants against each other on the current thread, one step at a time.
+/// A step polls one participant until it waits for a message that has not arrived, or returns.
+/// The `Scheduler` picks which participant steps next, so every run is reproducible.
+pub struct Simulation {
+    participants: Vec<(Participant, Program)>,
+    max_steps: usize,
+}
+
+/// Decides the order participants run in.
+pub trait Scheduler {
+    /// Picks which of the participants that can make progress takes the next step, as an index into `enabled`.
+    fn pick(&mut self, enabled: &[Participant]) -> usize;
+
+    /// Called after every successful run. Returning `true` runs the simulation again from the start.
+    fn rerun(&mut self) -> bool {
+        false
+    }
+}
+
+/// Steps each participant in turn, in order of their roles, skipping those that are waiting.
+#[derive(Default)]
+pub struct RoundRobin {
+    last: Option<Participant>,
+}
+
+/// Picks a participant at random, from a seed so a failing schedule can be reproduced.
+pub struct SeededRandom {
+    state: u64,
+}
+
+/// Runs the simulation once for every possible schedule.
+#[derive(Default)]
+pub struct Exhaustive {
+    // The choice made at each step of the current run, and how many there were to choose from
+    choices: Vec<(usize, usize)>,
+    depth: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Failure {
+    /// Nobody can make progress, but these participants are still waiting, on a specific peer or on anyone
+    Deadlock(Vec<(Participant, Option<Participant>)>),
+    /// Every participant returned, but these messages were never received, as (from, to, label)
+    Unreceived(Vec<(Participant, Participant, String)>),
+    /// A participant received a message it could not decode as what it expected
+    LabelMismatch { role: Participant, from: Participant, error: WireError },
+    Panicked { role: Participant, message: String },
+    /// The run went on for longer than `max_steps`
+    StepLimit(usize),
+}
+
+/// A failed run, with the schedule that led to it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SimulationError {
+    /// The participant that took each step
+    pub schedule: Vec<Participant>,
+    pub failure: Failure,
+}
+
+/// What the successful runs did.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Report {
+    pub runs: usize,
+    /// The schedule of the last run
+    pub schedule: Vec<Participant>,
+}
+
+impl Display for Failure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Failure::Deadlock(waiting) => {
+                write!(f, "Deadlock, waiting: [")?;
+                for (role, from) in waiting {
+                    match from {
+                        Some(from) => write!(f, "{} on {}, ", role, from)?,
+                        None => write!(f, "{} on anyone, ", role)?,
+                    }
+                }
+                write!(f, "]")
+            },
+            Failure::Unreceived(messages) => {
+                write!(f, "Messages never received: [")?;
+                for (from, to, label) in messages {
+                    write!(f, "{} from {} to {}, ", label, from, to)?;
+                }
+                write!(f, "]")
+            },
+            Failure::LabelMismatch { role, from, error } => write!(f, "{} rejected a message from {}: {}", role, from, error),
+            Failure::Panicked { role, message } => write!(f, "{} panicked: {}", role, message),
+            Failure::StepLimit(steps) => write!(f, "Still running after {} steps", steps),
+        }
+    }
+}
+
+impl Display for SimulationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}, after schedule [", self.failure)?;
+        for role in &self.schedule {
+            write!(f, "{}, ", role)?;
+        }
+        write!(f, "]")
+    }
+}
+
+impl std::error::Error for SimulationError {}
+
+impl Default for Simulation {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Simulation {
+    pub fn new() -> Simulation {
+        Simulation {
+            participants: Vec::new(),
+            max_steps: 10_000,
+        }
+    }
+
+    /// Adds a participant. `program` is called again for every run, so it can be an `async fn` taking the session.
+    pub fn participant<F, Fut>(mut self, role: &str, program: F) -> Simulation
+    where
+        F: Fn(AsyncSession) -> Fut + 'static,
+        Fut: Future<Output = ()> + 'static,
+    {
+        self.participants.push((Participant::named(role), Box::new(move |s| Box::pin(program(s)))));
+        self
+    }
+
+    pub fn max_steps(mut self, max_steps: usize) -> Simulation {
+        self.max_steps = max_steps;
+        self
+    }
+
+    /// Runs the participants under `scheduler` until it stops asking for reruns, or a run fails.
+    pub fn run(&self, scheduler: &mut dyn Scheduler) -> Result<Report, SimulationError> {
+        let mut runs = 0;
+        loop {
+            let schedule = self.run_once(scheduler)?;
+            runs += 1;
+            if !scheduler.rerun() {
+                return Ok(Report { runs, schedule });
+            }
+        }
+    }
+
+    fn run_once(&self, scheduler: &mut dyn Scheduler) -> Result<Vec<Participant>, SimulationError> {
+        let mut roles: Vec<Participant> = self.participants.iter().map(|(role, _)| role.clone()).collect();
+        roles.sort();
+        let network = Arc::new(Mutex::new(Network::default()));
+        let mut running: BTreeMap<Participant, Pin<Box<dyn Future<Output = ()>>>> = self.participants.iter().map(|(role, program)| {
+            let transport = SimTransport { role: role.clone(), peers: roles.iter().filter(|peer| *peer != role).cloned().collect(), network: network.clone() };
+            (role.clone(), program(AsyncSession::new(role.clone(), Box::new(transport))))
+        }).collect();
+
+        let mut schedule = Vec::new();
+        let mut cx = Context::from_waker(Waker::noop());
+        loop {
+            let fail = |schedule: Vec<Participant>, failure| Err(SimulationError { schedule, failure });
+            let enabled: Vec<Participant> = {
+                let network = network.lock().unwrap();
+                roles.iter().filter(|role| running.contains_key(*role) && network.can_step(role)).cloned().collect()
+            };
+            if enabled.is_empty() {
+                let network = network.lock().unwrap();
+                if !running.is_empty() {
+                    let waiting = running.keys().map(|role| (role.clone(), network.waiting.get(role).cloned().flatten())).collect();
+                    return fail(schedule, Failure::Deadlock(waiting));
+                }
+                let unreceived: Vec<_> = network.inboxes.iter()
+                    .flat_map(|(to, inbox)| inbox.iter().map(|env| (env.from.clone(), to.clone(), env.frame.label.clone())))
+                    .collect();
+                if !unreceived.is_empty() {
+                    return fail(schedule, Failure::Unreceived(unreceived));
+                }
+                return Ok(schedule);
+            }
+            if schedule.len() == self.max_steps {
+                return fail(schedule, Failure::StepLimit(self.max_steps));
+            }
+
+            let role = enabled[scheduler.pick(&enabled)].clone();
+            schedule.push(role.clone());
+            network.lock().unwrap().waiting.remove(&role);
+            let program = running.get_mut(&role).unwrap();
+            match std::panic::catch_unwind(AssertUnwindSafe(|| program.as_mut().poll(&mut cx))) {
+                Ok(Poll::Ready(())) => {
+                    running.remove(&role);
+                },
+                Ok(Poll::Pending) => {},
+                Err(panic) => {
+                    let rejection = network.lock().unwrap().rejected.remove(&role);
+                    let failure = match rejection {
+                        Some((from, error)) => Failure::LabelMismatch { role, from, error },
+                        None => Failure::Panicked { role, message: panic_message(panic) },
+                    };
+                    return fail(schedule, failure);
+                },
+            }
+        }
+    }
+}
+
+fn panic_message(panic: Box<dyn std::any::Any + Send>) -> String {
+    match panic.downcast::<String>() {
+        Ok(message) => *message,
+        Err(panic) => panic.downcast_ref::<&str>().map_or_else(|| String::from("unknown panic"), |message| message.to_string()),
+    }
+}
+
+/// Every message in flight during a run.
+#[derive(Default)]
+struct Network {
+    inboxes: BTreeMap<Participant, VecDeque<Envelope>>,
+    // Who each participant is waiting for a message from, `None` meaning anyone
+    waiting: BTreeMap<Participant, Option<Participant>>,
+    rejected: BTreeMap<Participant, (Participant, WireError)>,
+}
+
+impl Network {
+    fn can_step(&self, role: &Participant) -> bool {
+        match self.waiting.get(role) {
+            None => true,
+            Some(from) => self.inboxes.get(role).is_some_and(|inbox| inbox.iter().any(|env| from.as_ref().is_none_or(|peer| env.from == *peer))),
+        }
+    }
+}
+
+struct SimTransport {
+    role: Participant,
+    peers: Vec<Participant>,
+    network: Arc<Mutex<Network>>,
+}
+
+impl AsyncTransport for SimTransport {
+    fn peers(&self) -> Vec<Participant> {
+        self.peers.clone()
+    }
+
+    fn send(&mut self, to: &Participant, frame: Frame) -> Result<(), SessionError> {
+        if !self.peers.contains(to) {
+            return Err(SessionError::UnknownPeer(to.clone()));
+        }
+        self.network.lock().unwrap().inboxes.entry(to.clone()).or_default().push_back(Envelope { from: self.role.clone(), frame });
+        Ok(())
+    }
+
+    fn poll_receive(&mut self, _cx: &mut Context<'_>, from: Option<&Participant>) -> Poll<Result<Envelope, SessionError>> {
+        if let Some(peer) = from {
+            if !self.peers.contains(peer) {
+                return Poll::Ready(Err(SessionError::UnknownPeer(peer.clone())));
+            }
+        }
+        let mut network = self.network.lock().unwrap();
+        let inbox = network.inboxes.entry(self.role.clone()).or_default();
+        match inbox.iter().position(|env| from.is_none_or(|peer| env.from == *peer)) {
+            Some(index) => Poll::Ready(Ok(inbox.remove(index).unwrap())),
+            // A message from a peer that has returned never arrives, which shows up as a deadlock
+            None => {
+                network.waiting.insert(self.role.clone(), from.cloned());
+                Poll::Pending
+            },
+        }
+    }
+
+    fn rejected(&mut self, envelope: &Envelope, error: &WireError) {
+        self.network.lock().unwrap().rejected.insert(self.role.clone(), (envelope.from.clone(), error.clone()));
+    }
+}
+
+impl Scheduler for RoundRobin {
+    fn pick(&mut self, enabled: &[Participant]) -> usize {
+        let index = match &self.last {
+            Some(last) => enabled.iter().position(|role| role > last).unwrap_or(0),
+            None => 0,
+        };
+        self.last = Some(enabled[index].clone());
+        index
+    }
+}
+
+impl SeededRandom {
+    pub fn new(seed: u64) -> SeededRandom {
+        // xorshift gets stuck at 0
+        SeededRandom { state: seed.max(1) }
+    }
+}
+
+impl Scheduler for SeededRandom {
+    fn pick(&mut self, enabled: &[Participant]) -> usize {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        (self.state % enabled.len() as u64) as usize
+    }
+}
+
+impl Scheduler for Exhaustive {
+    fn pick(&mut self, enabled: &[Participant]) -> usize {
+        // Follow the current prefix, then take the first option at every new step
+        if self.depth == self.choices.len() {
+            self.choices.push((0, enabled.len()));
+        }
+        let (choice, _) = self.choices[self.depth];
+        self.depth += 1;
+        choice
+    }
+
+    fn rerun(&mut self) -> bool {
+        self.depth = 0;
+        // Move on to the next option at the deepest step that has one left
+        while let Some((choice, options)) = self.choices.pop() {
+            if choice + 1 < options {
+                self.choices.push((choice + 1, options));
+                return true;
+            }
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{Bye, Hello};
+
+    async fn ping(mut s: AsyncSession) {
+        s.send(Hello).await;
+        s.receive::<Bye>().await;
+    }
+
+    async fn pong(mut s: AsyncSession) {
+        s.receive::<Hello>().await;
+        s.send(Bye).await;
+    }
+
+    #[test]
+    fn schedulers_run_to_completion() {
+        let simulation = Simulation::new().participant("A", ping).participant("B", pong);
+        let report = simulation.run(&mut RoundRobin::default()).unwrap();
+        assert_eq!(report.schedule, vec![Participant::named("A"), Participant::named("B"), Participant::named("A")]);
+        assert_eq!(simulation.run(&mut SeededRandom::new(7)).unwrap().runs, 1);
+        // B can only go once A has sent, so either of them can start
+        assert_eq!(simulation.run(&mut Exhaustive::default()).unwrap().runs, 2);
+    }
+
+    #[test]
+    fn reports_deadlock() {
+        let simulation = Simulation::new().participant("A", pong).participant("B", pong);
+        let err = simulation.run(&mut RoundRobin::default()).unwrap_err();
+        assert_eq!(err.failure, Failure::Deadlock(vec![(Participant::named("A"), None), (Participant::named("B"), None)]));
+    }
+
+    #[test]
+    fn reports_unreceived_messages() {
+        let simulation = Simulation::new()
+            .participant("A", |mut s: AsyncSession| async move {
+                s.send(Hello).await;
+                s.send(Hello).await;
+                s.receive::<Bye>().await;
+            })
+            .participant("B", pong);
+        let err = simulation.run(&mut RoundRobin::default()).unwrap_err();
+        assert_eq!(err.failure, Failure::Unreceived(vec![(Participant::named("A"), Participant::named("B"), String::from("Hello"))]));
+    }
+
+    #[test]
+    fn exhaustive_finds_a_race() {
+        // C takes the first message from anyone, assuming it comes from A
+        let simulation = Simulation::new()
+            .participant("A", |mut s: AsyncSession| async move { s.send_to("C", Hello).await })
+            .participant("B", |mut s: AsyncSession| async move { s.send_to("C", Bye).await })
+            .participant("C", |mut s: AsyncSession| async move {
+                s.receive::<Hello>().await;
+                s.receive::<Bye>().await;
+            });
+        assert!(simulation.run(&mut RoundRobin::default()).is_ok());
+        let err = simulation.run(&mut Exhaustive::default()).unwrap_err();
+        assert!(matches!(err.failure, Failure::LabelMismatch { from, .. } if from == Participant::named("B")));
+        assert_eq!(err.schedule.first(), Some(&Participant::named("B")));
+    }
+
+    #[test]
+    fn reports_label_mismatch() {
+        let simulation = Simulation::new().participant("A", ping).participant("B", ping);
+        let err = simulation.run(&mut RoundRobin::default()).unwrap_err();
+        assert!(matches!(err.failure, Failure::LabelMismatch { role, error: WireError::UnexpectedLabel { .. }, .. } if role == Participant::named("B")));
+    }
+}
@@ -0,0 +1,337 @@
+//! The text syntax session types are printed in, and parsed back from:
+//!
+//! ```text
+//! local   ::= end | mu X. local | X | role!{ choices } | role?{ choices }
+//! global  ::= end | mu X. global | X | role -> role : { choices } | role -> role : label. global
+//! choices ::= label. type, label. type, ...
+//! role    ::= name | ?
+//! ```
+//!
+//! For example `mu X. B!{ Hello. A?{ Left. end, Right. X } }`. Labels are written as they print, e.g. `Response::Accept(bool)`
+//! or `Reply::Counter { price: u32 }`, and `?` is an anonymous participant. A recursion variable named `X3` has the id 3,
+//! and other names are numbered from one past the highest such id, in the order their `mu` appears in. Every `mu` binds a different id.
+//! In a global type, `role -> role : label. global` is a `Send` and the braces a `Select`, even of one label.
+
+use std::{fmt::Display, str::FromStr};
+
+use crate::session_type::{Label, MPSTLocalType, Participant};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Reads the parts both kinds of type are made of, so `GlobalType` can be parsed the same way outside this crate.
+pub struct Parser<'a> {
+    text: &'a str,
+    pos: usize,
+    // The id the next `mu` without an explicit id binds
+    next_id: i32,
+    // Every id bound so far, so none is bound twice
+    bound: Vec<i32>,
+    // The recursion variables in scope, innermost last
+    scope: Vec<(&'a str, i32)>,
+}
+
+impl<'a> Parser<'a> {
+    pub fn new(text: &'a str) -> Self {
+        // Explicit ids can come after the names numbered for them, so they are all found first
+        let words: Vec<&str> = text.split(|c| !is_name_char(c)).filter(|word| !word.is_empty()).collect();
+        let highest = words.windows(2)
+            .filter(|pair| pair[0] == "mu")
+            .filter_map(|pair| explicit_id(pair[1]))
+            .max()
+            .unwrap_or(0);
+        Parser { text, pos: 0, next_id: highest + 1, bound: Vec::new(), scope: Vec::new() }
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.text[self.pos..]
+    }
+
+    /// Skips whitespace, and returns where the next token starts.
+    pub fn position(&mut self) -> usize {
+        let rest = self.rest();
+        self.pos += rest.len() - rest.trim_start().len();
+        self.pos
+    }
+
+    pub fn error_at(&self, pos: usize, message: impl Into<String>) -> ParseError {
+        let before = &self.text[..pos];
+        let line_start = before.rfind('\n').map_or(0, |newline| newline + 1);
+        ParseError {
+            line: before.matches('\n').count() + 1,
+            column: before[line_start..].chars().count() + 1,
+            message: message.into(),
+        }
+    }
+
+    pub fn error(&mut self, message: impl Into<String>) -> ParseError {
+        let pos = self.position();
+        self.error_at(pos, message)
+    }
+
+    pub fn peek(&mut self, token: &str) -> bool {
+        self.position();
+        self.rest().starts_with(token)
+    }
+
+    pub fn eat(&mut self, token: &str) -> bool {
+        let found = self.peek(token);
+        if found {
+            self.pos += token.len();
+        }
+        found
+    }
+
+    pub fn expect(&mut self, token: &str) -> Result<(), ParseError> {
+        if self.eat(token) {
+            Ok(())
+        } else {
+            Err(self.error(format!("Expected `{}`", token)))
+        }
+    }
+
+    /// Eats `word` only if it is a whole word, so `endpoint` is not read as `end`.
+    pub fn keyword(&mut self, word: &str) -> bool {
+        let found = self.peek(word) && !self.rest()[word.len()..].starts_with(is_name_char);
+        if found {
+            self.pos += word.len();
+        }
+        found
+    }
+
+    pub fn name(&mut self) -> Result<&'a str, ParseError> {
+        self.position();
+        let rest = self.rest();
+        let len = rest.find(|c| !is_name_char(c)).unwrap_or(rest.len());
+        if len == 0 || rest.starts_with(|c: char| c.is_ascii_digit()) {
+            return Err(self.error("Expected a name"));
+        }
+        self.pos += len;
+        Ok(&rest[..len])
+    }
+
+    pub fn participant(&mut self) -> Result<Participant, ParseError> {
+        if self.eat("?") {
+            Ok(Participant::anonymous())
+        } else {
+            self.name().map(Participant::named)
+        }
+    }
+
+    /// A label runs to the end of its name and generic arguments, plus the bracketed sort that may follow them.
+    pub fn label(&mut self) -> Result<Label, ParseError> {
+        let start = self.position();
+        let rest = self.rest();
+        if !rest.starts_with(is_name_char) {
+            return Err(self.error("Expected a label"));
+        }
+        let mut end = 0;
+        let mut depth = 0;
+        for (i, c) in rest.char_indices() {
+            match c {
+                '<' => depth += 1,
+                '>' if depth > 0 => depth -= 1,
+                c if depth == 0 && !(is_name_char(c) || c == ':') => break,
+                _ => (),
+            }
+            end = i + c.len_utf8();
+        }
+        if depth > 0 {
+            return Err(self.error_at(self.text.len(), "Unclosed `<` in label"));
+        }
+        self.pos += end;
+        if self.peek("(") || self.peek("{") {
+            let sort_start = self.pos;
+            let mut depth = 0;
+            for (i, c) in self.rest().char_indices() {
+                match c {
+                    '(' | '{' | '[' => depth += 1,
+                    ')' | '}' | ']' => depth -= 1,
+                    _ => (),
+                }
+                if depth == 0 {
+                    end = sort_start - start + i + 1;
+                    break;
+                }
+            }
+            if depth > 0 {
+                return Err(self.error_at(sort_start, "Unclosed sort"));
+            }
+        }
+        self.pos = start + end;
+        Ok(Label::from(&self.text[start..self.pos]))
+    }
+
+    /// Reads `{ label. cont, ... }`, the conts with `cont`.
+    pub fn choices<T>(&mut self, mut cont: impl FnMut(&mut Self) -> Result<T, ParseError>) -> Result<Vec<(Label, T)>, ParseError> {
+        self.expect("{")?;
+        let mut choices = Vec::new();
+        if self.eat("}") {
+            return Ok(choices);
+        }
+        loop {
+            let label = self.label()?;
+            self.expect(".")?;
+            choices.push((label, cont(self)?));
+            if self.eat("}") {
+                return Ok(choices);
+            }
+            self.expect(",")?;
+        }
+    }
+
+    /// Reads the `X. body` of a `mu` that has been eaten, returning the recursion's id and its body.
+    pub fn recursion<T>(&mut self, body: impl FnOnce(&mut Self) -> Result<T, ParseError>) -> Result<(i32, T), ParseError> {
+        let pos = self.position();
+        let name = self.name()?;
+        let id = match explicit_id(name) {
+            Some(id) => id,
+            None => {
+                self.next_id += 1;
+                self.next_id - 1
+            },
+        };
+        if self.bound.contains(&id) {
+            return Err(self.error_at(pos, format!("Recursion id {} is bound twice", id)));
+        }
+        self.bound.push(id);
+        self.expect(".")?;
+        self.scope.push((name, id));
+        let body = body(self);
+        self.scope.pop();
+        Ok((id, body?))
+    }
+
+    /// The id of a recursion variable read at `pos`. Only variables with an explicit id can be free.
+    pub fn variable(&self, name: &str, pos: usize) -> Result<i32, ParseError> {
+        self.scope.iter().rev()
+            .find(|(bound, _)| *bound == name)
+            .map(|(_, id)| *id)
+            .or_else(|| explicit_id(name))
+            .ok_or_else(|| self.error_at(pos, format!("Unbound recursion variable `{}`", name)))
+    }
+
+    pub fn finish(&mut self) -> Result<(), ParseError> {
+        match self.rest().trim_start().chars().next() {
+            Some(c) => Err(self.error(format!("Unexpected `{}`", c))),
+            None => Ok(()),
+        }
+    }
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+fn explicit_id(name: &str) -> Option<i32> {
+    name.strip_prefix('X')?.parse().ok()
+}
+
+fn local_type(parser: &mut Parser) -> Result<MPSTLocalType, ParseError> {
+    if parser.keyword("end") {
+        return Ok(MPSTLocalType::End);
+    }
+    if parser.keyword("mu") {
+        let (id, cont) = parser.recursion(local_type)?;
+        return Ok(MPSTLocalType::RecX { cont: Box::new(cont), id, min_depth: None, max_depth: None });
+    }
+    let pos = parser.position();
+    let (participant, name) = if parser.eat("?") {
+        (Participant::anonymous(), None)
+    } else {
+        let name = parser.name()?;
+        (Participant::named(name), Some(name))
+    };
+    if parser.eat("!") {
+        Ok(MPSTLocalType::Select(participant, parser.choices(local_type)?))
+    } else if parser.eat("?") {
+        Ok(MPSTLocalType::Branch(participant, parser.choices(local_type)?))
+    } else if let Some(name) = name {
+        Ok(MPSTLocalType::X(parser.variable(name, pos)?, false))
+    } else {
+        Err(parser.error("Expected `!` or `?`"))
+    }
+}
+
+/// Parses the syntax `Display` prints, see the `syntax` module. Recursions have no depth bounds, and variables are unmapped.
+impl FromStr for MPSTLocalType {
+    type Err = ParseError;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser::new(text);
+        let local_type = local_type(&mut parser)?;
+        parser.finish()?;
+        Ok(local_type)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn local_types_as_text() {
+        let (a, b) = (Participant::named("A"), Participant::named("B"));
+        let parsed: MPSTLocalType = "mu X. B!{ Hello. A?{Left. end, Right. X} }".parse().unwrap();
+        let expected = MPSTLocalType::RecX {
+            cont: Box::new(MPSTLocalType::send(b, Label::from("Hello"), MPSTLocalType::Branch(a.clone(), vec![
+                (Label::from("Left"), MPSTLocalType::End),
+                (Label::from("Right"), MPSTLocalType::X(1, false)),
+            ]))),
+            id: 1,
+            min_depth: None,
+            max_depth: None,
+        };
+        assert_eq!(parsed, expected);
+        assert_eq!(parsed.to_string(), "mu X1. B!{ Hello. A?{ Left. end, Right. X1 } }");
+
+        let sorted = MPSTLocalType::Branch(Participant::anonymous(), vec![
+            (Label::from("Reply::Counter { price: u32 }"), MPSTLocalType::send(a, Label::from("Quote(u32, String)"), MPSTLocalType::X(4, false))),
+            (Label::from("Batch<Hello>"), MPSTLocalType::End),
+        ]);
+        let reparsed: MPSTLocalType = sorted.to_string().parse().unwrap();
+        assert_eq!(reparsed, sorted);
+        assert_eq!(reparsed.to_string(), sorted.to_string());
+    }
+
+    #[test]
+    fn text_errors_have_positions() {
+        let err = "B!{ Hello. end,\n  Bye end }".parse::<MPSTLocalType>().unwrap_err();
+        assert_eq!((err.line, err.column), (2, 7));
+        assert_eq!(err.to_string(), "2:7: Expected `.`");
+        let err = "mu X. A?{ Ping. Y }".parse::<MPSTLocalType>().unwrap_err();
+        assert_eq!(err.to_string(), "1:17: Unbound recursion variable `Y`");
+        assert!("end end".parse::<MPSTLocalType>().is_err());
+        let err = "mu X1. A!{ Ping. mu X1. end }".parse::<MPSTLocalType>().unwrap_err();
+        assert_eq!(err.to_string(), "1:21: Recursion id 1 is bound twice");
+    }
+
+    #[test]
+    fn text_binders_get_distinct_ids() {
+        // Named variables are numbered after the explicit ids, wherever those are
+        let ids = |text: &str| {
+            let mut ids = Vec::new();
+            let mut local_type: MPSTLocalType = text.parse().unwrap();
+            while let MPSTLocalType::RecX { id, cont, .. } = local_type {
+                ids.push(id);
+                local_type = *cont;
+            }
+            (ids, local_type)
+        };
+        assert_eq!(ids("mu X. mu X1. X1"), (vec![2, 1], MPSTLocalType::X(1, false)));
+        assert_eq!(ids("mu X2. mu Y. Y"), (vec![2, 3], MPSTLocalType::X(3, false)));
+        assert_eq!(ids("mu X. mu Y. X"), (vec![1, 2], MPSTLocalType::X(1, false)));
+    }
+}
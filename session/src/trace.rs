@@ -0,0 +1,207 @@
+use std::{collections::VecDeque, io::{self, BufRead, Write}, time::{SystemTime, UNIX_EPOCH}};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{session_type::{Label, Participant}, transport::{Envelope, SessionError, Transport}, wire::Frame, Session};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EventKind {
+    Send,
+    Receive,
+    Branch,
+}
+
+/// One message a participant sent or received, as recorded in a trace.
+/// Roles and labels are the ones the inferred types use, so a trace can be read against the `GlobalType` of the protocol.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TraceEvent {
+    /// The participant that recorded the event
+    pub role: Participant,
+    /// Who the message went to, or came from
+    pub peer: Participant,
+    pub kind: EventKind,
+    /// The label as the inferred types have it, with its message type and sort
+    pub label: Label,
+    /// Microseconds since the Unix epoch
+    pub timestamp: u64,
+    /// The serialized message, so the event can be replayed
+    pub payload: String,
+}
+
+/// A trace file that stays readable after the sessions writing it have moved to other threads, for tests.
+#[cfg(any(test, feature = "test-util"))]
+#[derive(Clone, Default)]
+pub struct SharedBuffer(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+#[cfg(any(test, feature = "test-util"))]
+impl SharedBuffer {
+    /// Reads back the events written so far.
+    pub fn events(&self) -> io::Result<Vec<TraceEvent>> {
+        read_trace(self.0.lock().unwrap().as_slice())
+    }
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Writes a session's events as they happen, one JSON object per line.
+/// Every event is flushed straight away, so the trace survives a crash of the participant.
+pub struct Recorder {
+    out: Box<dyn Write + Send>,
+}
+
+impl Recorder {
+    pub fn new(out: impl Write + Send + 'static) -> Recorder {
+        Recorder {
+            out: Box::new(out),
+        }
+    }
+
+    /// Records `frame`, which is sent or received under `label`.
+    pub fn record(&mut self, role: &Participant, peer: &Participant, kind: EventKind, label: &Label, frame: &Frame) -> io::Result<()> {
+        let event = TraceEvent {
+            role: role.clone(),
+            peer: peer.clone(),
+            kind,
+            label: label.clone(),
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_micros() as u64),
+            payload: String::from_utf8_lossy(&frame.payload).into_owned(),
+        };
+        // Written in one go, so participants can share an output without their lines interleaving
+        let mut line = serde_json::to_vec(&event)?;
+        line.push(b'\n');
+        self.out.write_all(&line)?;
+        self.out.flush()
+    }
+}
+
+/// Reads a trace written by a `Recorder`. Traces from several participants can be concatenated, since every event names its role.
+pub fn read_trace(input: impl BufRead) -> io::Result<Vec<TraceEvent>> {
+    input.lines()
+        .filter(|line| !matches!(line, Ok(line) if line.trim().is_empty()))
+        .map(|line| Ok(serde_json::from_str(&line?)?))
+        .collect()
+}
+
+/// Backend that plays back what `role` received in a trace, and checks it sends what the trace says it sent.
+/// Anything else is reported as `SessionError::Diverged`.
+pub struct ReplayTransport {
+    role: Participant,
+    events: VecDeque<TraceEvent>,
+    peers: Vec<Participant>,
+}
+
+/// A session for `role` that replays its part of `events`, so the participant function can be rerun against a recorded run.
+pub fn replay(role: &Participant, events: impl IntoIterator<Item = TraceEvent>) -> Session {
+    let events: VecDeque<TraceEvent> = events.into_iter().filter(|event| event.role == *role).collect();
+    let mut peers: Vec<Participant> = events.iter().map(|event| event.peer.clone()).collect();
+    peers.sort();
+    peers.dedup();
+    Session::new(role.clone(), Box::new(ReplayTransport {
+        role: role.clone(),
+        events,
+        peers,
+    }))
+}
+
+impl Transport for ReplayTransport {
+    fn peers(&self) -> Vec<Participant> {
+        self.peers.clone()
+    }
+
+    fn send(&mut self, to: &Participant, frame: Frame) -> Result<(), SessionError> {
+        match self.events.front() {
+            Some(event) if event.kind == EventKind::Send && event.peer == *to && event.label.short() == frame.label => {
+                self.events.pop_front();
+                Ok(())
+            },
+            Some(event) => Err(SessionError::Diverged(format!("{} sent {} to {}, but the trace has {:?} {} with {}", self.role, frame.label, to, event.kind, event.label, event.peer))),
+            None => Err(SessionError::Diverged(format!("{} sent {} to {} after the end of the trace", self.role, frame.label, to))),
+        }
+    }
+
+    fn receive(&mut self, from: Option<&Participant>) -> Result<Envelope, SessionError> {
+        match self.events.front() {
+            Some(event) if event.kind != EventKind::Send && from.is_none_or(|peer| event.peer == *peer) => {
+                let event = self.events.pop_front().unwrap();
+                Ok(Envelope {
+                    from: event.peer,
+                    frame: Frame { label: event.label.short().to_string(), payload: event.payload.into_bytes() },
+                })
+            },
+            Some(event) => Err(SessionError::Diverged(format!("{} waited for a message, but the trace has {:?} {} with {}", self.role, event.kind, event.label, event.peer))),
+            // Nothing more was recorded, as if every peer had hung up
+            None => Err(SessionError::Disconnected(from.cloned())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{channel, tests::{Hello, Response}};
+
+    fn server(mut s: Session) {
+        s.receive::<Hello>();
+        s.send(Response::Accept(true));
+    }
+
+    fn record_run() -> Vec<TraceEvent> {
+        let buffer = SharedBuffer::default();
+        let mut sessions = channel::connect(&[Participant::named("A"), Participant::named("B")]);
+        let mut b = sessions.pop().unwrap();
+        let mut a = sessions.pop().unwrap();
+        a.record(buffer.clone());
+        b.record(buffer.clone());
+
+        let handle = std::thread::spawn(move || server(b));
+        a.send(Hello);
+        assert_eq!(a.branch::<Response>(), Response::Accept(true));
+        handle.join().unwrap();
+
+        buffer.events().unwrap()
+    }
+
+    #[test]
+    fn records_every_message() {
+        let events: Vec<_> = record_run().into_iter()
+            .map(|event| (event.role.to_string(), event.peer.to_string(), event.kind, event.label))
+            .collect();
+        // Events are in the order the roles saw them, so only each role's own events are ordered
+        let of = |role: &str| events.iter().filter(|event| event.0 == role).cloned().collect::<Vec<_>>();
+        assert_eq!(of("A"), vec![
+            (String::from("A"), String::from("B"), EventKind::Send, Label::message("Hello")),
+            (String::from("A"), String::from("B"), EventKind::Branch, Label::variant("Response", "Accept")),
+        ]);
+        assert_eq!(of("B"), vec![
+            (String::from("B"), String::from("A"), EventKind::Receive, Label::message("Hello")),
+            (String::from("B"), String::from("A"), EventKind::Send, Label::variant("Response", "Accept")),
+        ]);
+    }
+
+    #[test]
+    fn replays_a_participant() {
+        let events = record_run();
+        server(replay(&Participant::named("B"), events.clone()));
+
+        let mut a = replay(&Participant::named("A"), events);
+        a.send(Hello);
+        assert_eq!(a.branch::<Response>(), Response::Accept(true));
+    }
+
+    #[test]
+    #[should_panic(expected = "Diverged from the trace")]
+    fn replay_rejects_divergence() {
+        let mut b = replay(&Participant::named("B"), record_run());
+        b.receive::<Hello>();
+        b.send(Response::Reject(0));
+    }
+}
@@ -0,0 +1,174 @@
+use std::{collections::{BTreeSet, VecDeque}, fmt::Display, sync::mpsc::Receiver, task::{Context, Poll}};
+
+use futures::{channel::mpsc::UnboundedReceiver, Stream};
+
+use crate::{session_type::Participant, wire::{Frame, WireError}};
+
+/// A message in flight, tagged with the role that sent it.
+pub struct Envelope {
+    pub from: Participant,
+    pub frame: Frame,
+}
+
+/// What a backend pushes into a participant's inbox.
+/// `Closed` is sent once when a peer's endpoint is dropped, so that receivers waiting on it can fail instead of hanging.
+pub enum Delivery {
+    Message(Envelope),
+    Closed(Participant),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SessionError {
+    /// The peer hung up (or every peer did, for an unaddressed receive) before the message arrived
+    Disconnected(Option<Participant>),
+    UnknownPeer(Participant),
+    /// `send` was called without a role, and there is no single peer to default to
+    AmbiguousPeer(Vec<Participant>),
+    /// A message arrived, but could not be decoded as the type the receiver asked for
+    Rejected { from: Participant, error: WireError },
+    Encode(WireError),
+    /// A replayed participant did something other than what its trace recorded
+    Diverged(String),
+}
+
+impl Display for SessionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SessionError::Disconnected(Some(peer)) => write!(f, "Peer {} disconnected", peer),
+            SessionError::Disconnected(None) => write!(f, "All peers disconnected"),
+            SessionError::UnknownPeer(peer) => write!(f, "No channel to peer {}", peer),
+            SessionError::AmbiguousPeer(peers) => {
+                write!(f, "Cannot pick a peer to send to, candidates are [")?;
+                for peer in peers {
+                    write!(f, "{}, ", peer)?;
+                }
+                write!(f, "]. Set a default peer first")
+            },
+            SessionError::Rejected { from, error } => write!(f, "Rejected message from {}: {}", from, error),
+            SessionError::Encode(error) => write!(f, "{}", error),
+            SessionError::Diverged(error) => write!(f, "Diverged from the trace: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for SessionError {}
+
+/// A backend connecting one participant to its peers.
+pub trait Transport: Send {
+    fn peers(&self) -> Vec<Participant>;
+
+    fn send(&mut self, to: &Participant, frame: Frame) -> Result<(), SessionError>;
+
+    /// Takes the oldest message from `from`, or from any peer if `from` is `None`.
+    fn receive(&mut self, from: Option<&Participant>) -> Result<Envelope, SessionError>;
+}
+
+/// The async counterpart of `Transport`. Sends never block, so only receiving is polled.
+pub trait AsyncTransport: Send {
+    fn peers(&self) -> Vec<Participant>;
+
+    fn send(&mut self, to: &Participant, frame: Frame) -> Result<(), SessionError>;
+
+    /// Takes the oldest message from `from`, or from any peer if `from` is `None`, registering `cx` to be woken if there is none yet.
+    fn poll_receive(&mut self, cx: &mut Context<'_>, from: Option<&Participant>) -> Poll<Result<Envelope, SessionError>>;
+
+    /// Called when a received message could not be decoded as what the participant expected, just before the session panics.
+    fn rejected(&mut self, _envelope: &Envelope, _error: &WireError) {}
+}
+
+/// The receiving half shared by every backend: a single inbox all peers deliver into,
+/// plus the messages that were skipped over while waiting for a specific peer.
+/// Blocking backends use a `std` channel as the inbox, async ones a `futures` channel.
+pub struct Mailbox<I = Receiver<Delivery>> {
+    inbox: I,
+    pending: VecDeque<Envelope>,
+    peers: BTreeSet<Participant>,
+    closed: BTreeSet<Participant>,
+}
+
+impl<I> Mailbox<I> {
+    pub fn new(inbox: I, peers: impl IntoIterator<Item = Participant>) -> Mailbox<I> {
+        Mailbox {
+            inbox,
+            pending: VecDeque::new(),
+            peers: peers.into_iter().collect(),
+            closed: BTreeSet::new(),
+        }
+    }
+
+    pub fn peers(&self) -> Vec<Participant> {
+        self.peers.iter().cloned().collect()
+    }
+
+    /// Checks `from` is a peer, and takes a message from it that arrived earlier, if any.
+    fn take_pending(&mut self, from: Option<&Participant>) -> Result<Option<Envelope>, SessionError> {
+        if let Some(peer) = from {
+            if !self.peers.contains(peer) {
+                return Err(SessionError::UnknownPeer(peer.clone()));
+            }
+        }
+        Ok(self.pending.iter().position(|env| from.is_none_or(|peer| env.from == *peer))
+            .map(|index| self.pending.remove(index).unwrap()))
+    }
+
+    /// Files a delivery from the inbox, returning it if it is the message being waited for.
+    fn file(&mut self, delivery: Option<Delivery>, from: Option<&Participant>) -> Option<Result<Envelope, SessionError>> {
+        match delivery {
+            Some(Delivery::Message(env)) => {
+                if from.is_none_or(|peer| env.from == *peer) {
+                    return Some(Ok(env));
+                }
+                self.pending.push_back(env);
+            },
+            Some(Delivery::Closed(peer)) => {
+                self.closed.insert(peer);
+            },
+            None => return Some(Err(SessionError::Disconnected(from.cloned()))),
+        }
+        None
+    }
+
+    fn is_closed(&self, from: Option<&Participant>) -> bool {
+        match from {
+            Some(peer) => self.closed.contains(peer),
+            None => self.closed.len() == self.peers.len(),
+        }
+    }
+}
+
+impl Mailbox<Receiver<Delivery>> {
+    pub fn take(&mut self, from: Option<&Participant>) -> Result<Envelope, SessionError> {
+        if let Some(env) = self.take_pending(from)? {
+            return Ok(env);
+        }
+        loop {
+            if self.is_closed(from) {
+                return Err(SessionError::Disconnected(from.cloned()));
+            }
+            let delivery = self.inbox.recv().ok();
+            if let Some(result) = self.file(delivery, from) {
+                return result;
+            }
+        }
+    }
+}
+
+impl Mailbox<UnboundedReceiver<Delivery>> {
+    pub fn poll_take(&mut self, cx: &mut Context<'_>, from: Option<&Participant>) -> Poll<Result<Envelope, SessionError>> {
+        if let Some(env) = self.take_pending(from)? {
+            return Poll::Ready(Ok(env));
+        }
+        loop {
+            if self.is_closed(from) {
+                return Poll::Ready(Err(SessionError::Disconnected(from.cloned())));
+            }
+            let delivery = match std::pin::Pin::new(&mut self.inbox).poll_next(cx) {
+                Poll::Ready(delivery) => delivery,
+                Poll::Pending => return Poll::Pending,
+            };
+            if let Some(result) = self.file(delivery, from) {
+                return Poll::Ready(result);
+            }
+        }
+    }
+}
@@ -0,0 +1,151 @@
+use std::{collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque}, fmt::Display};
+
+/// A message as it travels between participants: the label the protocol knows it by, and its serialized contents.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub label: String,
+    pub payload: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WireError {
+    /// The frame's label is not one the receiving type can be sent under
+    UnexpectedLabel { expected: Vec<String>, found: String },
+    Encode(String),
+    Decode(String),
+}
+
+impl Display for WireError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WireError::UnexpectedLabel { expected, found } => {
+                write!(f, "Expected one of [")?;
+                for label in expected {
+                    write!(f, "{}, ", label)?;
+                }
+                write!(f, "], received {}", found)
+            },
+            WireError::Encode(err) => write!(f, "Cannot encode payload: {}", err),
+            WireError::Decode(err) => write!(f, "Cannot decode payload: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for WireError {}
+
+pub fn encode_payload<T: serde::Serialize>(msg: &T) -> Result<Vec<u8>, WireError> {
+    serde_json::to_vec(msg).map_err(|err| WireError::Encode(err.to_string()))
+}
+
+pub fn decode_payload<T: serde::de::DeserializeOwned>(payload: &[u8]) -> Result<T, WireError> {
+    serde_json::from_slice(payload).map_err(|err| WireError::Decode(err.to_string()))
+}
+
+/// The name of a type as it is written, without module paths, e.g. `Quote` for `travel_agency::Quote`, or `Vec<Item>` for `Vec<proto::Item>`.
+/// It is the label a type is known by when it has only one, and matches the label the macro reads from a `receive::<T>()` turbofish.
+/// Unlike `std::any::type_name`, it is the same whichever compiler built the participants exchanging it.
+/// `#[derive(Message)]` implements it from the type's declaration.
+pub trait TypeName {
+    fn type_name() -> String;
+}
+
+macro_rules! impl_type_name {
+    ($($ty:ident),*) => {
+        $(impl TypeName for $ty {
+            fn type_name() -> String {
+                String::from(stringify!($ty))
+            }
+        })*
+    };
+}
+
+impl_type_name!(bool, char, u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, f32, f64, String);
+
+macro_rules! impl_generic_type_name {
+    ($($ty:ident<$($arg:ident),*>),*) => {
+        $(impl<$($arg: TypeName),*> TypeName for $ty<$($arg),*> {
+            fn type_name() -> String {
+                let args: Vec<String> = vec![$($arg::type_name()),*];
+                format!("{}<{}>", stringify!($ty), args.join(", "))
+            }
+        })*
+    };
+}
+
+impl_generic_type_name!(Vec<T>, VecDeque<T>, Option<T>, Box<T>, Result<T, E>, HashMap<K, V>, BTreeMap<K, V>, HashSet<T>, BTreeSet<T>);
+
+macro_rules! impl_tuple_type_name {
+    ($($arg:ident),*) => {
+        impl<$($arg: TypeName),*> TypeName for ($($arg,)*) {
+            fn type_name() -> String {
+                let args: Vec<String> = vec![$($arg::type_name()),*];
+                // A tuple of one is told from a parenthesised type by its comma
+                match args.len() {
+                    1 => format!("({},)", args[0]),
+                    _ => format!("({})", args.join(", ")),
+                }
+            }
+        }
+    };
+}
+
+impl_tuple_type_name!();
+impl_tuple_type_name!(A);
+impl_tuple_type_name!(A, B);
+impl_tuple_type_name!(A, B, C);
+impl_tuple_type_name!(A, B, C, D);
+impl_tuple_type_name!(A, B, C, D, E);
+impl_tuple_type_name!(A, B, C, D, E, G);
+
+impl<T: TypeName, const N: usize> TypeName for [T; N] {
+    fn type_name() -> String {
+        format!("[{}; {}]", T::type_name(), N)
+    }
+}
+
+/// A type name with the module paths of every type in it stripped, `Batch<Item>` for `proto::Batch<proto::Item>`.
+/// This is how the macro writes the label of a message type from the source, as `TypeName` does at runtime.
+pub fn strip_paths(full_name: &str) -> String {
+    let mut label = String::with_capacity(full_name.len());
+    // Where the path segment currently being written starts in `label`
+    let mut segment_start = 0;
+    let mut chars = full_name.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == ':' && chars.peek() == Some(&':') {
+            chars.next();
+            label.truncate(segment_start);
+        } else {
+            label.push(c);
+            if !(c.is_alphanumeric() || c == '_') {
+                segment_start = label.len();
+            }
+        }
+    }
+    label
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{tests::{Hello, Response}, Message};
+
+    #[test]
+    fn frames_carry_labels() {
+        let frame = Response::Accept(true).encode().unwrap();
+        assert_eq!(frame.label, "Accept");
+        assert_eq!(Response::decode(&frame), Ok(Response::Accept(true)));
+        assert_eq!(Hello.encode().unwrap().label, "Hello");
+        assert_eq!(<Vec<Option<Hello>>>::type_name(), "Vec<Option<Hello>>");
+        assert_eq!(<(u32, [bool; 2], Result<(String,), ()>)>::type_name(), "(u32, [bool; 2], Result<(String,), ()>)");
+    }
+
+    #[test]
+    fn mismatched_label_is_rejected() {
+        let frame = Frame { label: String::from("Bye"), payload: Hello.encode().unwrap().payload };
+        assert_eq!(Hello::decode(&frame).err(), Some(WireError::UnexpectedLabel { expected: vec![String::from("Hello")], found: String::from("Bye") }));
+
+        // Right type, wrong variant
+        let frame = Frame { label: String::from("Reject"), payload: Response::Accept(true).encode().unwrap().payload };
+        assert!(matches!(Response::decode(&frame), Err(WireError::UnexpectedLabel { .. })));
+    }
+}